@@ -0,0 +1,107 @@
+use std::{
+    fs::OpenOptions,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+use crate::{error::ApplicationError, geometry::PieceIndex, session::csv_field};
+
+const HEADER: &str = "timestamp,info_hash,piece,blocks,peer,client,verdict\n";
+
+/// Whether a downloaded piece matched its hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Verified,
+    Corrupt,
+}
+
+impl Verdict {
+    fn name(self) -> &'static str {
+        match self {
+            Verdict::Verified => "verified",
+            Verdict::Corrupt  => "corrupt",
+        }
+    }
+}
+
+/// Provenance of one downloaded piece
+///
+/// Blocks are only accepted over the connection that requested them, so
+/// the one peer recorded here delivered all `blocks` of the piece.
+#[derive(Debug, Clone)]
+pub struct PieceAudit {
+    pub info_hash: [u8; 20],
+    pub piece:     PieceIndex,
+    pub blocks:    usize,
+    pub peer:      String,
+    /// Client name from the peer's handshake
+    pub client:    String,
+    pub verdict:   Verdict,
+}
+
+/// Cloneable handle appending a CSV line per downloaded piece to a file
+///
+/// Like [`Recorder`](crate::recorder::Recorder), auditing never blocks:
+/// lines are written by a background task.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl AuditLog {
+    /// Opens `path` in append mode, writing the header to new files, and
+    /// spawns the writer task
+    pub fn open(path: &Path) -> Result<(Self, JoinHandle<()>), ApplicationError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| ApplicationError::WorkerError(format!("audit log: {}", e)))?;
+        let empty = file
+            .metadata()
+            .map_err(|e| ApplicationError::WorkerError(format!("audit log: {}", e)))?
+            .len()
+            == 0;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        if empty {
+            let _ = tx.send(HEADER.to_string());
+        }
+        let task = task::spawn(write_lines(File::from_std(file), rx));
+        Ok((Self { tx }, task))
+    }
+
+    pub fn record(&self, entry: PieceAudit) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let _ = self.tx.send(format!(
+            "{},{},{},{},{},{},{}\n",
+            timestamp,
+            hex::encode(entry.info_hash),
+            entry.piece,
+            entry.blocks,
+            entry.peer,
+            csv_field(&entry.client),
+            entry.verdict.name(),
+        ));
+    }
+}
+
+async fn write_lines(mut file: File, mut rx: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = rx.recv().await {
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            println!("Failed to write audit log: {}", e);
+            return;
+        }
+    }
+    let _ = file.flush().await;
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ApplicationError,
+    seal::{self, Passphrase},
+};
+
+/// A banned address, as stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BanEntry {
+    ip:      IpAddr,
+    /// UNIX timestamp (seconds) after which the ban is lifted; `None` is permanent
+    expires: Option<u64>,
+}
+
+/// Set of banned peer addresses, optionally persisted to a JSON file
+///
+/// Every change is written back immediately so bans survive restarts.
+#[derive(Debug, Default)]
+pub struct BanList {
+    path:       Option<PathBuf>,
+    /// Encrypts the file when set
+    passphrase: Option<Passphrase>,
+    entries:    HashMap<IpAddr, Option<u64>>,
+}
+
+impl BanList {
+    /// Loads the list stored at `path`; a missing file yields an empty list
+    ///
+    /// With a `passphrase` the file is decrypted on load and encrypted on save.
+    pub fn load(path: &Path, passphrase: Option<Passphrase>) -> Result<Self, ApplicationError> {
+        let mut list = Self {
+            path:       Some(path.to_path_buf()),
+            passphrase,
+            entries:    HashMap::new(),
+        };
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(list),
+            Err(e) => return Err(ApplicationError::WorkerError(format!("ban list: {}", e))),
+        };
+        let data = match &list.passphrase {
+            Some(passphrase) => seal::open(passphrase, &data)?,
+            None             => data,
+        };
+
+        let entries: Vec<BanEntry> = serde_json::from_slice(&data)
+            .map_err(|e| ApplicationError::ParserError(format!("ban list: {}", e)))?;
+
+        let now = now();
+        list.entries = entries
+            .into_iter()
+            .filter(|e| e.expires.is_none_or(|t| t > now))
+            .map(|e| (e.ip, e.expires))
+            .collect();
+        Ok(list)
+    }
+
+    /// Bans `ip`, forever or for the given duration
+    pub fn ban(&mut self, ip: IpAddr, duration: Option<Duration>) -> Result<(), ApplicationError> {
+        let expires = duration.map(|d| now() + d.as_secs());
+        self.entries.insert(ip, expires);
+        self.save()
+    }
+
+    /// Lifts the ban on `ip`, if any
+    pub fn unban(&mut self, ip: IpAddr) -> Result<(), ApplicationError> {
+        if self.entries.remove(&ip).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if `ip` is banned and the ban has not expired
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match self.entries.get(ip) {
+            Some(Some(expires)) => *expires > now(),
+            Some(None)          => true,
+            None                => false,
+        }
+    }
+
+    fn save(&self) -> Result<(), ApplicationError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let now     = now();
+        let entries = self
+            .entries
+            .iter()
+            .filter(|(_, expires)| expires.is_none_or(|t| t > now))
+            .map(|(ip, expires)| BanEntry {
+                ip:      *ip,
+                expires: *expires,
+            })
+            .collect::<Vec<_>>();
+
+        let mut data = serde_json::to_vec_pretty(&entries)
+            .map_err(|e| ApplicationError::WorkerError(format!("ban list: {}", e)))?;
+        if let Some(passphrase) = &self.passphrase {
+            data = seal::seal(passphrase, &data)?;
+        }
+        fs::write(path, data).map_err(|e| ApplicationError::WorkerError(format!("ban list: {}", e)))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
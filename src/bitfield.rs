@@ -0,0 +1,114 @@
+use std::ops::{BitAnd, BitOr, Not};
+
+use bitvec::{order::Msb0, vec::BitVec};
+
+/// Set of piece indices, one bit per piece in wire order
+///
+/// Bit `i` is the `i`-th bit of the `bitfield` message, most significant bit
+/// first. Setting a bit past the end grows the set; operations between sets
+/// of different lengths treat missing bits as unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: BitVec<u8, Msb0>,
+}
+
+impl Bitfield {
+    /// Creates a set of `len` pieces, none of them present
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: BitVec::repeat(false, len),
+        }
+    }
+
+    /// Reads the payload of a `bitfield` message, spare bits included
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            bits: BitVec::from_slice(bytes),
+        }
+    }
+
+    /// Returns the payload of a `bitfield` message, padded to whole bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bits.as_raw_slice()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        self.bits.get(index).is_some_and(|b| *b)
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.bits.len() {
+            if !value {
+                return;
+            }
+            self.bits.resize(index + 1, false);
+        }
+        self.bits.set(index, value);
+    }
+
+    /// Returns the number of pieces present
+    pub fn count_ones(&self) -> usize {
+        self.bits.count_ones()
+    }
+
+    /// Iterates over the indices of the pieces present, in increasing order
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter_ones()
+    }
+
+    /// Returns `true` if some piece is present in both sets
+    pub fn intersects(&self, other: &Bitfield) -> bool {
+        let len = self.len().min(other.len());
+        self.bits[..len]
+            .iter_ones()
+            .any(|i| other.bits[i])
+    }
+
+    /// Shrinks or extends the set to `len` pieces, dropping spare bits
+    pub fn truncate_to(&mut self, len: usize) {
+        self.bits.resize(len, false);
+    }
+}
+
+impl BitAnd for &Bitfield {
+    type Output = Bitfield;
+
+    fn bitand(self, other: &Bitfield) -> Bitfield {
+        let mut bits = self.bits.clone();
+        bits.resize(self.len().max(other.len()), false);
+        let len = self.len().min(other.len());
+        bits[..len] &= &other.bits[..len];
+        bits[len..].fill(false);
+        Bitfield { bits }
+    }
+}
+
+impl BitOr for &Bitfield {
+    type Output = Bitfield;
+
+    fn bitor(self, other: &Bitfield) -> Bitfield {
+        let mut bits = self.bits.clone();
+        bits.resize(self.len().max(other.len()), false);
+        let len = other.len();
+        bits[..len] |= &other.bits[..];
+        Bitfield { bits }
+    }
+}
+
+impl Not for &Bitfield {
+    type Output = Bitfield;
+
+    fn not(self) -> Bitfield {
+        Bitfield {
+            bits: !self.bits.clone(),
+        }
+    }
+}
@@ -0,0 +1,559 @@
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    ops::Range,
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use reqwest::Url;
+use tokio::net::lookup_host;
+
+use torrentz::{
+    config::{Config, Keepalive, Limits, PeerSockets, SeedLimits, TorrentOptions, TrackerAuth, TrackerHttp, TrackerUdp},
+    error::ApplicationError,
+    events::{EventCategory, EventMask},
+    peer::{Peer, PeerSource},
+    seal::Passphrase,
+    torrent::Torrent,
+    tracker::PEER_PORT,
+};
+
+/// Environment variable holding the passphrase of `--encrypt-state`
+const STATE_PASSPHRASE_ENV: &str = "TORRENTZ_STATE_PASSPHRASE";
+
+/// A BitTorrent client
+#[derive(Debug, Parser)]
+#[command(name = "torrentz", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: CliCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Download a .torrent file, or every .torrent file in a directory
+    Download(Box<DownloadArgs>),
+    /// Show the name, size, pieces and files of a .torrent file
+    Info(InfoArgs),
+    /// Replay a peer-wire recording made with `download --record-wire`
+    Replay(ReplayArgs),
+    /// Ask every tracker of a torrent how many peers its swarm has
+    Scrape(ScrapeArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ScrapeArgs {
+    /// Torrent whose trackers are scraped
+    pub path: PathBuf,
+
+    /// Give up on an HTTP tracker after this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub tracker_timeout: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    /// Torrent to describe
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Recording to replay
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct DownloadArgs {
+    /// Path to a .torrent file, a directory of .torrent files or a magnet link
+    pub path: PathBuf,
+
+    /// Peer to connect to in addition to the ones from the tracker, as IP:PORT
+    /// or HOST:PORT (repeatable)
+    #[arg(long = "peer", value_name = "ADDR", value_parser = parse_peer)]
+    pub peers: Vec<PeerAddr>,
+
+    /// Directory the downloaded files are written to
+    #[arg(short, long, visible_alias = "out", value_name = "DIR", default_value = ".")]
+    pub output: PathBuf,
+
+    /// Keep unfinished downloads in this directory, moving them to the
+    /// output directory once complete
+    #[arg(long, value_name = "DIR")]
+    pub incomplete_dir: Option<PathBuf>,
+
+    /// Append every engine event as a JSON line to this file
+    #[arg(long, value_name = "FILE")]
+    pub event_log: Option<PathBuf>,
+
+    /// Only log events of these categories: status, peer, tracker, storage,
+    /// performance (comma-separated, default: all)
+    #[arg(long, value_name = "LIST", value_delimiter = ',', requires = "event_log")]
+    pub event_categories: Vec<EventCategory>,
+
+    /// Persist banned peers to this file
+    #[arg(long, value_name = "FILE")]
+    pub ban_list: Option<PathBuf>,
+
+    /// Encrypt the ban list and resume files with the passphrase in $TORRENTZ_STATE_PASSPHRASE
+    #[arg(long)]
+    pub encrypt_state: bool,
+
+    /// Use one peer id and announce key for all torrents instead of one per torrent
+    #[arg(long)]
+    pub shared_identity: bool,
+
+    /// IP address to report to trackers instead of the one they observe
+    #[arg(long, value_name = "IP")]
+    pub announce_ip: Option<IpAddr>,
+
+    /// Make all peer and tracker connections from this local address (e.g. a VPN's)
+    #[arg(long, value_name = "IP")]
+    pub bind_ip: Option<IpAddr>,
+
+    /// TCP port to accept peers on, announced to trackers and the DHT
+    #[arg(long, value_name = "PORT", default_value_t = PEER_PORT)]
+    pub port: u16,
+
+    /// Accept peers on this local address, told to trackers and peers
+    /// (repeatable, e.g. once for IPv4 and once for IPv6; default: --bind-ip,
+    /// or every IPv4 and IPv6 address)
+    #[arg(long = "listen", value_name = "IP")]
+    pub listen_ips: Vec<IpAddr>,
+
+    /// Download at most this many bytes per second in total, e.g. 2M
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub download_limit: Option<u64>,
+
+    /// Upload at most this many bytes per second in total, e.g. 512K
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub upload_limit: Option<u64>,
+
+    /// Download at most this many bytes per second from each peer
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub peer_download_limit: Option<u64>,
+
+    /// Upload at most this many bytes per second to each peer
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub peer_upload_limit: Option<u64>,
+
+    /// Peers each torrent downloads from at once (default: 10)
+    #[arg(long, value_name = "N")]
+    pub max_peers: Option<NonZeroUsize>,
+
+    /// Peer connections open at once, across all torrents (default: 200)
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<NonZeroUsize>,
+
+    /// Peers we upload to at once, across all torrents (default: 4)
+    #[arg(long, value_name = "N")]
+    pub upload_slots: Option<NonZeroUsize>,
+
+    /// JSON file of rate limits, connection caps and queue sizes, applied
+    /// again whenever it changes or on SIGHUP; its values override the flags'
+    #[arg(long = "config", value_name = "FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Disable Nagle's algorithm on peer connections, sending requests right away
+    #[arg(long)]
+    pub tcp_nodelay: bool,
+
+    /// Kernel send buffer of peer connections, e.g. 4M
+    #[arg(long, value_name = "SIZE", value_parser = parse_buffer_size)]
+    pub socket_send_buffer: Option<usize>,
+
+    /// Kernel receive buffer of peer connections, e.g. 4M
+    #[arg(long, value_name = "SIZE", value_parser = parse_buffer_size)]
+    pub socket_recv_buffer: Option<usize>,
+
+    /// Send TCP keepalive probes after peer connections are idle this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Seconds between TCP keepalive probes
+    #[arg(long, value_name = "SECONDS", requires = "tcp_keepalive")]
+    pub tcp_keepalive_interval: Option<u64>,
+
+    /// Unanswered TCP keepalive probes after which a peer connection is dropped
+    #[arg(long, value_name = "N", requires = "tcp_keepalive")]
+    pub tcp_keepalive_retries: Option<u32>,
+
+    /// DSCP codepoint (0-63) to mark peer traffic with, e.g. 8 for CS1 (low priority)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..64))]
+    pub dscp: Option<u8>,
+
+    /// Only get peers from trackers, not from the DHT
+    #[arg(long)]
+    pub no_dht: bool,
+
+    /// Keep torrents running when the network goes away instead of pausing
+    /// them until it is back
+    #[arg(long)]
+    pub no_network_watch: bool,
+
+    /// UDP port of the DHT node (default: any free port)
+    #[arg(long, value_name = "PORT", default_value_t = 0, conflicts_with = "no_dht")]
+    pub dht_port: u16,
+
+    /// Node to join the DHT through, as HOST:PORT (repeatable, default:
+    /// router.bittorrent.com:6881)
+    #[arg(long = "dht-bootstrap", value_name = "ADDR", conflicts_with = "no_dht")]
+    pub dht_bootstrap: Vec<String>,
+
+    /// Give up on an HTTP tracker announce after this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub tracker_timeout: u64,
+
+    /// Number of HTTP redirects followed when announcing (0 to disable)
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub tracker_max_redirects: usize,
+
+    /// PEM file with an extra root certificate trusted for HTTPS trackers
+    #[arg(long, value_name = "FILE")]
+    pub tracker_ca: Option<PathBuf>,
+
+    /// Times an unanswered UDP tracker request is sent again, each waiting
+    /// twice as long as the last from 15 seconds
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub tracker_udp_retries: u32,
+
+    /// Don't ask trackers for gzip-compressed responses
+    #[arg(long)]
+    pub no_tracker_gzip: bool,
+
+    /// Authenticate to trackers with HTTP basic auth (password optional)
+    #[arg(long, value_name = "USER[:PASSWORD]")]
+    pub tracker_auth: Option<String>,
+
+    /// Send this header with every tracker request (repeatable)
+    #[arg(long = "tracker-header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub tracker_headers: Vec<(String, String)>,
+
+    /// Send this cookie with every tracker request
+    #[arg(long, value_name = "COOKIE")]
+    pub tracker_cookie: Option<String>,
+
+    /// Exit once downloads complete instead of seeding
+    #[arg(long, alias = "no-seed")]
+    pub exit_when_done: bool,
+
+    /// Write a JSON report of the run to this file (`-` for stdout)
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Shell command to run when a torrent finishes downloading; it receives
+    /// TORRENTZ_NAME, TORRENTZ_PATH, TORRENTZ_HASH and TORRENTZ_LABEL
+    #[arg(long, value_name = "CMD")]
+    pub exec_on_complete: Option<String>,
+
+    /// POST a JSON payload to this URL when a torrent completes or fails,
+    /// or a tracker can't be reached
+    #[arg(long, value_name = "URL")]
+    pub webhook: Option<Url>,
+
+    /// Show a desktop notification for the same events as --webhook
+    #[arg(long)]
+    pub notify: bool,
+
+    /// Record every peer-wire message to this file (replay it with `torrentz replay`)
+    #[arg(long, value_name = "FILE")]
+    pub record_wire: Option<PathBuf>,
+
+    /// Log which peer delivered each downloaded piece, and whether it was corrupt, to this CSV file
+    #[arg(long, value_name = "FILE")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Append per-minute transfer totals of every torrent to this CSV file
+    #[arg(long, value_name = "FILE")]
+    pub usage_csv: Option<PathBuf>,
+
+    /// Outstanding block requests accepted from each peer (default: 250)
+    #[arg(long, value_name = "N")]
+    pub max_requests: Option<NonZeroUsize>,
+
+    /// Bytes asked of a peer per request, a power of two from 1K to 128K
+    /// (default: 16K; many peers refuse anything larger)
+    #[arg(long, value_name = "SIZE", value_parser = parse_block_size)]
+    pub block_size: Option<u32>,
+
+    /// Threads used to hash pieces in parallel (default: one per core)
+    #[arg(long, value_name = "N")]
+    pub hash_workers: Option<NonZeroUsize>,
+
+    /// Pin hashing threads to these CPU cores, e.g. 0,2,4 (advanced)
+    #[arg(long, value_name = "LIST", value_delimiter = ',')]
+    pub hash_cores: Vec<usize>,
+
+    /// Hash the pieces already on disk again instead of trusting the resume file
+    #[arg(long)]
+    pub recheck: bool,
+
+    /// Only download the pieces of this file (path inside the torrent)
+    #[arg(long, value_name = "PATH", conflicts_with = "range")]
+    pub file: Option<PathBuf>,
+
+    /// Only download the pieces overlapping this byte range of the content,
+    /// given as START-END with END inclusive
+    #[arg(long, value_name = "START-END", value_parser = parse_range)]
+    pub range: Option<Range<u64>>,
+
+    /// Download the first and last piece of every selected file first (for media)
+    #[arg(long)]
+    pub first_last_first: bool,
+
+    /// Download the files fewest peers can complete first (for poorly seeded torrents)
+    #[arg(long)]
+    pub rare_files_first: bool,
+
+    /// Label attached to the torrents, passed on to hooks
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// Refuse to start torrents whose selected content exceeds this size,
+    /// in bytes or with a K, M, G or T suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub quota: Option<u64>,
+
+    /// Quota of the torrents with this label, unless --quota is given (repeatable)
+    #[arg(long = "label-quota", value_name = "LABEL=SIZE", value_parser = parse_label_quota)]
+    pub label_quotas: Vec<(String, u64)>,
+
+    /// Stop seeding once the upload/download ratio reaches this value
+    #[arg(long, value_name = "RATIO")]
+    pub seed_ratio: Option<f64>,
+
+    /// Stop seeding after this many minutes
+    #[arg(long, value_name = "MINUTES")]
+    pub seed_time: Option<u64>,
+
+    /// Stop seeding after this many minutes without uploading
+    #[arg(long, value_name = "MINUTES")]
+    pub seed_idle: Option<u64>,
+}
+
+impl DownloadArgs {
+    /// Builds the session configuration shared by every torrent
+    pub fn config(&self) -> Result<Config, ApplicationError> {
+        let mut state_passphrase = None;
+        if self.encrypt_state {
+            let passphrase = env::var(STATE_PASSPHRASE_ENV).map_err(|_| {
+                ApplicationError::ParserError(format!("--encrypt-state needs {} to be set", STATE_PASSPHRASE_ENV))
+            })?;
+            state_passphrase = Some(Passphrase::new(&passphrase));
+        }
+
+        Ok(Config {
+            download_dir: self.output.clone(),
+            incomplete_dir: self.incomplete_dir.clone(),
+            event_log: self.event_log.clone(),
+            event_categories: if self.event_categories.is_empty() {
+                EventMask::ALL
+            } else {
+                self.event_categories.iter().copied().collect()
+            },
+            ban_list: self.ban_list.clone(),
+            shared_identity: self.shared_identity,
+            announce_ip: self.announce_ip,
+            bind_ip: self.bind_ip,
+            listen_port: self.port,
+            listen_ips: self.listen_ips.clone(),
+            limits: match &self.config_file {
+                Some(path) => Limits::load(path)?.or(self.limits()),
+                None       => self.limits(),
+            },
+            peer_sockets: PeerSockets {
+                nodelay:     self.tcp_nodelay,
+                send_buffer: self.socket_send_buffer,
+                recv_buffer: self.socket_recv_buffer,
+                keepalive:   self.tcp_keepalive.map(|idle| Keepalive {
+                    idle:     Duration::from_secs(idle),
+                    interval: self.tcp_keepalive_interval.map(Duration::from_secs),
+                    retries:  self.tcp_keepalive_retries,
+                }),
+                dscp:        self.dscp,
+            },
+            stop_after_download: self.exit_when_done,
+            webhook: self.webhook.clone(),
+            desktop_notifications: self.notify,
+            record_wire: self.record_wire.clone(),
+            audit_log: self.audit_log.clone(),
+            usage_csv: self.usage_csv.clone(),
+            hash_workers: self.hash_workers,
+            hash_cores: self.hash_cores.clone(),
+            recheck: self.recheck,
+            block_size: self.block_size,
+            dht: !self.no_dht,
+            dht_port: self.dht_port,
+            dht_bootstrap: self.dht_bootstrap.clone(),
+            network_watch: !self.no_network_watch,
+            tracker_http: TrackerHttp {
+                timeout:       Duration::from_secs(self.tracker_timeout),
+                max_redirects: self.tracker_max_redirects,
+                ca_cert:       self.tracker_ca.clone(),
+                gzip:          !self.no_tracker_gzip,
+            },
+            tracker_udp: TrackerUdp { retries: self.tracker_udp_retries },
+            seed_limits: SeedLimits {
+                ratio: self.seed_ratio,
+                time:  self.seed_time.map(|m| Duration::from_secs(m * 60)),
+                idle:  self.seed_idle.map(|m| Duration::from_secs(m * 60)),
+            },
+            label_quotas: self.label_quotas.iter().cloned().collect(),
+            state_passphrase,
+            ..Config::default()
+        })
+    }
+
+    /// Returns the limits set by flags, which the `--config` file overrides
+    pub fn limits(&self) -> Limits {
+        Limits {
+            download_limit:      self.download_limit,
+            upload_limit:        self.upload_limit,
+            peer_download_limit: self.peer_download_limit,
+            peer_upload_limit:   self.peer_upload_limit,
+            upload_slots:        self.upload_slots,
+            max_peers:           self.max_peers,
+            max_connections:     self.max_connections,
+            max_requests:        self.max_requests,
+            ..Limits::default()
+        }
+    }
+
+    /// Builds the settings applied to `torrent`
+    pub fn options(&self, torrent: &Torrent) -> Result<TorrentOptions, ApplicationError> {
+        let range = match &self.file {
+            Some(path) => Some(torrent.file_range(path).ok_or_else(|| {
+                ApplicationError::ParserError(format!(
+                    "{} has no file {}",
+                    torrent.info.name,
+                    path.display()
+                ))
+            })?),
+            None => self.range.clone(),
+        };
+
+        let mut headers = self.tracker_headers.clone();
+        if let Some(cookie) = &self.tracker_cookie {
+            headers.push(("Cookie".into(), cookie.clone()));
+        }
+        let basic = self.tracker_auth.as_ref().map(|auth| match auth.split_once(':') {
+            Some((user, password)) => (user.to_string(), Some(password.to_string())),
+            None                   => (auth.clone(), None),
+        });
+
+        Ok(TorrentOptions {
+            label:            self.label.clone(),
+            exec_on_complete: self.exec_on_complete.clone(),
+            range,
+            first_last_first: self.first_last_first,
+            rare_files_first: self.rare_files_first,
+            tracker_auth:     TrackerAuth { basic, headers },
+            quota:            self.quota,
+        })
+    }
+}
+
+/// Address of a peer given on the command line
+#[derive(Debug, Clone)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Host(String, u16),
+}
+
+impl PeerAddr {
+    /// Resolves the address into a manually added peer
+    pub async fn resolve(&self) -> Result<Peer, ApplicationError> {
+        let (addr, host) = match self {
+            PeerAddr::Ip(addr)         => (*addr, None),
+            PeerAddr::Host(host, port) => {
+                let addr = lookup_host((host.as_str(), *port))
+                    .await
+                    .map_err(|e| ApplicationError::PeerError(format!("{}: {}", host, e)))?
+                    .next()
+                    .ok_or_else(|| ApplicationError::PeerError(format!("{}: no address", host)))?;
+                (addr, Some(host.clone()))
+            }
+        };
+        Ok(Peer {
+            ip:      addr.ip(),
+            port:    addr.port(),
+            source:  PeerSource::Manual,
+            peer_id: None,
+            host,
+        })
+    }
+}
+
+fn parse_peer(value: &str) -> Result<PeerAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(PeerAddr::Ip(addr));
+    }
+    let (host, port) = value
+        .rsplit_once(':')
+        .ok_or_else(|| "expected IP:PORT or HOST:PORT".to_string())?;
+    let port = port.parse::<u16>().map_err(|e| e.to_string())?;
+    Ok(PeerAddr::Host(host.to_string(), port))
+}
+
+/// Parses a `NAME: VALUE` HTTP header
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value
+        .split_once(':')
+        .ok_or_else(|| "expected NAME: VALUE".to_string())?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Parses a size in bytes, optionally followed by a binary K, M, G or T suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value           = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 30),
+        Some((i, 'T' | 't')) => (&value[..i], 40),
+        _                    => (value, 0),
+    };
+    let number = digits.trim().parse::<u64>().map_err(|e| e.to_string())?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "size too large".to_string())
+}
+
+/// Parses a socket buffer size, with the suffixes of [`parse_size`]
+fn parse_buffer_size(value: &str) -> Result<usize, String> {
+    usize::try_from(parse_size(value)?).map_err(|_| "size too large".to_string())
+}
+
+/// Parses a request size, with the suffixes of [`parse_size`]
+fn parse_block_size(value: &str) -> Result<u32, String> {
+    let size = parse_size(value)?;
+    if !size.is_power_of_two() || !(1 << 10..=1 << 17).contains(&size) {
+        return Err("expected a power of two from 1K to 128K".into());
+    }
+    Ok(size as u32)
+}
+
+/// Parses a `LABEL=SIZE` quota
+fn parse_label_quota(value: &str) -> Result<(String, u64), String> {
+    let (label, size) = value
+        .split_once('=')
+        .ok_or_else(|| "expected LABEL=SIZE".to_string())?;
+    Ok((label.to_string(), parse_size(size)?))
+}
+
+/// Parses an inclusive `START-END` byte range
+fn parse_range(value: &str) -> Result<Range<u64>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| "expected START-END".to_string())?;
+    let start = start.trim().parse::<u64>().map_err(|e| e.to_string())?;
+    let end   = end.trim().parse::<u64>().map_err(|e| e.to_string())?;
+    if end < start {
+        return Err("END must not be before START".into());
+    }
+    let end   = end.checked_add(1).ok_or("END too large")?;
+    Ok(start..end)
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{error::ApplicationError, events::EventMask, inspect::Inspectors, seal::Passphrase};
+
+/// Settings shared by every torrent of a session
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Directory downloaded files are written to; the working directory if empty
+    pub download_dir: PathBuf,
+    /// Directory torrents are downloaded to until they complete, then
+    /// moved to `download_dir`; straight to `download_dir` if `None`
+    pub incomplete_dir: Option<PathBuf>,
+    /// Append every engine event as a JSON line to this file
+    pub event_log: Option<PathBuf>,
+    /// Categories of events written to the event log; all of them by default
+    pub event_categories: EventMask,
+    /// Rate limits, connection caps and queue sizes; the only settings
+    /// that can change while the session runs
+    pub limits: Limits,
+    /// File where banned peer addresses are persisted across restarts
+    pub ban_list: Option<PathBuf>,
+    /// Use the same peer id and announce key for every torrent instead of
+    /// generating a distinct pair per torrent
+    pub shared_identity: bool,
+    /// Address reported to trackers, for hosts behind NAT with a known
+    /// public IP or with several interfaces
+    pub announce_ip: Option<IpAddr>,
+    /// Local address every outgoing connection is made from, peers and
+    /// trackers alike, e.g. the address of a VPN interface
+    pub bind_ip: Option<IpAddr>,
+    /// TCP port peers connect to, announced to trackers and the DHT; any
+    /// free one if 0
+    pub listen_port: u16,
+    /// Local addresses peers connect to, e.g. an IPv4, an IPv6 and a VPN
+    /// address, all on `listen_port`; `bind_ip`, or every IPv4 and IPv6
+    /// address if empty. Specific ones are told to trackers and peers
+    pub listen_ips: Vec<IpAddr>,
+    /// Options of the TCP sockets connecting to peers
+    pub peer_sockets: PeerSockets,
+    /// Stop torrents as soon as their download completes instead of seeding
+    pub stop_after_download: bool,
+    /// Conditions that end seeding; without any, torrents seed until stopped
+    pub seed_limits: SeedLimits,
+    /// Largest download in bytes allowed for torrents with a given label,
+    /// unless they set [`TorrentOptions::quota`]
+    pub label_quotas: HashMap<String, u64>,
+    /// Shell command run whenever a torrent finishes downloading
+    pub exec_on_complete: Option<String>,
+    /// URL receiving a JSON POST when a torrent completes or fails, or a
+    /// tracker can't be reached
+    pub webhook: Option<Url>,
+    /// Show a desktop notification for the same events as the webhook
+    pub desktop_notifications: bool,
+    /// Record every peer-wire message to this file, for debugging
+    pub record_wire: Option<PathBuf>,
+    /// Append a CSV line per downloaded piece to this file: the peer that
+    /// sent it and whether it matched its hash
+    pub audit_log: Option<PathBuf>,
+    /// Append per-minute transfer totals of every torrent to this CSV file
+    pub usage_csv: Option<PathBuf>,
+    /// Also look for peers in the DHT (BEP 5); never for private torrents
+    pub dht: bool,
+    /// UDP port of the DHT node; any free one if 0
+    pub dht_port: u16,
+    /// `host:port` of the nodes the DHT is joined through;
+    /// [`DEFAULT_BOOTSTRAP`](crate::dht::DEFAULT_BOOTSTRAP) if empty
+    pub dht_bootstrap: Vec<String>,
+    /// Pause the running torrents while the machine has no routable address,
+    /// resuming and announcing them again once it has one
+    pub network_watch: bool,
+    /// Settings of the HTTP client shared by all announces
+    pub tracker_http: TrackerHttp,
+    /// Settings of the UDP client shared by all announces
+    pub tracker_udp: TrackerUdp,
+    /// Threads hashing pieces in parallel; one per core if `None`
+    pub hash_workers: Option<NonZeroUsize>,
+    /// Cores the hashing threads are pinned to, each given a contiguous run
+    /// of pieces; empty leaves scheduling to the OS. For large, multi-socket
+    /// machines where threads migrating between cores slow hashing down
+    pub hash_cores: Vec<usize>,
+    /// Hooks on the messages exchanged with peers, for embedders
+    pub inspectors: Inspectors,
+    /// Hash the pieces a resume file lists as verified instead of trusting it
+    pub recheck: bool,
+    /// Bytes asked of a peer per request; [`BLOCK_SIZE`](crate::engine::BLOCK_SIZE)
+    /// if `None`
+    pub block_size: Option<u32>,
+    /// Encrypt the state files kept across runs: the ban list and resume files
+    pub state_passphrase: Option<Passphrase>,
+}
+
+/// Session settings that can be changed without restarting, see
+/// [`Session::reconfigure`](crate::session::Session::reconfigure)
+///
+/// Also the format of the `--config` file, a JSON object whose keys are
+/// the fields below; missing ones keep their default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Global download limit in bytes per second (unlimited if `None`)
+    pub download_limit:      Option<u64>,
+    /// Global upload limit in bytes per second (unlimited if `None`)
+    pub upload_limit:        Option<u64>,
+    /// Download limit of each peer connection in bytes per second, within
+    /// the global one (unlimited if `None`)
+    pub peer_download_limit: Option<u64>,
+    /// Upload limit of each peer connection in bytes per second, within
+    /// the global one (unlimited if `None`)
+    pub peer_upload_limit:   Option<u64>,
+    /// Peers unchoked at once across all torrents, i.e. uploaded to;
+    /// 4 if `None`
+    pub upload_slots:        Option<NonZeroUsize>,
+    /// Peers each torrent downloads from at once; 10 if `None`
+    pub max_peers:           Option<NonZeroUsize>,
+    /// Peer connections open at once across all torrents, the ones peers
+    /// opened included; 200 if `None`
+    pub max_connections:     Option<NonZeroUsize>,
+    /// Connections peers open to each torrent kept at once; 50 if `None`
+    pub max_incoming:        Option<NonZeroUsize>,
+    /// Outstanding block requests accepted from each peer, advertised to
+    /// peers supporting the extension protocol; 250 if `None`. Changes
+    /// only apply to connections opened afterwards
+    pub max_requests:        Option<NonZeroUsize>,
+}
+
+impl Limits {
+    /// Reads limits from a JSON file
+    pub fn load(path: &Path) -> Result<Self, ApplicationError> {
+        let data = fs::read(path)
+            .map_err(|e| ApplicationError::ParserError(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_slice(&data)
+            .map_err(|e| ApplicationError::ParserError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Fills the limits `self` leaves unset from `base`
+    pub fn or(self, base: Limits) -> Limits {
+        Limits {
+            download_limit:      self.download_limit.or(base.download_limit),
+            upload_limit:        self.upload_limit.or(base.upload_limit),
+            peer_download_limit: self.peer_download_limit.or(base.peer_download_limit),
+            peer_upload_limit:   self.peer_upload_limit.or(base.peer_upload_limit),
+            upload_slots:        self.upload_slots.or(base.upload_slots),
+            max_peers:           self.max_peers.or(base.max_peers),
+            max_connections:     self.max_connections.or(base.max_connections),
+            max_incoming:        self.max_incoming.or(base.max_incoming),
+            max_requests:        self.max_requests.or(base.max_requests),
+        }
+    }
+}
+
+/// Options set on every TCP socket connecting to a peer; the defaults
+/// leave the operating system's settings alone
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerSockets {
+    /// Send small messages such as requests right away instead of
+    /// coalescing them (disables Nagle's algorithm)
+    pub nodelay:     bool,
+    /// Size of the kernel send buffer (`SO_SNDBUF`) in bytes
+    pub send_buffer: Option<usize>,
+    /// Size of the kernel receive buffer (`SO_RCVBUF`) in bytes
+    pub recv_buffer: Option<usize>,
+    /// Probe idle connections so dead peers are noticed
+    pub keepalive:   Option<Keepalive>,
+    /// DSCP codepoint (0-63) marked on outgoing packets, for traffic shaping
+    pub dscp:        Option<u8>,
+}
+
+/// TCP keepalive settings of a peer connection
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// Idle time before the first probe
+    pub idle:     Duration,
+    /// Time between probes; the OS default if `None`
+    pub interval: Option<Duration>,
+    /// Unanswered probes after which the connection is dropped; the OS default if `None`
+    pub retries:  Option<u32>,
+}
+
+/// Settings of the HTTP client used to talk to trackers
+#[derive(Debug, Clone)]
+pub struct TrackerHttp {
+    /// Limit on a whole announce, from connecting to reading the response
+    pub timeout:       Duration,
+    /// Number of redirects followed before giving up; 0 disables redirects
+    pub max_redirects: usize,
+    /// PEM file with an extra root certificate, for trackers using a private CA
+    pub ca_cert:       Option<PathBuf>,
+    /// Accept gzip-compressed responses
+    pub gzip:          bool,
+}
+
+impl Default for TrackerHttp {
+    fn default() -> Self {
+        Self {
+            timeout:       Duration::from_secs(30),
+            max_redirects: 10,
+            ca_cert:       None,
+            gzip:          true,
+        }
+    }
+}
+
+/// Settings of the client used to talk to UDP trackers (BEP 15)
+#[derive(Debug, Clone)]
+pub struct TrackerUdp {
+    /// Unanswered requests sent again before giving up on a tracker, each
+    /// waiting twice as long as the last from 15 seconds. BEP 15 allows 8,
+    /// over two hours when the tracker is down
+    pub retries: u32,
+}
+
+impl Default for TrackerUdp {
+    fn default() -> Self {
+        Self { retries: 1 }
+    }
+}
+
+/// Settings of a single torrent, overriding the session-wide [`Config`]
+#[derive(Debug, Clone, Default)]
+pub struct TorrentOptions {
+    /// Free-form label, passed on to hooks
+    pub label:            Option<String>,
+    /// Shell command run when this torrent finishes downloading, instead of
+    /// [`Config::exec_on_complete`]
+    pub exec_on_complete: Option<String>,
+    /// Only download the pieces overlapping this byte range of the content
+    pub range:            Option<Range<u64>>,
+    /// Download the first and last piece of every selected file before the
+    /// rest, so media players can read headers and indexes early
+    pub first_last_first: bool,
+    /// Download the files fewest connected peers can complete first, to get
+    /// them while someone still has them on poorly seeded torrents
+    pub rare_files_first: bool,
+    /// Credentials for private trackers
+    pub tracker_auth:     TrackerAuth,
+    /// Refuse to add the torrent if the selected content exceeds this many bytes
+    pub quota:            Option<u64>,
+}
+
+/// Credentials sent with every request to a torrent's trackers
+///
+/// Passkeys embedded in the announce URL need nothing here; this covers
+/// trackers that want HTTP authentication or a session cookie.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerAuth {
+    /// User name and optional password for HTTP basic authentication
+    pub basic:   Option<(String, Option<String>)>,
+    /// Extra headers, such as `Cookie`
+    pub headers: Vec<(String, String)>,
+}
+
+/// Conditions that end seeding once a download is complete
+///
+/// Seeding stops as soon as any of the configured limits is reached.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeedLimits {
+    /// Stop once uploaded / downloaded bytes reach this ratio
+    pub ratio: Option<f64>,
+    /// Stop after seeding for this long
+    pub time:  Option<Duration>,
+    /// Stop after this long without uploading anything
+    pub idle:  Option<Duration>,
+}
@@ -0,0 +1,106 @@
+use std::{fs, ops::Range, path::PathBuf, sync::Arc};
+
+use tokio::sync::watch;
+
+use crate::{bitfield::Bitfield, storage::Storage};
+
+/// A torrent of the session whose verified pieces torrents added later can
+/// reuse, when they share its files
+#[derive(Debug, Clone)]
+pub struct SeedSource {
+    pub storage:  Arc<Storage>,
+    /// Expected SHA-1 of every piece
+    pub hashes:   Arc<[[u8; 20]]>,
+    /// Pieces written to disk, updated as they are downloaded
+    pub verified: watch::Receiver<Bitfield>,
+}
+
+/// A file of a torrent, identified by its path with links resolved
+struct SharedFile {
+    /// `None` if the file can't be found on disk
+    path:  Option<PathBuf>,
+    range: Range<u64>,
+}
+
+impl SharedFile {
+    fn of(storage: &Storage) -> Vec<SharedFile> {
+        storage
+            .files()
+            .into_iter()
+            .map(|(path, range)| SharedFile {
+                path: fs::canonicalize(path).ok(),
+                range,
+            })
+            .collect()
+    }
+
+    fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+}
+
+/// Returns the pieces of the torrent in `storage` that `sources` already
+/// verified on disk, so they need no hashing
+///
+/// A piece qualifies when every file it overlaps is also a file of the
+/// same source, at the same path and of the same size, and its bytes line
+/// up with a verified piece of that source: same offset in the shared
+/// files, same length and same hash. This reads the file system and blocks.
+pub fn reusable(storage: &Storage, hashes: &[[u8; 20]], sources: &[SeedSource]) -> Bitfield {
+    let geometry   = storage.geometry();
+    let ours       = SharedFile::of(storage);
+    let mut reused = Bitfield::new(hashes.len());
+
+    for source in sources {
+        let verified = source.verified.borrow().clone();
+        if verified.count_ones() == 0 {
+            continue;
+        }
+        let theirs = SharedFile::of(&source.storage);
+        // Where each of our files starts in the source's content, if it has it
+        let starts = ours
+            .iter()
+            .map(|file| {
+                let path = file.path.as_ref()?;
+                theirs
+                    .iter()
+                    .find(|f| f.path.as_ref() == Some(path) && f.len() == file.len())
+                    .map(|f| f.range.start)
+            })
+            .collect::<Vec<_>>();
+        let their_geometry = source.storage.geometry();
+
+        for piece in geometry.pieces() {
+            if reused.get(piece.get()) {
+                continue;
+            }
+            let start = geometry.piece_offset(piece);
+            let end   = start + geometry.piece_len(piece) as u64;
+
+            // Where the piece starts in the source's content, if the files it
+            // overlaps sit there back to back as in ours
+            let mut offsets = ours
+                .iter()
+                .zip(&starts)
+                .filter(|(file, _)| !file.range.is_empty() && file.range.start < end && start < file.range.end)
+                .map(|(file, their_start)| their_start.and_then(|s| (s + start).checked_sub(file.range.start)));
+            let Some(Some(offset)) = offsets.next() else {
+                continue;
+            };
+            if !offsets.all(|o| o == Some(offset)) {
+                continue;
+            }
+
+            let Some(their_piece) = their_geometry.piece_at(offset) else {
+                continue;
+            };
+            if their_geometry.piece_len(their_piece) == geometry.piece_len(piece)
+                && source.hashes.get(their_piece.get()) == Some(&hashes[piece.get()])
+                && verified.get(their_piece.get())
+            {
+                reused.set(piece.get(), true);
+            }
+        }
+    }
+    reused
+}
@@ -0,0 +1,688 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::join_all,
+    stream::{FuturesUnordered, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    sync::oneshot,
+    task,
+    time,
+};
+
+use crate::{
+    error::ApplicationError,
+    peer::{Peer, PeerSource},
+};
+
+/// Nodes the DHT is joined through when none are configured
+pub const DEFAULT_BOOTSTRAP: &[&str] = &["router.bittorrent.com:6881"];
+
+/// Limit on a whole peer lookup; peers found until then are kept
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Nodes per bucket of the routing table, and nodes a lookup converges on (BEP 5)
+const K: usize = 8;
+
+/// Queries a lookup keeps in flight at once
+const ALPHA: usize = 3;
+
+/// How long a node may take to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Nodes not heard from for this long make room for new ones in full buckets
+const NODE_STALE: Duration = Duration::from_secs(15 * 60);
+
+/// How often the secret behind `get_peers` tokens changes; tokens made with
+/// the previous secret are still accepted
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Peers remembered per info hash from `announce_peer` queries
+const MAX_STORED_PEERS: usize = 100;
+
+/// Largest KRPC datagram accepted
+const MAX_PACKET: usize = 64 * 1024;
+
+/// Length of a node in compact node info: id, IPv4 address and port
+const COMPACT_NODE_LEN: usize = 26;
+
+/// KRPC error codes sent back to misbehaving nodes (BEP 5)
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD: i64   = 204;
+
+type NodeId = [u8; 20];
+
+/// Where the answer to a query goes
+type Reply = oneshot::Sender<Result<Body, ApplicationError>>;
+
+/// A KRPC message: a query, its response or an error (BEP 5)
+///
+/// Fields are declared in key order, as bencode dictionaries require.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Message {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<Body>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Body>,
+    t: ByteBuf,
+    y: String,
+}
+
+/// Arguments of a query or values of a response; every method uses a few of them
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Body {
+    id:           ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash:    Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes:        Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port:         Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target:       Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token:        Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values:       Option<Vec<ByteBuf>>,
+}
+
+/// A DHT node we know the id of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Contact {
+    id:   NodeId,
+    addr: SocketAddrV4,
+}
+
+#[derive(Debug)]
+struct Bucketed {
+    contact: Contact,
+    seen:    Instant,
+}
+
+/// Known nodes, in 160 buckets of at most [`K`] by distance to our id
+#[derive(Debug)]
+struct RoutingTable {
+    own:     NodeId,
+    buckets: Vec<Vec<Bucketed>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Index of the bucket `id` belongs to: the number of leading bits it
+    /// shares with our id; `None` for our own id
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own, id);
+        let zeros    = distance
+            .iter()
+            .position(|b| *b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// Records that `contact` answered; it only gets in if its bucket has
+    /// room or holds a node not heard from for [`NODE_STALE`]
+    fn insert(&mut self, contact: Contact) {
+        let Some(index) = self.bucket(&contact.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(known) = bucket.iter_mut().find(|b| b.contact.id == contact.id) {
+            known.contact = contact;
+            known.seen    = Instant::now();
+            return;
+        }
+
+        let entry = Bucketed {
+            contact,
+            seen: Instant::now(),
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+        } else if let Some(stale) = bucket
+            .iter_mut()
+            .filter(|b| b.seen.elapsed() >= NODE_STALE)
+            .min_by_key(|b| b.seen)
+        {
+            *stale = entry;
+        }
+    }
+
+    fn remove(&mut self, addr: SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|b| b.contact.addr != addr);
+        }
+    }
+
+    /// Returns up to `count` nodes, the closest to `target` first
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut all = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|b| b.contact)
+            .collect::<Vec<_>>();
+        all.sort_by_key(|c| distance(&c.id, target));
+        all.truncate(count);
+        all
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// Secrets `get_peers` tokens are derived from, so `announce_peer` can
+/// check a token without remembering who got one
+#[derive(Debug)]
+struct Tokens {
+    current:  [u8; 16],
+    previous: [u8; 16],
+    rotated:  Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Self {
+            current:  rand::random(),
+            previous: rand::random(),
+            rotated:  Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current  = rand::random();
+            self.rotated  = Instant::now();
+        }
+    }
+
+    fn issue(&mut self, ip: &Ipv4Addr) -> Vec<u8> {
+        self.rotate();
+        token(&self.current, ip)
+    }
+
+    fn accepts(&mut self, ip: &Ipv4Addr, value: &[u8]) -> bool {
+        self.rotate();
+        token(&self.current, ip) == value || token(&self.previous, ip) == value
+    }
+}
+
+fn token(secret: &[u8; 16], ip: &Ipv4Addr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(ip.octets());
+    hasher.finalize()[..8].to_vec()
+}
+
+/// Cloneable handle to the session's DHT node (BEP 5)
+///
+/// The node answers other nodes' queries in the background for as long as
+/// a handle exists, and finds peers for torrents through [`Dht::get_peers`].
+/// Only IPv4 is supported.
+#[derive(Debug, Clone)]
+pub struct Dht {
+    node: Arc<Node>,
+}
+
+#[derive(Debug)]
+struct Node {
+    id:        NodeId,
+    socket:    Arc<UdpSocket>,
+    /// `host:port` of the nodes to join through while the table is empty
+    bootstrap: Vec<String>,
+    table:     Mutex<RoutingTable>,
+    /// Queries awaiting an answer, by transaction id, with the node asked
+    pending:   Mutex<HashMap<u16, (SocketAddrV4, Reply)>>,
+    tokens:    Mutex<Tokens>,
+    /// Peers that announced themselves to us, by info hash
+    stored:    Mutex<HashMap<NodeId, Vec<SocketAddrV4>>>,
+}
+
+/// Outcome of a walk toward a target id
+struct Lookup {
+    peers:   HashSet<SocketAddrV4>,
+    /// The closest nodes that answered, with the token they handed out
+    closest: Vec<(Contact, Option<ByteBuf>)>,
+}
+
+impl Dht {
+    /// Opens the node's UDP socket on `port` (any if 0), from `bind_ip` if
+    /// it is IPv4, and joins the DHT through `bootstrap` in the background
+    pub fn start(bind_ip: Option<IpAddr>, port: u16, bootstrap: Vec<String>) -> Result<Self, ApplicationError> {
+        let ip     = match bind_ip {
+            Some(IpAddr::V4(ip)) => ip,
+            _                    => Ipv4Addr::UNSPECIFIED,
+        };
+        let socket = StdUdpSocket::bind((ip, port))
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            })
+            .map_err(|e| ApplicationError::TrackerError(format!("DHT: {}", e)))?;
+
+        let id   = rand::random::<NodeId>();
+        let node = Arc::new(Node {
+            id,
+            socket:  Arc::new(socket),
+            bootstrap,
+            table:   Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            tokens:  Mutex::new(Tokens::new()),
+            stored:  Mutex::new(HashMap::new()),
+        });
+        task::spawn(receive(node.socket.clone(), Arc::downgrade(&node)));
+
+        let dht = Self { node };
+        task::spawn({
+            let dht = dht.clone();
+            async move {
+                let deadline = time::Instant::now() + LOOKUP_TIMEOUT;
+                dht.node.lookup(dht.node.id, false, deadline).await;
+                println!("DHT: {} nodes in the routing table", dht.node.table.lock().unwrap().len());
+            }
+        });
+        Ok(dht)
+    }
+
+    /// Pings the nodes a trackerless torrent suggests, adding the ones that
+    /// answer to the routing table; see [`Torrent::dht_nodes`](crate::torrent::Torrent::dht_nodes)
+    pub async fn add_nodes(&self, nodes: &[(String, u16)]) {
+        let mut addrs = Vec::new();
+        for (host, port) in nodes {
+            if let Ok(found) = lookup_host((host.as_str(), *port)).await {
+                addrs.extend(found.filter_map(v4));
+            }
+        }
+        join_all(addrs.into_iter().map(|addr| self.node.query(addr, "ping", self.node.args()))).await;
+    }
+
+    /// Finds peers of `info_hash`, then announces to the nodes closest to it
+    /// that we accept peers on `port`
+    ///
+    /// Gives up after [`LOOKUP_TIMEOUT`], returning what was found by then.
+    pub async fn get_peers(&self, info_hash: [u8; 20], port: u16) -> Vec<Peer> {
+        let deadline = time::Instant::now() + LOOKUP_TIMEOUT;
+        let lookup   = self.node.lookup(info_hash, true, deadline).await;
+
+        let announces = lookup.closest.into_iter().filter_map(|(contact, token)| {
+            let args = Body {
+                info_hash:    Some(ByteBuf::from(info_hash.to_vec())),
+                port:         Some(port as i64),
+                implied_port: Some(0),
+                token:        Some(token?),
+                ..self.node.args()
+            };
+            Some(self.node.query(contact.addr, "announce_peer", args))
+        });
+        let _ = time::timeout_at(deadline, join_all(announces)).await;
+
+        lookup
+            .peers
+            .into_iter()
+            .map(|addr| Peer {
+                ip:      IpAddr::V4(*addr.ip()),
+                port:    addr.port(),
+                source:  PeerSource::Dht,
+                peer_id: None,
+                host:    None,
+            })
+            .collect()
+    }
+}
+
+impl Node {
+    /// Arguments every query and response starts with: our id
+    fn args(&self) -> Body {
+        Body {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Body::default()
+        }
+    }
+
+    /// Walks toward `target`, asking the closest nodes known so far for
+    /// closer ones, [`ALPHA`] at a time, until the [`K`] closest have all
+    /// been asked or `deadline` passes
+    ///
+    /// With `peers` set the walk uses `get_peers` and collects the peers of
+    /// `target` along the way; otherwise it uses `find_node`, which fills
+    /// the routing table.
+    async fn lookup(&self, target: NodeId, peers: bool, deadline: time::Instant) -> Lookup {
+        let mut candidates = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|c| (Some(c.id), c.addr))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = self.bootstrap_nodes().await.into_iter().map(|addr| (None, addr)).collect();
+        }
+
+        let mut seen    = candidates.iter().map(|(_, addr)| *addr).collect::<HashSet<_>>();
+        let mut asked   = HashSet::new();
+        let mut queries = FuturesUnordered::new();
+        let mut lookup  = Lookup {
+            peers:   HashSet::new(),
+            closest: Vec::new(),
+        };
+        loop {
+            // Bootstrap nodes, whose id is unknown, sort first
+            candidates.sort_by_key(|(id, _)| id.map(|id| distance(&id, &target)));
+            while queries.len() < ALPHA
+                && let Some(&(_, addr)) = candidates.iter().take(K).find(|(_, addr)| !asked.contains(addr))
+            {
+                asked.insert(addr);
+                let args = if peers {
+                    Body {
+                        info_hash: Some(ByteBuf::from(target.to_vec())),
+                        ..self.args()
+                    }
+                } else {
+                    Body {
+                        target: Some(ByteBuf::from(target.to_vec())),
+                        ..self.args()
+                    }
+                };
+                let method = if peers { "get_peers" } else { "find_node" };
+                queries.push(async move { (addr, self.query(addr, method, args).await) });
+            }
+
+            let Ok(Some((addr, result))) = time::timeout_at(deadline, queries.next()).await else {
+                break;
+            };
+            let Some((id, body)) = result.ok().and_then(|body| Some((node_id(&body.id)?, body))) else {
+                candidates.retain(|(_, a)| *a != addr);
+                continue;
+            };
+
+            if let Some(candidate) = candidates.iter_mut().find(|(_, a)| *a == addr) {
+                candidate.0 = Some(id);
+            }
+            for contact in body.nodes.as_deref().map(|nodes| parse_nodes(nodes)).unwrap_or_default() {
+                if contact.id != self.id && seen.insert(contact.addr) {
+                    candidates.push((Some(contact.id), contact.addr));
+                }
+            }
+            for value in body.values.iter().flatten() {
+                lookup.peers.extend(parse_peer(value));
+            }
+            lookup.closest.push((Contact { id, addr }, body.token));
+        }
+
+        lookup.closest.sort_by_key(|(contact, _)| distance(&contact.id, &target));
+        lookup.closest.truncate(K);
+        lookup
+    }
+
+    /// Resolves the bootstrap nodes to their IPv4 addresses
+    async fn bootstrap_nodes(&self) -> Vec<SocketAddrV4> {
+        let mut addrs = Vec::new();
+        for host in &self.bootstrap {
+            match lookup_host(host.as_str()).await {
+                Ok(found) => addrs.extend(found.filter_map(v4)),
+                Err(e)    => println!("DHT bootstrap node {} unreachable: {}", host, e),
+            }
+        }
+        addrs
+    }
+
+    /// Sends a `method` query to `addr` and waits for the answer
+    ///
+    /// Nodes that answer go into the routing table; nodes that don't are
+    /// dropped from it.
+    async fn query(&self, addr: SocketAddrV4, method: &str, args: Body) -> Result<Body, ApplicationError> {
+        let (tx, rx)    = oneshot::channel();
+        let transaction = {
+            let mut pending = self.pending.lock().unwrap();
+            loop {
+                let id = rand::random::<u16>();
+                if let Entry::Vacant(entry) = pending.entry(id) {
+                    entry.insert((addr, tx));
+                    break id;
+                }
+            }
+        };
+        let msg = Message {
+            t: ByteBuf::from(transaction.to_be_bytes().to_vec()),
+            y: "q".into(),
+            q: Some(method.into()),
+            a: Some(args),
+            ..Message::default()
+        };
+
+        let result = match self.send(addr, &msg).await {
+            Ok(()) => match time::timeout(QUERY_TIMEOUT, rx).await {
+                Ok(Ok(answer)) => answer,
+                _ => Err(ApplicationError::TrackerError(format!("DHT node {} did not answer", addr))),
+            },
+            Err(e) => Err(e),
+        };
+        self.pending.lock().unwrap().remove(&transaction);
+
+        let mut table = self.table.lock().unwrap();
+        match result.as_ref().ok().and_then(|body| node_id(&body.id)) {
+            Some(id) => table.insert(Contact { id, addr }),
+            None     => table.remove(addr),
+        }
+        result
+    }
+
+    async fn send(&self, addr: SocketAddrV4, msg: &Message) -> Result<(), ApplicationError> {
+        let packet = serde_bencode::to_bytes(msg).map_err(|e| ApplicationError::TrackerError(format!("DHT: {}", e)))?;
+        self.socket
+            .send_to(&packet, addr)
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("DHT node {}: {}", addr, e)))?;
+        Ok(())
+    }
+
+    /// Hands a response to the query awaiting it, or answers a query
+    async fn dispatch(&self, msg: Message, from: SocketAddrV4) {
+        match msg.y.as_str() {
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(msg.t.as_ref()) else {
+                    return;
+                };
+                // Only the node asked may answer
+                let waiting = {
+                    let mut pending = self.pending.lock().unwrap();
+                    let id          = u16::from_be_bytes(transaction);
+                    match pending.get(&id) {
+                        Some((addr, _)) if *addr == from => pending.remove(&id),
+                        _                                => None,
+                    }
+                };
+                let Some((_, reply)) = waiting else {
+                    return;
+                };
+                let answer = match (msg.r, msg.e) {
+                    (Some(body), _) => Ok(body),
+                    (_, Some((code, message))) => Err(ApplicationError::TrackerError(format!(
+                        "DHT node {}: error {} {}",
+                        from, code, message
+                    ))),
+                    _ => Err(ApplicationError::TrackerError(format!("DHT node {}: empty answer", from))),
+                };
+                let _ = reply.send(answer);
+            }
+            "q" => {
+                let (Some(method), Some(args)) = (msg.q, msg.a) else {
+                    return;
+                };
+                let reply = match self.answer(&method, args, from) {
+                    Ok(body) => Message {
+                        t: msg.t,
+                        y: "r".into(),
+                        r: Some(body),
+                        ..Message::default()
+                    },
+                    Err(error) => Message {
+                        t: msg.t,
+                        y: "e".into(),
+                        e: Some(error),
+                        ..Message::default()
+                    },
+                };
+                let _ = self.send(from, &reply).await;
+            }
+            _ => {}
+        }
+    }
+
+    /// Builds the response to a query of another node, or the KRPC error to send back
+    fn answer(&self, method: &str, args: Body, from: SocketAddrV4) -> Result<Body, (i64, String)> {
+        let id = node_id(&args.id).ok_or((ERROR_PROTOCOL, "invalid id".to_string()))?;
+        self.table.lock().unwrap().insert(Contact { id, addr: from });
+
+        match method {
+            "ping" => Ok(self.args()),
+            "find_node" => {
+                let target = args
+                    .target
+                    .as_deref()
+                    .and_then(|target| node_id(target))
+                    .ok_or((ERROR_PROTOCOL, "invalid target".into()))?;
+                Ok(Body {
+                    nodes: Some(self.closest_nodes(&target)),
+                    ..self.args()
+                })
+            }
+            "get_peers" => {
+                let info_hash = args
+                    .info_hash
+                    .as_deref()
+                    .and_then(|info_hash| node_id(info_hash))
+                    .ok_or((ERROR_PROTOCOL, "invalid info_hash".into()))?;
+                let token     = self.tokens.lock().unwrap().issue(from.ip());
+                let peers     = self.stored.lock().unwrap().get(&info_hash).cloned().unwrap_or_default();
+                Ok(Body {
+                    token: Some(ByteBuf::from(token)),
+                    nodes: peers.is_empty().then(|| self.closest_nodes(&info_hash)),
+                    values: (!peers.is_empty()).then(|| peers.iter().map(compact_peer).collect()),
+                    ..self.args()
+                })
+            }
+            "announce_peer" => {
+                let info_hash = args
+                    .info_hash
+                    .as_deref()
+                    .and_then(|info_hash| node_id(info_hash))
+                    .ok_or((ERROR_PROTOCOL, "invalid info_hash".into()))?;
+                let valid     = args
+                    .token
+                    .as_deref()
+                    .is_some_and(|token| self.tokens.lock().unwrap().accepts(from.ip(), token));
+                if !valid {
+                    return Err((ERROR_PROTOCOL, "bad token".into()));
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => u16::try_from(port).map_err(|_| (ERROR_PROTOCOL, "invalid port".to_string()))?,
+                    _ => return Err((ERROR_PROTOCOL, "missing port".into())),
+                };
+
+                let mut stored = self.stored.lock().unwrap();
+                let peers      = stored.entry(info_hash).or_default();
+                let peer       = SocketAddrV4::new(*from.ip(), port);
+                if !peers.contains(&peer) {
+                    if peers.len() >= MAX_STORED_PEERS {
+                        peers.remove(0);
+                    }
+                    peers.push(peer);
+                }
+                Ok(self.args())
+            }
+            _ => Err((ERROR_METHOD, format!("method {} unknown", method))),
+        }
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        let closest = self.table.lock().unwrap().closest(target, K);
+        let mut out = Vec::with_capacity(closest.len() * COMPACT_NODE_LEN);
+        for contact in closest {
+            out.extend_from_slice(&contact.id);
+            out.extend_from_slice(&compact_peer(&contact.addr));
+        }
+        ByteBuf::from(out)
+    }
+}
+
+/// Reads datagrams until every [`Dht`] handle is gone
+async fn receive(socket: Arc<UdpSocket>, node: Weak<Node>) {
+    let mut buf = vec![0u8; MAX_PACKET];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(node) = node.upgrade() else {
+            return;
+        };
+        let (SocketAddr::V4(from), Ok(msg)) = (from, serde_bencode::from_bytes::<Message>(&buf[..len])) else {
+            continue;
+        };
+        node.dispatch(msg, from).await;
+    }
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn node_id(bytes: &[u8]) -> Option<NodeId> {
+    bytes.try_into().ok()
+}
+
+fn v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(_)    => None,
+    }
+}
+
+/// Parses compact node info: 20-byte id, 4-byte IP and 2-byte port per node
+fn parse_nodes(data: &[u8]) -> Vec<Contact> {
+    data.chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            Some(Contact {
+                id:   node_id(&chunk[..20])?,
+                addr: parse_peer(&chunk[20..])?,
+            })
+        })
+        .collect()
+}
+
+/// Parses compact peer info: 4-byte IP and 2-byte port
+fn parse_peer(data: &[u8]) -> Option<SocketAddrV4> {
+    let [a, b, c, d, hi, lo] = <[u8; 6]>::try_from(data).ok()?;
+    let addr = SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), u16::from_be_bytes([hi, lo]));
+    (addr.port() != 0).then_some(addr)
+}
+
+fn compact_peer(addr: &SocketAddrV4) -> ByteBuf {
+    let mut out = addr.ip().octets().to_vec();
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    ByteBuf::from(out)
+}
@@ -0,0 +1,133 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time,
+};
+
+use crate::config::PeerSockets;
+
+/// Head start given to each connection attempt before the next one begins
+/// (RFC 8305 recommends 250 ms)
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to the first reachable address, racing attempts Happy Eyeballs style
+///
+/// Addresses are tried alternating between IPv6 and IPv4, starting with the
+/// family of the first one. A new attempt starts whenever the previous one
+/// fails or has been pending for [`ATTEMPT_DELAY`]; the first connection
+/// established wins and the others are dropped.
+///
+/// With `bind` set every attempt is made from that local address, and
+/// addresses of the other family are skipped. Every socket gets `options`
+/// before connecting.
+pub async fn connect(addrs: &[SocketAddr], bind: Option<IpAddr>, options: &PeerSockets) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = match bind {
+        Some(ip) => addrs.iter().copied().filter(|a| a.is_ipv6() == ip.is_ipv6()).collect(),
+        None     => addrs.to_vec(),
+    };
+    let mut pending  = interleave(&addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut error    = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr, bind, options)),
+                None       => {
+                    return Err(error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+                    }));
+                }
+            }
+        }
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e)     => {
+                    error = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(attempt(addr, bind, options));
+                    }
+                }
+            },
+            _ = time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(attempt(addr, bind, options));
+                }
+            }
+        }
+    }
+}
+
+/// Connects to `addr`, from `bind` if set
+async fn attempt(addr: SocketAddr, bind: Option<IpAddr>, options: &PeerSockets) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(ip) = bind {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    tune(SockRef::from(&socket), &addr, options)?;
+    socket.connect(addr).await
+}
+
+/// Applies `options` to a peer socket
+///
+/// Sockets we connect get them before connecting, so buffer sizes are taken
+/// into account for the TCP window.
+pub fn tune(socket: SockRef<'_>, addr: &SocketAddr, options: &PeerSockets) -> io::Result<()> {
+    if options.nodelay {
+        socket.set_tcp_nodelay(true)?;
+    }
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(keepalive) = &options.keepalive {
+        let mut params = TcpKeepalive::new().with_time(keepalive.idle);
+        if let Some(interval) = keepalive.interval {
+            params = params.with_interval(interval);
+        }
+        if let Some(retries) = keepalive.retries {
+            params = params.with_retries(retries);
+        }
+        socket.set_tcp_keepalive(&params)?;
+    }
+    if let Some(dscp) = options.dscp {
+        // DSCP is the upper six bits of the TOS / traffic class byte
+        let tos = u32::from(dscp) << 2;
+        match addr {
+            SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+            SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+        }
+    }
+    Ok(())
+}
+
+/// Orders `addrs` alternating address families, keeping their relative order
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = match addrs.first() {
+        Some(addr) => addrs.iter().partition(|a| a.is_ipv6() == addr.is_ipv6()),
+        None       => return Vec::new(),
+    };
+    first.reverse();
+    second.reverse();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while !first.is_empty() || !second.is_empty() {
+        ordered.extend(first.pop());
+        ordered.extend(second.pop());
+    }
+    ordered
+}
@@ -0,0 +1,1892 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use tokio::{
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot, watch},
+    task::{self, JoinSet},
+    time,
+};
+
+use crate::{
+    audit::{AuditLog, PieceAudit, Verdict},
+    banlist::BanList,
+    bitfield::Bitfield,
+    config::{Limits, PeerSockets, SeedLimits, TrackerAuth},
+    crossseed::SeedSource,
+    dht::{self, Dht},
+    error::ApplicationError,
+    events::{Event, Events},
+    geometry::{Geometry, PieceIndex},
+    hooks::{self, HookEnv},
+    identity::Identity,
+    inspect::Inspectors,
+    listen::Endpoints,
+    manager::PieceManager,
+    metadata::Metadata,
+    peer::{ConnectionSettings, DEFAULT_MAX_REQUESTS, DisconnectReason, Download, Peer, PeerConnection, PeerInfo, PeerSource},
+    piece::Piece,
+    protocol::{Handshake, Message},
+    ratelimit::Throttle,
+    recorder::Recorder,
+    resume::ResumeFile,
+    stats::{SourceStats, SwarmCounts, TorrentStats},
+    session::TorrentId,
+    storage::Storage,
+    torrent::Torrent,
+    tracker::{Announce, AnnounceEvent, Progress, Scrape, Tracker, TrackerStatus},
+};
+
+pub const BLOCK_SIZE: usize  = 16 * 1024;
+/// Peers a torrent downloads from at once unless configured otherwise
+pub const CONCURRENCY: usize = 10;
+pub const BATCH_SIZE: usize  = 20;
+
+/// Bounds of the batch handed to a peer, depending on its throughput
+const MIN_BATCH_SIZE: usize = 2;
+const MAX_BATCH_SIZE: usize = 64;
+
+/// Peers uploaded to at once unless configured otherwise
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// Peer connections open at once across all torrents unless configured otherwise
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+/// Peers downloading the same piece at once in endgame mode, once no piece
+/// is left to hand out
+const ENDGAME_COPIES: usize = 3;
+
+/// Connections peers opened to a torrent kept at once unless configured
+/// otherwise; more are refused
+const MAX_INCOMING: usize = 50;
+
+/// How long an unchoked peer may go without requesting a block before its
+/// upload slot is given to another peer
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Weight of the newest measurement in a peer's throughput estimate
+const THROUGHPUT_SMOOTHING: f64 = 0.5;
+
+/// Re-announce interval used when a tracker doesn't give one
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Shortest re-announce interval accepted from a tracker
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Wait before announcing again when every tracker failed
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// How long a new connection may take to tell which pieces it has
+const AVAILABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a torrent without peers waits for manually added ones, which
+/// arrive right after it is added and may beat an instant announce failure;
+/// with the DHT it waits for the lookup instead
+const MANUAL_PEER_GRACE: Duration = Duration::from_secs(2);
+
+/// Penalty after which a peer is no longer used, see [`reconnect_penalty`]
+const MAX_PEER_PENALTY: usize = 8;
+
+/// Penalty of every corrupt piece a peer sent
+const CORRUPT_PIECE_PENALTY: usize = 4;
+
+/// Penalty of every panic of a peer's task, three are enough to stop using it
+const PANIC_PENALTY: usize = 3;
+
+/// Time for a peer's penalty to drop by one after its last failure
+const PENALTY_DECAY: Duration = Duration::from_secs(300);
+
+/// Number of corrupt pieces after which a connection is dropped
+const MAX_CORRUPT_PIECES: usize = 2;
+
+/// How often seed limits are checked
+const SEED_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the resume file is updated while downloading
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Lifecycle of a torrent inside the engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Contacting the tracker to obtain peers
+    Announcing,
+    /// Handing out pieces to peers
+    Downloading,
+    /// No new work is handed out until resumed
+    Paused,
+    /// The download is complete and the torrent stays active for uploading
+    Seeding,
+    /// The download (and seeding, if enabled) is over
+    Finished,
+    /// The torrent was stopped on request before finishing
+    Stopped,
+    /// The torrent stopped because of an error
+    Failed(ApplicationError),
+}
+
+/// Why seeding ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedStop {
+    Ratio,
+    Time,
+    Idle,
+    Manual,
+}
+
+/// How much of a torrent is on disk and verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TorrentProgress {
+    pub pieces:       usize,
+    pub total_pieces: usize,
+    /// Bytes still to download
+    pub left:         u64,
+    pub total:        u64,
+}
+
+impl TorrentProgress {
+    /// Returns the share of the torrent already downloaded, from 0 to 1
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            (self.total - self.left) as f64 / self.total as f64
+        }
+    }
+}
+
+/// Events reported by peer tasks back to the torrent actor
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// The handshake with the peer succeeded
+    Connected(PeerInfo),
+    /// The state of a connected peer changed
+    Updated(PeerInfo),
+    /// The peer told which pieces it has; answered with the batch to
+    /// download from it
+    Ready(task::Id, Bitfield, oneshot::Sender<Vec<Piece>>),
+    /// The peer announced more pieces; all the pieces it has now
+    Available(task::Id, Bitfield),
+    /// A piece was downloaded in full from the peer and matches its hash
+    Piece(Peer, PieceIndex, Vec<u8>),
+    /// The peer sent a piece that doesn't match its hash
+    Corrupt(Peer, PieceIndex),
+    /// The connection with the peer was closed
+    Disconnected(Peer, DisconnectReason),
+}
+
+/// Commands accepted by a torrent actor
+#[derive(Debug)]
+pub enum TorrentCommand {
+    Pause,
+    Resume,
+    Stop,
+    GetPeers(oneshot::Sender<Vec<PeerInfo>>),
+    GetPeerSources(oneshot::Sender<HashMap<PeerSource, SourceStats>>),
+    GetTrackers(oneshot::Sender<Vec<TrackerStatus>>),
+    GetProgress(oneshot::Sender<TorrentProgress>),
+    /// Announces to the trackers now, e.g. once the network is back
+    Reannounce,
+    AddPeers(Vec<Peer>),
+    /// A peer connected to us for this torrent; its handshake was read already
+    Incoming(TcpStream, Peer, Handshake),
+    PeerEvent(PeerEvent),
+    /// An announce started with [`TorrentActor::start_announce`] ended
+    Announced(Vec<AnnounceAttempt>),
+    /// A scrape started with [`TorrentActor::start_scrape`] answered, for
+    /// the tracker at this index
+    Scraped(usize, Scrape),
+    /// A piece handed to a blocking task by [`TorrentActor::piece_received`]
+    /// was written to disk, or failed to be
+    Written(PieceIndex, Result<(), ApplicationError>),
+}
+
+/// Session-owned resources handed to a torrent actor
+pub struct TorrentResources {
+    pub geometry:         Geometry,
+    /// Files the downloaded pieces are written to
+    pub storage:          Storage,
+    /// Where the verified pieces are recorded for the next run
+    pub resume:           ResumeFile,
+    /// Pieces already on disk, never downloaded again
+    pub verified:         Bitfield,
+    /// Directory the files are moved to once downloaded, if not already there
+    pub destination:      Option<PathBuf>,
+    pub stats:            Arc<TorrentStats>,
+    pub events:           Events,
+    pub throttle:         Throttle,
+    pub bans:             Arc<RwLock<BanList>>,
+    pub identity:         Identity,
+    pub tracker:          Tracker,
+    /// The session's DHT node, `None` if disabled
+    pub dht:              Option<Dht>,
+    /// `None` if the torrent stops right after downloading
+    pub seeding:          Option<SeedLimits>,
+    pub label:            Option<String>,
+    /// Shell command run once the download completes
+    pub hook:             Option<String>,
+    pub recorder:         Option<Recorder>,
+    /// Where the provenance of every downloaded piece is logged, if anywhere
+    pub audit:            Option<AuditLog>,
+    /// Local address peer connections are made from
+    pub bind:             Option<IpAddr>,
+    /// Where peers can connect to us, told to the ones we talk to
+    pub endpoints:        Endpoints,
+    /// Options of the TCP sockets to peers
+    pub sockets:          PeerSockets,
+    /// Connection caps and queue sizes, updated when the session is reconfigured
+    pub caps:             watch::Receiver<Limits>,
+    /// Peers that may be uploaded to at once, shared with the other torrents
+    pub upload_slots:     Arc<Semaphore>,
+    /// One permit per open peer connection, shared with the other torrents
+    pub conn_slots:       Arc<Semaphore>,
+    pub inspectors:       Inspectors,
+    /// Credentials sent to the torrent's trackers
+    pub tracker_auth:     TrackerAuth,
+    /// Byte range of the content to download, everything if `None`
+    pub range:            Option<Range<u64>>,
+    /// Move the first and last piece of each selected file to the front
+    pub first_last_first: bool,
+    /// Hand out the pieces of the rarest files first
+    pub rare_files_first: bool,
+}
+
+/// Everything a peer task needs to know about its torrent
+#[derive(Debug, Clone)]
+struct PeerContext {
+    settings: ConnectionSettings,
+    geometry: Geometry,
+    hashes:   Arc<[[u8; 20]]>,
+    stats:    Arc<TorrentStats>,
+    events:   mpsc::UnboundedSender<TorrentCommand>,
+    /// Where the blocks peers request are read from
+    storage:  Arc<Storage>,
+    /// Pieces we have, updated as they are downloaded
+    have:     watch::Receiver<Bitfield>,
+    slots:    Arc<Semaphore>,
+}
+
+impl PeerContext {
+    fn report(&self, event: PeerEvent) {
+        let _ = self.events.send(TorrentCommand::PeerEvent(event));
+    }
+
+    /// Asks the actor which of the pieces a peer has to download from it
+    async fn batch(&self, have: Bitfield) -> Result<Vec<Piece>, ApplicationError> {
+        let (tx, rx) = oneshot::channel();
+        self.report(PeerEvent::Ready(task::id(), have, tx));
+        rx.await
+            .map_err(|_| ApplicationError::WorkerError("torrent actor is gone".into()))
+    }
+}
+
+/// A running peer task, as seen by its torrent actor
+struct PeerTask {
+    id:         task::Id,
+    peer:       Peer,
+    /// Pieces handed to the task, put back in the queue if it panics
+    batch:      Vec<Piece>,
+    /// Pieces the peer is known to have, counted in their availability
+    have:       Bitfield,
+    /// The task got its batch; until then it holds no pieces
+    ready:      bool,
+    /// Tells the task about pieces of its batch another peer delivered first
+    cancel:     mpsc::UnboundedSender<PieceIndex>,
+    /// Rate limits of the connection
+    throttle:   Throttle,
+    /// Asks the task to close its connection; taken once used
+    disconnect: Option<oneshot::Sender<DisconnectReason>>,
+}
+
+impl PeerTask {
+    /// Asks the task to close its connection, unless already asked
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        if let Some(tx) = self.disconnect.take() {
+            let _ = tx.send(reason);
+        }
+    }
+}
+
+/// A running task serving a peer that connected to us
+struct Upload {
+    id:         task::Id,
+    peer:       Peer,
+    /// Rate limits of the connection
+    throttle:   Throttle,
+    /// Asks the task to close its connection; dropping it does too
+    disconnect: Option<oneshot::Sender<DisconnectReason>>,
+}
+
+impl Upload {
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        if let Some(tx) = self.disconnect.take() {
+            let _ = tx.send(reason);
+        }
+    }
+}
+
+/// How past connections with a peer ended, used to pick the next peer
+#[derive(Debug, Default)]
+struct PeerRecord {
+    /// Number of times the peer's task panicked
+    panics:      usize,
+    /// Number of pieces the peer sent that didn't match their hash
+    corrupt:     usize,
+    /// Number of connections that ended for each reason since the last
+    /// successful one
+    disconnects: HashMap<DisconnectReason, usize>,
+    /// When the peer last failed in any of the ways above
+    failed:      Option<Instant>,
+}
+
+impl PeerRecord {
+    fn disconnected(&mut self, reason: DisconnectReason) {
+        if reason == DisconnectReason::Done {
+            self.disconnects.clear();
+        } else {
+            *self.disconnects.entry(reason).or_default() += 1;
+            self.failed = Some(Instant::now());
+        }
+    }
+
+    fn panicked(&mut self) {
+        self.panics += 1;
+        self.failed  = Some(Instant::now());
+    }
+
+    fn sent_corrupt(&mut self) {
+        self.corrupt += 1;
+        self.failed   = Some(Instant::now());
+    }
+
+    /// Sum of the peer's failures, less one for every [`PENALTY_DECAY`]
+    /// since the last one
+    ///
+    /// A peer given up on is thus tried again later, and each further
+    /// failure makes it wait longer.
+    fn penalty(&self) -> usize {
+        let disconnects = self
+            .disconnects
+            .iter()
+            .map(|(reason, count)| reconnect_penalty(*reason) * count)
+            .sum::<usize>();
+        let forgiven = self
+            .failed
+            .map_or(0, |at| (at.elapsed().as_secs() / PENALTY_DECAY.as_secs()) as usize);
+        let failures = disconnects + self.corrupt * CORRUPT_PIECE_PENALTY + self.panics * PANIC_PENALTY;
+        failures.saturating_sub(forgiven)
+    }
+}
+
+/// The actor owning all download state of a single torrent
+///
+/// Peer tasks never share state with it: they ask for their batch of
+/// pieces once connected and report back through [`TorrentCommand::PeerEvent`].
+pub struct TorrentActor {
+    id:         TorrentId,
+    torrent:    Torrent,
+    geometry:   Geometry,
+    storage:    Arc<Storage>,
+    resume:     ResumeFile,
+    /// Directory the files are moved to once downloaded
+    move_to:    Option<PathBuf>,
+    /// Pieces written to disk, this run or a previous one
+    verified:   watch::Sender<Bitfield>,
+    /// Number of verified pieces the resume file lists
+    saved:      usize,
+    /// Pieces being written to disk, verified once written
+    writing:    HashSet<PieceIndex>,
+    /// Expected SHA-1 of every piece
+    hashes:     Arc<[[u8; 20]]>,
+    /// Pieces not handed to any peer yet, and how many peers have each
+    pieces:     PieceManager,
+    peers:      Vec<Peer>,
+    connected:  Vec<PeerInfo>,
+    tasks:      Vec<PeerTask>,
+    uploads:    Vec<Upload>,
+    /// Tasks of `uploads`, joined to forget each entry however its task ends
+    incoming:   JoinSet<Result<DisconnectReason, ApplicationError>>,
+    /// Peers that may be uploaded to at once, shared with the other torrents
+    slots:      Arc<Semaphore>,
+    /// One permit per open peer connection, shared with the other torrents
+    conn_slots: Arc<Semaphore>,
+    sources:    HashMap<PeerSource, SourceStats>,
+    /// One entry per tracker of the torrent
+    trackers:   Vec<TrackerStatus>,
+    /// When the next regular announce is due; `None` while one is running
+    reannounce: Option<time::Instant>,
+    /// Whether a tracker accepted one of our announces
+    announced:  bool,
+    /// When each tracker, by index, was last scraped
+    scraped:    HashMap<usize, time::Instant>,
+    /// Bytes of the selected pieces not written yet, reported to trackers
+    left:       u64,
+    /// Smoothed download rate of every peer that reported one, in bytes per second
+    throughput: HashMap<SocketAddr, f64>,
+    /// How past connections with each peer ended
+    records:    HashMap<SocketAddr, PeerRecord>,
+    peer_idx:   usize,
+    /// No peer could be connected to the last time one was picked; no new
+    /// connection is tried until an announce, a DHT lookup or a manual add
+    /// completes, or a connection ends
+    starved:    bool,
+    paused:     bool,
+    stopped:    bool,
+    seeding:    bool,
+    limits:     Option<SeedLimits>,
+    stats:      Arc<TorrentStats>,
+    state:      watch::Sender<TorrentState>,
+    events:     Events,
+    throttle:   Throttle,
+    bans:       Arc<RwLock<BanList>>,
+    identity:   Identity,
+    tracker:    Tracker,
+    dht:        Option<Dht>,
+    label:      Option<String>,
+    hook:       Option<String>,
+    recorder:   Option<Recorder>,
+    audit:      Option<AuditLog>,
+    bind:       Option<IpAddr>,
+    endpoints:  Endpoints,
+    sockets:    PeerSockets,
+    caps:       watch::Receiver<Limits>,
+    /// Info dictionary served to peers, `None` if too large
+    metadata:   Option<Metadata>,
+    inspectors: Inspectors,
+    /// Credentials sent to the torrent's trackers
+    auth:       TrackerAuth,
+    /// Set when a piece couldn't be written, failing the download
+    failure:    Option<ApplicationError>,
+    tx:         mpsc::UnboundedSender<TorrentCommand>,
+    rx:         mpsc::UnboundedReceiver<TorrentCommand>,
+}
+
+impl TorrentActor {
+    pub fn new(
+        id:        TorrentId,
+        torrent:   Torrent,
+        state:     watch::Sender<TorrentState>,
+        resources: TorrentResources,
+    ) -> (Self, mpsc::UnboundedSender<TorrentCommand>) {
+        let TorrentResources {
+            geometry,
+            storage,
+            resume,
+            verified,
+            destination,
+            stats,
+            events,
+            throttle,
+            bans,
+            identity,
+            tracker,
+            dht,
+            seeding,
+            label,
+            hook,
+            recorder,
+            audit,
+            bind,
+            endpoints,
+            sockets,
+            caps,
+            upload_slots,
+            conn_slots,
+            inspectors,
+            tracker_auth,
+            range,
+            first_last_first,
+            rare_files_first,
+        } = resources;
+        let (tx, rx)    = mpsc::unbounded_channel();
+        let hashes      = Arc::<[[u8; 20]]>::from(torrent.piece_hashes());
+        let mut manager = PieceManager::new(&geometry, hashes.clone());
+        manager.pieces.retain(|p| !verified.get(p.index.get()));
+        if let Some(range) = &range {
+            let wanted = torrent.pieces_in(range);
+            manager.pieces.retain(|p| wanted.contains(&p.index.get()));
+        }
+        if first_last_first {
+            prioritize_file_edges(&torrent, range.as_ref(), &mut manager.pieces);
+        }
+        if rare_files_first {
+            manager.prefer_rare_files(torrent.file_ranges().iter().map(|file| torrent.pieces_in(file)).collect());
+        }
+        let metadata = Metadata::new(&torrent.info_raw_bytes);
+        let left     = manager.pieces.iter().map(|p| geometry.piece_len(p.index) as u64).sum();
+        // BEP 12: trackers of a tier are tried in random order, so clients
+        // don't all hammer the first one
+        let trackers = torrent
+            .tiers()
+            .into_iter()
+            .enumerate()
+            .flat_map(|(tier, mut urls)| {
+                urls.shuffle(&mut rand::thread_rng());
+                urls.into_iter().map(move |url| TrackerStatus::new(&url, tier))
+            })
+            .collect();
+        let actor    = Self {
+            id,
+            hashes,
+            torrent,
+            geometry,
+            storage:    Arc::new(storage),
+            resume,
+            move_to:    destination,
+            saved:      verified.count_ones(),
+            writing:    HashSet::new(),
+            verified:   watch::Sender::new(verified),
+            pieces:     manager,
+            peers:      Vec::new(),
+            connected:  Vec::new(),
+            tasks:      Vec::new(),
+            uploads:    Vec::new(),
+            incoming:   JoinSet::new(),
+            slots:      upload_slots,
+            conn_slots,
+            sources:    HashMap::new(),
+            trackers,
+            reannounce: None,
+            announced:  false,
+            scraped:    HashMap::new(),
+            left,
+            throughput: HashMap::new(),
+            records:    HashMap::new(),
+            peer_idx:   0,
+            starved:    false,
+            paused:     false,
+            stopped:    false,
+            seeding:    false,
+            limits:     seeding,
+            stats,
+            state,
+            events,
+            throttle,
+            bans,
+            identity,
+            tracker,
+            dht,
+            label,
+            hook,
+            recorder,
+            audit,
+            bind,
+            endpoints,
+            sockets,
+            caps,
+            metadata,
+            inspectors,
+            auth:       tracker_auth,
+            failure:    None,
+            tx:         tx.clone(),
+            rx,
+        };
+        (actor, tx)
+    }
+
+    /// Returns what torrents sharing files with this one can reuse of it
+    pub fn seed_source(&self) -> SeedSource {
+        SeedSource {
+            storage:  self.storage.clone(),
+            hashes:   self.hashes.clone(),
+            verified: self.verified.subscribe(),
+        }
+    }
+
+    /// Announces to the tracker, runs the download loop to completion, then seeds
+    pub async fn run(mut self) {
+        let _ = self.state.send(TorrentState::Announcing);
+        // Nothing is downloaded if every piece was already on disk
+        let complete = self.pieces.is_empty();
+
+        let searching = self.search_dht();
+        let mut announce_error = None;
+        match self.announce(AnnounceEvent::Started).await {
+            Ok(announce) => self.add_peers(announce.peers),
+            // Manually added peers may still allow the download to proceed
+            Err(e)       => announce_error = Some(e),
+        }
+
+        // Pick up commands (e.g. manually added peers) queued while announcing
+        while let Ok(cmd) = self.rx.try_recv() {
+            self.handle(cmd);
+        }
+        let grace = Instant::now() + if searching { dht::LOOKUP_TIMEOUT } else { MANUAL_PEER_GRACE };
+        while !complete
+            && self.peers.is_empty()
+            && let Ok(Some(cmd)) = time::timeout_at(grace.into(), self.rx.recv()).await
+        {
+            self.handle(cmd);
+        }
+
+        if self.peers.is_empty() && !complete {
+            let error = announce_error.unwrap_or_else(|| ApplicationError::TrackerError("no peers".into()));
+            self.leave().await;
+            return self.fail(error);
+        }
+
+        let _ = self.state.send(TorrentState::Downloading);
+        if let Err(e) = self.download_loop().await {
+            self.leave().await;
+            return self.fail(e);
+        }
+        if self.stopped {
+            self.leave().await;
+            let _ = self.state.send(TorrentState::Stopped);
+            return;
+        }
+        self.move_files().await;
+        self.events.emit(Event::TorrentFinished { torrent: self.id });
+        self.run_hook();
+
+        match self.limits {
+            // Trackers are told of downloads completing, not of ones
+            // complete from the start
+            Some(limits) => {
+                if !complete {
+                    self.start_announce(AnnounceEvent::Completed);
+                }
+                self.seed(limits).await;
+            }
+            None => {
+                if !complete {
+                    let _ = self.announce(AnnounceEvent::Completed).await;
+                }
+            }
+        }
+        self.leave().await;
+        let _ = self.state.send(TorrentState::Finished);
+    }
+
+    /// Starts looking for peers in the DHT, unless it is disabled or the
+    /// torrent is private; they are added to the pool once the lookup ends
+    fn search_dht(&self) -> bool {
+        let Some(dht) = self.dht.clone().filter(|_| !self.torrent.is_private()) else {
+            return false;
+        };
+
+        let info_hash = self.torrent.info_hash();
+        let nodes     = self.torrent.dht_nodes();
+        let port      = self.tracker.endpoints.port;
+        let tx        = self.tx.clone();
+        task::spawn(async move {
+            dht.add_nodes(&nodes).await;
+            let peers = dht.get_peers(info_hash, port).await;
+            println!("DHT: found {} peers", peers.len());
+            let _ = tx.send(TorrentCommand::AddPeers(peers));
+        });
+        true
+    }
+
+    /// Announces to the trackers now and records the outcome, see [`announce_in_turn`]
+    async fn announce(&mut self, event: AnnounceEvent) -> Result<Announce, ApplicationError> {
+        let attempts = self.announce_job(event).await;
+        self.record_announce(attempts)
+    }
+
+    /// Announces on a separate task; the outcome comes back as [`TorrentCommand::Announced`]
+    fn start_announce(&mut self, event: AnnounceEvent) {
+        self.reannounce = None;
+        let job = self.announce_job(event);
+        let tx  = self.tx.clone();
+        task::spawn(async move {
+            let _ = tx.send(TorrentCommand::Announced(job.await));
+        });
+    }
+
+    /// Prepares an announce of `event` with the current transfer counters
+    ///
+    /// Within a tier the fastest working trackers are tried first and the
+    /// ones that failed last, so a tracker that slows down or breaks loses
+    /// its place to the others. Trackers not contacted yet keep the random
+    /// order they got when the torrent was added.
+    fn announce_job(&self, event: AnnounceEvent) -> impl Future<Output = Vec<AnnounceAttempt>> + use<> {
+        let mut order = (0..self.trackers.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| (self.trackers[i].tier, self.trackers[i].preference()));
+
+        let urls     = order.into_iter().map(|i| (i, self.trackers[i].url.clone())).collect();
+        let progress = Progress {
+            uploaded:   self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left:       self.left,
+            event,
+        };
+        announce_in_turn(
+            self.tracker.clone(),
+            urls,
+            self.torrent.info_hash(),
+            progress,
+            self.identity,
+            self.auth.clone(),
+        )
+    }
+
+    /// Updates the trackers' state with the outcome of an announce and
+    /// schedules the next one
+    ///
+    /// Returns the answer of the tracker that accepted the announce, or the
+    /// last error if none did.
+    fn record_announce(&mut self, attempts: Vec<AnnounceAttempt>) -> Result<Announce, ApplicationError> {
+        let mut result = Err(ApplicationError::TrackerError("no tracker".into()));
+        let mut scrape = None;
+        for attempt in attempts {
+            let status = &mut self.trackers[attempt.tracker];
+            status.update(&attempt.result, attempt.rtt);
+
+            match attempt.result {
+                Ok(announce) => {
+                    // Trackers leaving out completed downloads tell them when scraped
+                    if announce.downloads.is_none() && attempt.event != AnnounceEvent::Stopped {
+                        scrape = Some(attempt.tracker);
+                    }
+                    // Later announces go straight to where the tracker moved
+                    if let Some(moved) = &announce.redirected {
+                        println!("Tracker moved to {}", moved);
+                        status.url = moved.to_string();
+                    }
+                    self.events.emit(Event::TrackerResponse {
+                        torrent: self.id,
+                        url:     status.url.clone(),
+                        peers:   announce.peers.len(),
+                    });
+                    self.record_swarm(attempt.tracker);
+                    result = Ok(announce);
+                }
+                Err(e) => {
+                    self.events.emit(Event::TrackerFailed {
+                        torrent: self.id,
+                        url:     attempt.url,
+                        message: format!("{:?}", e),
+                    });
+                    result = Err(e);
+                }
+            }
+        }
+
+        let wait = match &result {
+            Ok(announce) => {
+                self.announced = true;
+                announce.interval.unwrap_or(DEFAULT_ANNOUNCE_INTERVAL).max(MIN_ANNOUNCE_INTERVAL)
+            }
+            Err(_) => ANNOUNCE_RETRY,
+        };
+        if !self.trackers.is_empty() {
+            self.reannounce = Some(time::Instant::now() + wait);
+        }
+        if let Some(index) = scrape
+            && self.scraped.get(&index).is_none_or(|at| at.elapsed() >= wait)
+        {
+            self.start_scrape(index);
+        }
+        result
+    }
+
+    /// Scrapes the tracker at `index` on a separate task; the totals come
+    /// back as [`TorrentCommand::Scraped`]
+    fn start_scrape(&mut self, index: usize) {
+        self.scraped.insert(index, time::Instant::now());
+        let tracker   = self.tracker.clone();
+        let url       = self.trackers[index].url.clone();
+        let info_hash = self.torrent.info_hash();
+        let auth      = self.auth.clone();
+        let tx        = self.tx.clone();
+        task::spawn(async move {
+            if let Ok(scrape) = tracker.scrape(&url, &info_hash, &auth).await {
+                let _ = tx.send(TorrentCommand::Scraped(index, scrape));
+            }
+        });
+    }
+
+    /// Records the swarm totals the tracker at `index` last reported, if it
+    /// reported both seeders and leechers
+    fn record_swarm(&self, index: usize) {
+        let status = &self.trackers[index];
+        if let (Some(seeders), Some(leechers)) = (status.seeders, status.leechers) {
+            self.stats.record_swarm(SwarmCounts {
+                seeders,
+                leechers,
+                downloads: status.downloads,
+            });
+        }
+    }
+
+    /// Tells the trackers we are going away, if one of them knows about us
+    async fn leave(&mut self) {
+        if self.announced {
+            let _ = self.announce(AnnounceEvent::Stopped).await;
+        }
+    }
+
+    /// Moves the downloaded files and their resume file to the destination
+    /// directory, if there is one
+    ///
+    /// The torrent keeps seeding from where the files are if they can't be moved.
+    async fn move_files(&mut self) {
+        let Some(dir) = self.move_to.take() else {
+            return;
+        };
+
+        let storage = self.storage.clone();
+        let moved   = task::spawn_blocking(move || storage.relocate(&dir))
+            .await
+            .map_err(|e| ApplicationError::WorkerError(format!("moving files: {}", e)))
+            .and_then(|moved| moved);
+        let result  = moved.and_then(|root| {
+            println!("Moved {} to {}", self.torrent.info.name, root.display());
+            self.resume.relocate(&root)
+        });
+        if let Err(e) = result {
+            println!("Failed to move {}: {:?}", self.torrent.info.name, e);
+            self.events.emit(Event::Error {
+                torrent: Some(self.id),
+                message: format!("moving files: {:?}", e),
+            });
+        }
+    }
+
+    /// Starts the completion hook, if one is configured
+    fn run_hook(&self) {
+        let Some(command) = &self.hook else {
+            return;
+        };
+
+        let path = std::env::current_dir()
+            .unwrap_or_default()
+            .join(self.storage.root());
+        let env  = HookEnv {
+            torrent:   self.id,
+            name:      self.torrent.info.name.clone(),
+            path,
+            info_hash: hex::encode(self.torrent.info_hash()),
+            label:     self.label.clone(),
+        };
+        hooks::spawn(command, env, self.events.clone());
+    }
+
+    /// Keeps the torrent active until one of `limits` is reached or it is stopped
+    async fn seed(&mut self, limits: SeedLimits) {
+        self.seeding = true;
+        if !self.paused {
+            let _ = self.state.send(TorrentState::Seeding);
+        }
+
+        let started     = Instant::now();
+        let mut uploads = (self.stats.uploaded(), Instant::now());
+        let mut ticker  = time::interval(SEED_CHECK_INTERVAL);
+
+        let reason = loop {
+            tokio::select! {
+                cmd = self.rx.recv() => match cmd {
+                    Some(cmd) => self.handle(cmd),
+                    None      => break SeedStop::Manual,
+                },
+                Some(joined) = self.incoming.join_next_with_id() => self.upload_ended(joined),
+                _ = due(self.reannounce) => self.start_announce(AnnounceEvent::None),
+                Ok(()) = self.caps.changed() => self.limit_peers(),
+                _ = ticker.tick() => {
+                    let uploaded   = self.stats.uploaded();
+                    let downloaded = self.stats.downloaded();
+                    if uploaded != uploads.0 {
+                        uploads = (uploaded, Instant::now());
+                    }
+
+                    if limits.ratio.is_some_and(|r| downloaded > 0 && uploaded as f64 / downloaded as f64 >= r) {
+                        break SeedStop::Ratio;
+                    }
+                    if limits.time.is_some_and(|t| started.elapsed() >= t) {
+                        break SeedStop::Time;
+                    }
+                    if limits.idle.is_some_and(|t| uploads.1.elapsed() >= t) {
+                        break SeedStop::Idle;
+                    }
+                }
+            }
+
+            if self.stopped {
+                break SeedStop::Manual;
+            }
+        };
+
+        self.events.emit(Event::SeedingStopped {
+            torrent: self.id,
+            reason,
+        });
+    }
+
+    fn fail(&self, error: ApplicationError) {
+        self.events.emit(Event::TorrentFailed {
+            torrent: self.id,
+            message: format!("{:?}", error),
+        });
+        let _ = self.state.send(TorrentState::Failed(error));
+    }
+
+    /// Returns how many peers may be downloaded from at once
+    fn max_peers(&self) -> usize {
+        self.caps.borrow().max_peers.map_or(CONCURRENCY, NonZeroUsize::get)
+    }
+
+    /// Returns how many connections peers opened to us may be kept at once
+    fn max_incoming(&self) -> usize {
+        self.caps.borrow().max_incoming.map_or(MAX_INCOMING, NonZeroUsize::get)
+    }
+
+    /// Returns what the tasks of downloads and uploads need to know about the torrent
+    fn peer_context(&self) -> PeerContext {
+        PeerContext {
+            settings: ConnectionSettings {
+                info_hash:    self.torrent.info_hash(),
+                peer_id:      self.identity.peer_id,
+                geometry:     Some(self.geometry),
+                bind:         self.bind,
+                endpoints:    self.endpoints,
+                sockets:      self.sockets,
+                max_requests: self.caps.borrow().max_requests.map_or(DEFAULT_MAX_REQUESTS, NonZeroUsize::get),
+                metadata:     self.metadata.clone(),
+                recorder:     self.recorder.clone(),
+                inspectors:   self.inspectors.clone(),
+            },
+            geometry: self.geometry,
+            hashes:   self.hashes.clone(),
+            stats:    self.stats.clone(),
+            events:   self.tx.clone(),
+            storage:  self.storage.clone(),
+            have:     self.verified.subscribe(),
+            slots:    self.slots.clone(),
+        }
+    }
+
+    async fn download_loop(&mut self) -> Result<(), ApplicationError> {
+        let mut workers = JoinSet::new();
+        let mut result  = Ok(());
+        let mut saving  = time::interval(RESUME_SAVE_INTERVAL);
+
+        // Runs until nothing is left to hand out and every task has ended,
+        // serving commands all along
+        loop {
+            if let Some(error) = self.failure.take() {
+                result = Err(error);
+            }
+            let idle = self.pieces.is_empty() || self.stopped || result.is_err();
+            if idle && workers.is_empty() && self.writing.is_empty() {
+                break;
+            }
+            if self.stopped {
+                self.disconnect_all(DisconnectReason::Stopped);
+            }
+            let spawn = !self.stopped
+                && result.is_ok()
+                && !self.paused
+                && !self.starved
+                && workers.len() < self.max_peers()
+                && self.has_work();
+
+            tokio::select! {
+                cmd = self.rx.recv() => match cmd {
+                    Some(cmd) => self.handle(cmd),
+                    None      => break,
+                },
+                Some(joined) = workers.join_next_with_id() => self.task_ended(joined),
+                Some(joined) = self.incoming.join_next_with_id() => self.upload_ended(joined),
+                _ = due(self.reannounce) => self.start_announce(AnnounceEvent::None),
+                Ok(()) = self.caps.changed() => self.limit_peers(),
+                _ = saving.tick() => self.save_resume(),
+                // Waits for the other torrents to close a connection when
+                // the session's budget is used up
+                Ok(permit) = self.conn_slots.clone().acquire_owned(), if spawn => {
+                    // Announces, the DHT and other peers may bring new ones
+                    let Some(peer) = self.next_peer(self.pieces.is_empty()) else {
+                        if workers.is_empty() {
+                            println!("Every peer is banned or failing, waiting for more");
+                        }
+                        self.starved = true;
+                        continue;
+                    };
+                    let limits   = self.peer_throttle();
+                    let ctx      = self.peer_context();
+                    let (tx, rx) = oneshot::channel();
+                    let (cancel, cancelled) = mpsc::unbounded_channel();
+
+                    // Spawn a new task to handle the peer download; it gets
+                    // its pieces once the peer says which ones it has
+                    let handle = workers.spawn({
+                        let peer = peer.clone();
+                        let limits = limits.clone();
+                        async move {
+                            let _permit = permit;
+                            runtime(&peer, limits, &ctx, cancelled, rx).await
+                        }
+                    });
+                    self.tasks.push(PeerTask {
+                        id:         handle.id(),
+                        peer,
+                        batch:      Vec::new(),
+                        have:       Bitfield::default(),
+                        ready:      false,
+                        cancel,
+                        throttle:   limits,
+                        disconnect: Some(tx),
+                    });
+                }
+            }
+        }
+
+        // Process events sent by the tasks that just finished
+        while let Ok(cmd) = self.rx.try_recv() {
+            self.handle(cmd);
+        }
+        self.save_resume();
+        result
+    }
+
+    /// Records the verified pieces in the resume file, if any were added
+    /// since the last save
+    fn save_resume(&mut self) {
+        let verified = self.verified.borrow().clone();
+        let count    = verified.count_ones();
+        if count == self.saved {
+            return;
+        }
+        match self.resume.save(&self.torrent.info_hash(), &verified) {
+            Ok(())  => self.saved = count,
+            Err(e) => println!("Failed to save resume file: {:?}", e),
+        }
+    }
+
+    /// Forgets a peer task that ended, handing back the pieces it didn't download
+    fn task_ended(&mut self, joined: Result<(task::Id, Result<DisconnectReason, ApplicationError>), task::JoinError>) {
+        // Take in the pieces the task completed before looking at what is left
+        while let Ok(cmd) = self.rx.try_recv() {
+            self.handle(cmd);
+        }
+
+        let id = match &joined {
+            Ok((id, _)) => *id,
+            Err(e)      => e.id(),
+        };
+        let Some(pos) = self.tasks.iter().position(|t| t.id == id) else {
+            return;
+        };
+        let task = self.tasks.swap_remove(pos);
+        self.starved = false;
+
+        let record = self.records.entry(task.peer.addr()).or_default();
+        match joined {
+            Ok((_, Ok(reason))) => record.disconnected(reason),
+            Ok((_, Err(e)))     => {
+                record.disconnected(DisconnectReason::from_error(&e));
+                self.events.emit(Event::Error {
+                    torrent: Some(self.id),
+                    message: format!("peer {}: {:?}", task.peer, e),
+                });
+            }
+            Err(e) => {
+                let message = panic_message(e);
+                println!("Task of peer {} panicked: {}", task.peer, message);
+                self.events.emit(Event::Error {
+                    torrent: Some(self.id),
+                    message: format!("peer {}: task panicked: {}", task.peer, message),
+                });
+                record.panicked();
+                if let Some(pos) = self.connected.iter().position(|p| p.peer == task.peer && !p.incoming) {
+                    self.connected.swap_remove(pos);
+                }
+            }
+        }
+        // Hand what is left to the next peer, ahead of everything else,
+        // unless another task is downloading it too in endgame
+        let mut batch = task.batch;
+        batch.retain(|p| !self.tasks.iter().any(|t| t.batch.iter().any(|q| q.index == p.index)));
+        self.pieces.update_availability(&task.have, &Bitfield::default());
+        self.pieces.give_back(batch);
+    }
+
+    /// Forgets an upload task that ended, including ones whose peer failed
+    /// the handshake and never got to report a disconnect
+    fn upload_ended(&mut self, joined: Result<(task::Id, Result<DisconnectReason, ApplicationError>), task::JoinError>) {
+        let id = match &joined {
+            Ok((id, _)) => *id,
+            Err(e)      => e.id(),
+        };
+        let Some(pos) = self.uploads.iter().position(|u| u.id == id) else {
+            return;
+        };
+        let upload = self.uploads.swap_remove(pos);
+        match joined {
+            Ok((_, Ok(_)))  => {}
+            Ok((_, Err(e))) => println!("Upload to {} ended: {:?}", upload.peer, e),
+            Err(e)          => {
+                println!("Task of peer {} panicked: {}", upload.peer, panic_message(e));
+                if let Some(pos) = self.connected.iter().position(|p| p.peer == upload.peer && p.incoming) {
+                    self.connected.swap_remove(pos);
+                }
+            }
+        }
+    }
+
+    /// Hands a verified piece to a blocking task writing it to disk and
+    /// takes it off the batch of its task
+    ///
+    /// In endgame, the other tasks downloading the piece give it up, and the
+    /// ones left with nothing to download are closed.
+    fn piece_received(&mut self, peer: &Peer, index: PieceIndex, data: Vec<u8>) {
+        // Another peer may have been faster in endgame
+        if self.verified.borrow().get(index.get()) || !self.writing.insert(index) {
+            return;
+        }
+        self.audit(peer, index, Verdict::Verified);
+        for task in &mut self.tasks {
+            let held = task.batch.len();
+            task.batch.retain(|p| p.index != index);
+            if task.batch.len() == held || task.peer == *peer {
+                continue;
+            }
+            let _ = task.cancel.send(index);
+            if task.batch.is_empty() {
+                task.disconnect(DisconnectReason::Done);
+            }
+        }
+
+        let storage = self.storage.clone();
+        let tx      = self.tx.clone();
+        task::spawn_blocking(move || {
+            let _ = tx.send(TorrentCommand::Written(index, storage.write_piece(index, &data)));
+        });
+    }
+
+    /// Marks a piece verified once written, or fails the download if it couldn't be
+    fn piece_written(&mut self, index: PieceIndex, result: Result<(), ApplicationError>) {
+        self.writing.remove(&index);
+        match result {
+            Ok(())  => {
+                self.verified.send_modify(|verified| verified.set(index.get(), true));
+                self.left = self.left.saturating_sub(self.geometry.piece_len(index) as u64);
+                self.events.emit(Event::PieceFinished { torrent: self.id, piece: index });
+            }
+            Err(e) => self.failure = Some(e),
+        }
+    }
+
+    /// Holds a corrupt piece against the peer that sent it
+    fn piece_corrupt(&mut self, peer: Peer, index: PieceIndex) {
+        println!("Peer {} sent corrupt data for piece {}", peer, index);
+        self.audit(&peer, index, Verdict::Corrupt);
+        self.stats.record_piece_failed();
+        self.records.entry(peer.addr()).or_default().sent_corrupt();
+        self.events.emit(Event::Error {
+            torrent: Some(self.id),
+            message: format!("peer {}: piece {} failed verification", peer, index),
+        });
+    }
+
+    /// Logs who delivered piece `index`, if auditing is on
+    fn audit(&self, peer: &Peer, index: PieceIndex, verdict: Verdict) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let client = self
+            .connected
+            .iter()
+            .find(|p| p.peer == *peer)
+            .map(|p| p.client.clone())
+            .unwrap_or_default();
+        audit.record(PieceAudit {
+            info_hash: self.torrent.info_hash(),
+            piece:     index,
+            blocks:    self.geometry.blocks(index).count(),
+            peer:      peer.to_string(),
+            client,
+            verdict,
+        });
+    }
+
+    /// Asks every running peer task to close its connection
+    fn disconnect_all(&mut self, reason: DisconnectReason) {
+        for task in &mut self.tasks {
+            task.disconnect(reason);
+        }
+        for upload in &mut self.uploads {
+            upload.disconnect(reason);
+        }
+    }
+
+    /// Creates the rate limiters of a new peer connection, nested under the torrent's
+    fn peer_throttle(&self) -> Throttle {
+        let caps = self.caps.borrow();
+        self.throttle.child(caps.peer_download_limit, caps.peer_upload_limit)
+    }
+
+    /// Applies the current per-peer rate limits to the open connections
+    fn limit_peers(&self) {
+        let caps      = *self.caps.borrow();
+        let throttles = self.tasks.iter().map(|t| &t.throttle).chain(self.uploads.iter().map(|u| &u.throttle));
+        for throttle in throttles {
+            throttle.download.set_rate(caps.peer_download_limit);
+            throttle.upload.set_rate(caps.peer_upload_limit);
+        }
+    }
+
+    /// Starts serving a peer that connected to us, unless the torrent is
+    /// paused, the peer is banned or too many peers already did
+    fn accept(&mut self, stream: TcpStream, peer: Peer, handshake: Handshake) {
+        if self.stopped || self.paused || self.uploads.len() >= self.max_incoming() || self.bans.read().unwrap().is_banned(&peer.ip) {
+            return;
+        }
+        let Ok(permit) = self.conn_slots.clone().try_acquire_owned() else {
+            return;
+        };
+
+        let ctx      = self.peer_context();
+        let limits   = self.peer_throttle();
+        let (tx, rx) = oneshot::channel();
+        let handle   = self.incoming.spawn({
+            let peer   = peer.clone();
+            let limits = limits.clone();
+            async move {
+                let _permit = permit;
+                upload(&peer, stream, &handshake, limits, &ctx, rx).await
+            }
+        });
+        self.uploads.push(Upload {
+            id:         handle.id(),
+            peer,
+            throttle:   limits,
+            disconnect: Some(tx),
+        });
+    }
+
+    fn handle(&mut self, cmd: TorrentCommand) {
+        match cmd {
+            TorrentCommand::Pause => {
+                self.paused = true;
+                self.disconnect_all(DisconnectReason::Paused);
+                let _ = self.state.send(TorrentState::Paused);
+            }
+            TorrentCommand::Resume => {
+                self.paused = false;
+                let _ = self.state.send(if self.seeding {
+                    TorrentState::Seeding
+                } else {
+                    TorrentState::Downloading
+                });
+            }
+            TorrentCommand::Stop => {
+                self.stopped = true;
+            }
+            TorrentCommand::GetPeers(reply) => {
+                let _ = reply.send(self.connected.clone());
+            }
+            TorrentCommand::GetPeerSources(reply) => {
+                let _ = reply.send(self.sources.clone());
+            }
+            TorrentCommand::GetTrackers(reply) => {
+                let _ = reply.send(self.trackers.clone());
+            }
+            TorrentCommand::GetProgress(reply) => {
+                let _ = reply.send(TorrentProgress {
+                    pieces:       self.verified.borrow().count_ones(),
+                    total_pieces: self.geometry.pieces_count(),
+                    left:         self.left,
+                    total:        self.geometry.total_len(),
+                });
+            }
+            TorrentCommand::Reannounce => {
+                // `None` while an announce is already running
+                if self.reannounce.is_some() {
+                    self.start_announce(AnnounceEvent::None);
+                }
+            }
+            TorrentCommand::AddPeers(peers) => {
+                self.add_peers(peers);
+            }
+            TorrentCommand::Incoming(stream, peer, handshake) => {
+                self.accept(stream, peer, handshake);
+            }
+            TorrentCommand::Announced(attempts) => {
+                // Even with no peers, penalties may have decayed since
+                let peers = self.record_announce(attempts).map(|a| a.peers).unwrap_or_default();
+                self.add_peers(peers);
+            }
+            TorrentCommand::Written(index, result) => {
+                self.piece_written(index, result);
+            }
+            TorrentCommand::Scraped(index, scrape) => {
+                self.trackers[index].scraped(&scrape);
+                self.record_swarm(index);
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Connected(info)) => {
+                self.events.emit(Event::PeerConnected {
+                    torrent: self.id,
+                    peer:    info.peer.to_string(),
+                    source:  info.peer.source,
+                });
+                self.sources.entry(info.peer.source).or_default().connected += 1;
+                self.connected.push(info);
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Updated(info)) => {
+                // Peers that connected to us are only uploaded to
+                if !info.incoming {
+                    let rate = self.throughput.entry(info.peer.addr()).or_insert(info.download_rate);
+                    *rate += THROUGHPUT_SMOOTHING * (info.download_rate - *rate);
+                }
+                if self.bans.read().unwrap().is_banned(&info.peer.ip) {
+                    for task in self.tasks.iter_mut().filter(|t| t.peer == info.peer) {
+                        task.disconnect(DisconnectReason::Banned);
+                    }
+                    for upload in self.uploads.iter_mut().filter(|u| u.peer == info.peer) {
+                        upload.disconnect(DisconnectReason::Banned);
+                    }
+                }
+                if let Some(entry) = self.connected.iter_mut().find(|p| p.peer == info.peer && p.incoming == info.incoming) {
+                    *entry = info;
+                }
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Ready(id, have, reply)) => {
+                self.assign_batch(id, have, reply);
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Available(id, have)) => {
+                if let Some(task) = self.tasks.iter_mut().find(|t| t.id == id) {
+                    self.pieces.update_availability(&task.have, &have);
+                    task.have = have;
+                }
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Piece(peer, index, data)) => {
+                self.piece_received(&peer, index, data);
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Corrupt(peer, index)) => {
+                self.piece_corrupt(peer, index);
+            }
+            TorrentCommand::PeerEvent(PeerEvent::Disconnected(peer, reason)) => {
+                self.events.emit(Event::PeerDisconnected {
+                    torrent: self.id,
+                    peer:    peer.to_string(),
+                    reason,
+                });
+                // A peer may have a connection of each direction with us;
+                // upload entries go once their task is joined
+                let incoming = peer.source == PeerSource::Incoming;
+                if let Some(pos) = self.connected.iter().position(|p| p.peer == peer && p.incoming == incoming) {
+                    self.connected.swap_remove(pos);
+                }
+            }
+        }
+    }
+
+    /// Adds newly discovered peers to the pool, skipping banned and known ones,
+    /// and tries connecting again if every peer was failing
+    fn add_peers(&mut self, peers: Vec<Peer>) {
+        let mut added = 0;
+        {
+            let bans = self.bans.read().unwrap();
+            for peer in peers {
+                if bans.is_banned(&peer.ip) || self.peers.contains(&peer) {
+                    continue;
+                }
+                self.sources.entry(peer.source).or_default().discovered += 1;
+                self.peers.push(peer);
+                added += 1;
+            }
+        }
+        self.starved = false;
+        self.make_room(added);
+    }
+
+    /// Disconnects up to `count` useless peers when every connection slot is taken
+    ///
+    /// Only peers [`TorrentActor::uselessness`] ranks are candidates, the
+    /// least useful first; useful connections are never dropped for a newcomer.
+    fn make_room(&mut self, count: usize) {
+        if count == 0 || self.tasks.len() < self.max_peers() {
+            return;
+        }
+
+        let mut candidates = self
+            .tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.disconnect.is_some())
+            .filter_map(|(i, t)| self.uselessness(&t.peer).map(|rank| (rank, i)))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        candidates.truncate(count);
+
+        for (rank, i) in candidates {
+            let reason = match rank {
+                0 => DisconnectReason::SeedToSeed,
+                _ => DisconnectReason::ChokePolicy,
+            };
+            self.tasks[i].disconnect(reason);
+        }
+    }
+
+    /// Ranks a connected peer that is not worth its slot, lowest first
+    ///
+    /// Returns `None` for useful peers. In order: seeds while we are seeding,
+    /// extra connections to the same address, and peers without any piece.
+    fn uselessness(&self, peer: &Peer) -> Option<u8> {
+        let info = self.connected.iter().find(|p| p.peer == *peer);
+        if self.seeding && info.is_some_and(|i| i.progress >= 1.0) {
+            return Some(0);
+        }
+        if self.tasks.iter().filter(|t| t.peer == *peer).count() > 1 {
+            return Some(1);
+        }
+        if info.is_some_and(|i| i.progress == 0.0) {
+            return Some(2);
+        }
+        None
+    }
+
+    /// Hands a task the rarest pieces its peer has, see [`PieceManager::take_rarest`]
+    ///
+    /// The peer's pieces count towards their availability until the task ends.
+    fn assign_batch(&mut self, id: task::Id, have: Bitfield, reply: oneshot::Sender<Vec<Piece>>) {
+        let Some(pos) = self.tasks.iter().position(|t| t.id == id) else {
+            let _ = reply.send(Vec::new());
+            return;
+        };
+        let count = self.batch_size(&self.tasks[pos].peer);
+        let batch = if self.pieces.is_empty() {
+            self.endgame_batch(&have, count)
+        } else {
+            self.pieces.take_rarest(&have, count)
+        };
+
+        let task  = &mut self.tasks[pos];
+        self.pieces.update_availability(&task.have, &have);
+        task.have  = have;
+        task.batch = batch.clone();
+        task.ready = true;
+        let _ = reply.send(batch);
+    }
+
+    /// Picks up to `count` pieces other tasks are still downloading, for a
+    /// peer having `have`
+    ///
+    /// Pieces with the fewest tasks on them come first; those already
+    /// downloaded by [`ENDGAME_COPIES`] tasks are left alone.
+    fn endgame_batch(&self, have: &Bitfield, count: usize) -> Vec<Piece> {
+        let mut candidates = Vec::<(usize, &Piece)>::new();
+        for piece in self.tasks.iter().flat_map(|t| &t.batch) {
+            let copies = self.copies(piece.index);
+            if have.get(piece.index.get())
+                && copies < ENDGAME_COPIES
+                && !candidates.iter().any(|(_, p)| p.index == piece.index)
+            {
+                candidates.push((copies, piece));
+            }
+        }
+        candidates.sort_by_key(|(copies, piece)| (*copies, self.pieces.availability(piece.index)));
+        candidates.into_iter().take(count).map(|(_, p)| p.clone()).collect()
+    }
+
+    /// Returns the number of tasks downloading piece `index`
+    fn copies(&self, index: PieceIndex) -> usize {
+        self.tasks
+            .iter()
+            .filter(|t| t.batch.iter().any(|p| p.index == index))
+            .count()
+    }
+
+    /// Returns `true` if another peer task would get pieces to download
+    ///
+    /// Until every piece is handed out, that is while more are left than
+    /// tasks waiting for their batch. Then, in endgame, while some piece has
+    /// fewer than [`ENDGAME_COPIES`] tasks on it and a peer not downloading
+    /// yet could take it.
+    fn has_work(&self) -> bool {
+        let waiting = self.tasks.iter().filter(|t| !t.ready).count();
+        if !self.pieces.is_empty() {
+            return self.pieces.len() > waiting;
+        }
+        waiting == 0
+            && self
+                .tasks
+                .iter()
+                .flat_map(|t| &t.batch)
+                .any(|p| self.copies(p.index) < ENDGAME_COPIES)
+            && self.peers.iter().any(|p| self.eligible(p, true))
+    }
+
+    /// Sizes a batch after the peer's throughput relative to the others
+    ///
+    /// Unknown peers get [`BATCH_SIZE`]. Near the end of the download no peer
+    /// gets more than its share of what is left, so a slow peer can't hold
+    /// the last pieces hostage.
+    fn batch_size(&self, peer: &Peer) -> usize {
+        let average = self.throughput.values().sum::<f64>() / self.throughput.len().max(1) as f64;
+        let size    = match self.throughput.get(&peer.addr()) {
+            Some(rate) if average > 0.0 => {
+                let scaled = (BATCH_SIZE as f64 * rate / average).round() as usize;
+                scaled.clamp(MIN_BATCH_SIZE, MAX_BATCH_SIZE)
+            }
+            _ => BATCH_SIZE,
+        };
+
+        let share = self.pieces.len().div_ceil(self.max_peers()).max(1);
+        size.min(share)
+    }
+
+    /// Selects the next peer a connection may be opened to, in round-robin
+    /// order, see [`TorrentActor::eligible`]
+    fn next_peer(&mut self, endgame: bool) -> Option<Peer> {
+        for _ in 0..self.peers.len() {
+            let peer      = self.peers[self.peer_idx].clone();
+            self.peer_idx = (self.peer_idx + 1) % self.peers.len();
+            if self.eligible(&peer, endgame) {
+                return Some(peer);
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if a new connection to `peer` may be opened
+    ///
+    /// Banned peers and those whose connections keep failing never qualify,
+    /// see [`PeerRecord`]. In endgame, neither do peers already downloading
+    /// or that had none of the pieces asked last time.
+    fn eligible(&self, peer: &Peer, endgame: bool) -> bool {
+        let record  = self.records.get(&peer.addr());
+        let failing = record.is_some_and(|r| r.penalty() >= MAX_PEER_PENALTY);
+        if failing || self.bans.read().unwrap().is_banned(&peer.ip) {
+            return false;
+        }
+        if !endgame {
+            return true;
+        }
+        let busy    = self.tasks.iter().any(|t| t.peer == *peer);
+        let useless = record.is_some_and(|r| r.disconnects.contains_key(&DisconnectReason::Useless));
+        !busy && !useless
+    }
+}
+
+/// Outcome of announcing to one tracker
+#[derive(Debug)]
+pub struct AnnounceAttempt {
+    /// Index of the tracker in the torrent's tracker list
+    tracker: usize,
+    url:     String,
+    result:  Result<Announce, ApplicationError>,
+    rtt:     Duration,
+    event:   AnnounceEvent,
+}
+
+/// Announces to the trackers of `urls`, in order, until one answers
+async fn announce_in_turn(
+    tracker:   Tracker,
+    urls:      Vec<(usize, String)>,
+    info_hash: [u8; 20],
+    progress:  Progress,
+    identity:  Identity,
+    auth:      TrackerAuth,
+) -> Vec<AnnounceAttempt> {
+    let mut attempts = Vec::new();
+    for (index, url) in urls {
+        let started = Instant::now();
+        let result  = tracker.announce(&url, &info_hash, progress, &identity, &auth).await;
+        let rtt     = started.elapsed();
+        let done    = result.is_ok();
+        attempts.push(AnnounceAttempt {
+            tracker: index,
+            url,
+            result,
+            rtt,
+            event:   progress.event,
+        });
+        if done {
+            break;
+        }
+    }
+    attempts
+}
+
+/// Completes at `deadline`, or never without one
+async fn due(deadline: Option<time::Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None           => future::pending().await,
+    }
+}
+
+/// How much a connection ending for `reason` counts against reconnecting
+///
+/// Disconnects we asked for cost nothing; a peer breaking the protocol is
+/// given up on sooner than one that is merely unreachable.
+fn reconnect_penalty(reason: DisconnectReason) -> usize {
+    match reason {
+        DisconnectReason::ProtocolViolation => 4,
+        DisconnectReason::Timeout           => 2,
+        DisconnectReason::ConnectionError   => 1,
+        DisconnectReason::Useless           => 1,
+        _                                   => 0,
+    }
+}
+
+/// Extracts the message a peer task panicked with
+fn panic_message(error: task::JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
+    let payload = error.into_panic();
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None          => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".into()),
+    }
+}
+
+/// Moves the first and last piece of every file overlapping `range` to the front
+///
+/// The remaining pieces keep their order. Files entirely outside `range` are
+/// not selected and don't get priority.
+fn prioritize_file_edges(torrent: &Torrent, range: Option<&Range<u64>>, pieces: &mut Vec<Piece>) {
+    let mut edges = Vec::new();
+    for file in torrent.file_ranges() {
+        let selected = match range {
+            Some(range) => file.start < range.end && range.start < file.end,
+            None        => true,
+        };
+        let file_pieces = torrent.pieces_in(&file);
+        if !selected || file_pieces.is_empty() {
+            continue;
+        }
+        edges.push(file_pieces.start);
+        edges.push(file_pieces.end - 1);
+    }
+
+    let (mut first, rest): (Vec<_>, Vec<_>) = pieces.drain(..).partition(|p| edges.contains(&p.index.get()));
+    first.sort_by_key(|p| edges.iter().position(|&i| i == p.index.get()));
+    first.extend(rest);
+    *pieces = first;
+}
+
+/// Downloads the pieces of `batch`, which the peer has, reporting each one
+/// completed
+///
+/// Pieces failing verification are downloaded again, up to
+/// [`MAX_CORRUPT_PIECES`] times per connection.
+async fn download(
+    conn:          &mut PeerConnection<'_>,
+    batch:         Vec<Piece>,
+    mut cancelled: mpsc::UnboundedReceiver<PieceIndex>,
+    disconnect:    &mut oneshot::Receiver<DisconnectReason>,
+    ctx:           &PeerContext,
+) -> Result<DisconnectReason, ApplicationError> {
+    let mut manager = PieceManager::with_pieces(&ctx.geometry, ctx.hashes.clone(), batch);
+    let mut corrupt = 0;
+    let peer        = conn.peer().clone();
+    conn.download_pieces(&mut manager, &mut cancelled, disconnect, |progress| {
+        let completed = match progress {
+            Download::Block(length) => {
+                ctx.stats.record_download(length as u64);
+                return Ok(());
+            }
+            Download::Available(have) => {
+                ctx.report(PeerEvent::Available(task::id(), have));
+                return Ok(());
+            }
+            Download::Piece(completed) => completed,
+        };
+        if completed.valid {
+            ctx.report(PeerEvent::Piece(peer.clone(), completed.index, completed.data));
+            return Ok(());
+        }
+
+        ctx.report(PeerEvent::Corrupt(peer.clone(), completed.index));
+        corrupt += 1;
+        if corrupt >= MAX_CORRUPT_PIECES {
+            return Err(ApplicationError::ProtocolError(format!("{} corrupt pieces", corrupt)));
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Handles a single peer connection: connect, handshake, interested, and download.
+async fn runtime(
+    peer:           &Peer,
+    throttle:       Throttle,
+    ctx:            &PeerContext,
+    cancelled:      mpsc::UnboundedReceiver<PieceIndex>,
+    mut disconnect: oneshot::Receiver<DisconnectReason>,
+) -> Result<DisconnectReason, ApplicationError> {
+    let mut conn = PeerConnection::connect(peer, &ctx.settings, throttle).await?;
+    ctx.stats.peer_connected();
+    ctx.report(PeerEvent::Connected(conn.info(ctx.geometry.pieces_count())));
+
+    // Nothing is pending until the first request, so setting up can be
+    // abandoned anywhere; the download withdraws its requests itself
+    let setup = async {
+        if !conn.read_availability(AVAILABILITY_TIMEOUT).await? {
+            return Ok(Err(DisconnectReason::Timeout));
+        }
+        let pieces = ctx.batch(conn.available_pieces().clone()).await?;
+        if pieces.is_empty() {
+            return Ok(Err(DisconnectReason::Useless));
+        }
+        println!("Connected to {}:{}, downloading {} pieces", peer.ip, peer.port, pieces.len());
+
+        let mut wanted = Bitfield::new(ctx.geometry.pieces_count());
+        for piece in &pieces {
+            wanted.set(piece.index.get(), true);
+        }
+        conn.update_interest(&wanted).await?;
+        Ok(Ok(pieces))
+    };
+    let setup = tokio::select! {
+        setup = setup => setup,
+        Ok(reason) = &mut disconnect => {
+            println!("Disconnecting from {} ({})", peer, reason);
+            Ok(Err(reason))
+        }
+    };
+    let result = match setup {
+        Ok(Ok(pieces))  => download(&mut conn, pieces, cancelled, &mut disconnect, ctx).await,
+        Ok(Err(reason)) => Ok(reason),
+        Err(e)          => Err(e),
+    };
+    hang_up(conn, ctx, &result).await;
+
+    // // Print pieces that peer has available
+    // let available: Vec<_> = conn.available_pieces().iter().cloned().collect();
+    // println!("Peer {} has pieces {:?}", peer.ip, available);
+
+    result
+}
+
+/// Closes a connection that ended with `result` and reports it to the actor
+async fn hang_up(
+    conn:   PeerConnection<'_>,
+    ctx:    &PeerContext,
+    result: &Result<DisconnectReason, ApplicationError>,
+) {
+    let reason = match result {
+        Ok(reason) => *reason,
+        Err(e)     => DisconnectReason::from_error(e),
+    };
+    let peer = conn.peer().clone();
+    ctx.report(PeerEvent::Updated(conn.info(ctx.geometry.pieces_count())));
+    conn.close(reason.is_graceful()).await;
+    ctx.stats.peer_disconnected();
+    ctx.report(PeerEvent::Disconnected(peer, reason));
+}
+
+/// Handles a peer that connected to us: answer its handshake, then serve it
+async fn upload(
+    peer:       &Peer,
+    stream:     TcpStream,
+    handshake:  &Handshake,
+    throttle:   Throttle,
+    ctx:        &PeerContext,
+    disconnect: oneshot::Receiver<DisconnectReason>,
+) -> Result<DisconnectReason, ApplicationError> {
+    let mut conn = PeerConnection::accept(peer, stream, handshake, &ctx.settings, throttle).await?;
+    ctx.stats.peer_connected();
+    ctx.report(PeerEvent::Connected(conn.info(ctx.geometry.pieces_count())));
+    println!("Accepted connection from {}", peer);
+
+    let result = serve(&mut conn, ctx, disconnect).await;
+    hang_up(conn, ctx, &result).await;
+    result
+}
+
+/// Serves the pieces we have to the peer until it leaves or we disconnect it
+///
+/// The peer is unchoked while interested and holding one of the session's
+/// upload slots; it gives the slot back when it loses interest or stops
+/// requesting for [`UPLOAD_IDLE_TIMEOUT`]. Pieces downloaded meanwhile are
+/// announced with `have` messages.
+async fn serve(
+    conn:           &mut PeerConnection<'_>,
+    ctx:            &PeerContext,
+    mut disconnect: oneshot::Receiver<DisconnectReason>,
+) -> Result<DisconnectReason, ApplicationError> {
+    let mut have = ctx.have.clone();
+    let mut sent = have.borrow_and_update().clone();
+    conn.send_bitfield(&sent).await?;
+
+    let mut slot: Option<OwnedSemaphorePermit> = None;
+    let mut idle = time::Instant::now() + UPLOAD_IDLE_TIMEOUT;
+    loop {
+        let waiting = slot.is_none() && conn.peer_interested();
+        tokio::select! {
+            readable = conn.readable() => {
+                readable?;
+                let Some(msg) = conn.next_message().await? else {
+                    return Ok(DisconnectReason::Done);
+                };
+                match msg {
+                    Message::Request { index, begin, length } if slot.is_some() => {
+                        send_requested(conn, ctx, &sent, index, begin, length).await?;
+                        idle = time::Instant::now() + UPLOAD_IDLE_TIMEOUT;
+                    }
+                    Message::NotInterested if slot.is_some() => {
+                        slot = None;
+                        conn.set_choking(true).await?;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(permit) = ctx.slots.clone().acquire_owned(), if waiting => {
+                slot = Some(permit);
+                idle = time::Instant::now() + UPLOAD_IDLE_TIMEOUT;
+                conn.set_choking(false).await?;
+            }
+            _ = time::sleep_until(idle), if slot.is_some() => {
+                slot = None;
+                conn.set_choking(true).await?;
+            }
+            changed = have.changed() => {
+                // The actor is gone once its sender is
+                if changed.is_err() {
+                    return Ok(DisconnectReason::Stopped);
+                }
+                let now = have.borrow_and_update().clone();
+                for piece in (&now & &!&sent).iter_ones() {
+                    conn.send_have(ctx.geometry.piece(piece as u32)?).await?;
+                }
+                sent = now;
+            }
+            reason = &mut disconnect => {
+                return Ok(reason.unwrap_or(DisconnectReason::Stopped));
+            }
+        }
+    }
+}
+
+/// Sends the block a peer requested, if it belongs to a piece we have
+async fn send_requested(
+    conn:   &mut PeerConnection<'_>,
+    ctx:    &PeerContext,
+    have:   &Bitfield,
+    index:  u32,
+    begin:  u32,
+    length: u32,
+) -> Result<(), ApplicationError> {
+    // Peers ask for blocks of the usual size, whatever size we ask ours in
+    let geometry       = ctx.geometry.with_block_len(BLOCK_SIZE as u32);
+    let (piece, block) = geometry.check_block(index, begin, length as usize)?;
+    if !have.get(piece.get()) {
+        return Ok(());
+    }
+    let storage = ctx.storage.clone();
+    let data    = task::spawn_blocking(move || storage.read_block(piece, block, length as usize))
+        .await
+        .map_err(|e| ApplicationError::WorkerError(format!("reading block: {}", e)))
+        .and_then(|data| data)?;
+    conn.send_block(piece, block, data).await?;
+    ctx.stats.record_upload(length as u64);
+    Ok(())
+}
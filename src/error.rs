@@ -1,8 +1,10 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum ApplicationError {
     ParserError(String),
     TrackerError(String),
     ProtocolError(String),
     PeerError(String),
     WorkerError(String),
+    VerificationError(String),
 }
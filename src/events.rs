@@ -0,0 +1,292 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+use crate::{
+    engine::SeedStop,
+    error::ApplicationError,
+    geometry::PieceIndex,
+    notify::Notifier,
+    peer::{DisconnectReason, PeerSource},
+    session::TorrentId,
+};
+
+/// Kind of an [`Event`], used to pick which events a sink receives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    /// Torrents being added, finishing, stopping or failing
+    Status,
+    /// Peers connecting, disconnecting and being banned
+    Peer,
+    /// Announce results
+    Tracker,
+    /// Pieces written to disk
+    Storage,
+    /// Periodic transfer totals
+    Performance,
+}
+
+impl EventCategory {
+    pub const ALL: [EventCategory; 5] = [
+        EventCategory::Status,
+        EventCategory::Peer,
+        EventCategory::Tracker,
+        EventCategory::Storage,
+        EventCategory::Performance,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            EventCategory::Status      => "status",
+            EventCategory::Peer        => "peer",
+            EventCategory::Tracker     => "tracker",
+            EventCategory::Storage     => "storage",
+            EventCategory::Performance => "performance",
+        }
+    }
+}
+
+impl fmt::Display for EventCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EventCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventCategory::ALL
+            .into_iter()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| format!("unknown event category '{}'", s))
+    }
+}
+
+/// Set of [`EventCategory`] a sink is interested in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventMask(u8);
+
+impl EventMask {
+    pub const NONE: EventMask = EventMask(0);
+    pub const ALL: EventMask  = EventMask(0b1_1111);
+
+    pub fn with(self, category: EventCategory) -> Self {
+        Self(self.0 | category.bit())
+    }
+
+    pub fn without(self, category: EventCategory) -> Self {
+        Self(self.0 & !category.bit())
+    }
+
+    pub fn contains(self, category: EventCategory) -> bool {
+        self.0 & category.bit() != 0
+    }
+}
+
+impl Default for EventMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl FromIterator<EventCategory> for EventMask {
+    fn from_iter<I: IntoIterator<Item = EventCategory>>(iter: I) -> Self {
+        iter.into_iter().fold(Self::NONE, Self::with)
+    }
+}
+
+/// Something noteworthy that happened inside the engine
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TorrentAdded {
+        torrent:   TorrentId,
+        name:      String,
+        info_hash: String,
+    },
+    TrackerResponse {
+        torrent: TorrentId,
+        url:     String,
+        peers:   usize,
+    },
+    TrackerFailed {
+        torrent: TorrentId,
+        url:     String,
+        message: String,
+    },
+    PeerConnected {
+        torrent: TorrentId,
+        peer:    String,
+        source:  PeerSource,
+    },
+    PeerDisconnected {
+        torrent: TorrentId,
+        peer:    String,
+        reason:  DisconnectReason,
+    },
+    PeerBanned {
+        ip:      String,
+        /// Length of the ban in seconds, `None` if permanent
+        seconds: Option<u64>,
+    },
+    TorrentFinished {
+        torrent: TorrentId,
+    },
+    SeedingStopped {
+        torrent: TorrentId,
+        reason:  SeedStop,
+    },
+    /// The torrent stopped because of an error
+    TorrentFailed {
+        torrent: TorrentId,
+        message: String,
+    },
+    /// A verified piece was written to disk
+    PieceFinished {
+        torrent: TorrentId,
+        piece:   PieceIndex,
+    },
+    /// Bytes transferred by a torrent during the last usage interval
+    Transfer {
+        torrent:    TorrentId,
+        seconds:    u64,
+        downloaded: u64,
+        uploaded:   u64,
+    },
+    Error {
+        torrent: Option<TorrentId>,
+        message: String,
+    },
+    /// The machine lost its last routable address, or got one back
+    NetworkChanged {
+        up: bool,
+    },
+}
+
+impl Event {
+    pub fn category(&self) -> EventCategory {
+        match self {
+            Event::TorrentAdded { .. }
+            | Event::TorrentFinished { .. }
+            | Event::SeedingStopped { .. }
+            | Event::TorrentFailed { .. }
+            | Event::Error { .. }
+            | Event::NetworkChanged { .. } => EventCategory::Status,
+            Event::PeerConnected { .. } | Event::PeerDisconnected { .. } | Event::PeerBanned { .. } => {
+                EventCategory::Peer
+            }
+            Event::TrackerResponse { .. } | Event::TrackerFailed { .. } => EventCategory::Tracker,
+            Event::PieceFinished { .. } => EventCategory::Storage,
+            Event::Transfer { .. } => EventCategory::Performance,
+        }
+    }
+}
+
+/// An [`Event`] together with the moment it was emitted
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub event:     Event,
+}
+
+/// A consumer of events and the categories it receives
+#[derive(Debug, Clone)]
+struct Sink {
+    mask: EventMask,
+    tx:   mpsc::UnboundedSender<Record>,
+}
+
+/// Cloneable handle used by the actors to emit events
+///
+/// Emitting never blocks: records are handed to background sink tasks.
+/// Events no sink is interested in are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    sinks: Vec<Sink>,
+}
+
+impl Events {
+    /// Opens `path` in append mode and spawns a task writing one JSON object
+    /// per line for the events in `mask`
+    ///
+    /// The task ends once every handle has been dropped and the queue is drained.
+    pub fn add_log(&mut self, path: &Path, mask: EventMask) -> Result<JoinHandle<()>, ApplicationError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| ApplicationError::WorkerError(format!("event log: {}", e)))?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.sinks.push(Sink { mask, tx });
+        Ok(task::spawn(write_log(File::from_std(file), rx)))
+    }
+
+    /// Spawns a task delivering notifications about the emitted events
+    pub fn add_notifier(&mut self, notifier: Notifier) -> JoinHandle<()> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mask     = EventMask::NONE
+            .with(EventCategory::Status)
+            .with(EventCategory::Tracker);
+        self.sinks.push(Sink { mask, tx });
+        task::spawn(notifier.run(rx))
+    }
+
+    /// Returns `true` if some sink receives events of `category`, so callers
+    /// can skip building events nobody wants
+    pub fn wants(&self, category: EventCategory) -> bool {
+        self.sinks.iter().any(|s| s.mask.contains(category))
+    }
+
+    pub fn emit(&self, event: Event) {
+        let category = event.category();
+        if !self.wants(category) {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let record    = Record { timestamp, event };
+        for sink in self.sinks.iter().filter(|s| s.mask.contains(category)) {
+            let _ = sink.tx.send(record.clone());
+        }
+    }
+}
+
+async fn write_log(mut file: File, mut rx: mpsc::UnboundedReceiver<Record>) {
+    while let Some(record) = rx.recv().await {
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(_)   => continue,
+        };
+        line.push(b'\n');
+
+        if let Err(e) = file.write_all(&line).await {
+            println!("Failed to write event log: {}", e);
+            return;
+        }
+        let _ = file.flush().await;
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{error::ApplicationError, torrent::Torrent};
+
+/// Index of a piece, only obtainable through a [`Geometry`] that vouches for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct PieceIndex(u32);
+
+impl PieceIndex {
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Returns the index as sent in `have`, `request` and `piece` messages
+    pub fn to_wire(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for PieceIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Byte offset of a block from the start of its piece, aligned to the block size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct BlockOffset(u32);
+
+impl BlockOffset {
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Returns the offset as sent in `request` and `piece` messages
+    pub fn to_wire(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for BlockOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// How a torrent's content is split into pieces and blocks
+///
+/// Every index or offset coming from the wire goes through here before it
+/// is used, so the last (shorter) piece and block are handled in one place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    total_len: u64,
+    piece_len: u32,
+    block_len: u32,
+    pieces:    u32,
+}
+
+impl Geometry {
+    /// Computes the layout of `torrent` split into blocks of `block_len` bytes
+    ///
+    /// Fails if the piece length is unusable or the number of piece hashes
+    /// doesn't match the size of the content.
+    pub fn new(torrent: &Torrent, block_len: u32) -> Result<Self, ApplicationError> {
+        let piece_len = u32::try_from(torrent.piece_length())
+            .ok()
+            .filter(|len| *len > 0)
+            .ok_or_else(|| {
+                ApplicationError::ParserError(format!("invalid piece length {}", torrent.piece_length()))
+            })?;
+        let total_len = u64::try_from(torrent.total_size())
+            .map_err(|_| ApplicationError::ParserError(format!("invalid size {}", torrent.total_size())))?;
+        if block_len == 0 {
+            return Err(ApplicationError::ParserError("invalid block length 0".into()));
+        }
+
+        let pieces = total_len.div_ceil(piece_len as u64);
+        if pieces != torrent.pieces_count() as u64 {
+            return Err(ApplicationError::ParserError(format!(
+                "{} bytes in pieces of {} need {} hashes, found {}",
+                total_len,
+                piece_len,
+                pieces,
+                torrent.pieces_count()
+            )));
+        }
+        let pieces = u32::try_from(pieces)
+            .map_err(|_| ApplicationError::ParserError(format!("too many pieces ({})", pieces)))?;
+
+        Ok(Self {
+            total_len,
+            piece_len,
+            block_len: block_len.min(piece_len),
+            pieces,
+        })
+    }
+
+    /// Returns the same layout split into blocks of `block_len` bytes
+    pub fn with_block_len(self, block_len: u32) -> Self {
+        Self {
+            block_len: block_len.min(self.piece_len),
+            ..self
+        }
+    }
+
+    /// Returns the size of the blocks pieces are requested in; the last
+    /// block of a piece may be shorter
+    pub fn max_block_len(&self) -> u32 {
+        self.block_len
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.pieces as usize
+    }
+
+    /// Validates a piece index received from a peer
+    pub fn piece(&self, index: u32) -> Result<PieceIndex, ApplicationError> {
+        if index >= self.pieces {
+            return Err(ApplicationError::ProtocolError(format!(
+                "piece {} out of range (torrent has {})",
+                index, self.pieces
+            )));
+        }
+        Ok(PieceIndex(index))
+    }
+
+    /// Iterates over every piece, in order
+    pub fn pieces(&self) -> impl Iterator<Item = PieceIndex> + use<> {
+        (0..self.pieces).map(PieceIndex)
+    }
+
+    /// Returns the position of the first byte of `piece` in the content
+    pub fn piece_offset(&self, piece: PieceIndex) -> u64 {
+        piece.0 as u64 * self.piece_len as u64
+    }
+
+    /// Returns the piece starting at byte `offset` of the content, if one does
+    pub fn piece_at(&self, offset: u64) -> Option<PieceIndex> {
+        let piece_len = self.piece_len as u64;
+        (offset.is_multiple_of(piece_len) && offset < self.total_len).then(|| PieceIndex((offset / piece_len) as u32))
+    }
+
+    /// Returns the length of `piece`; only the last one may be shorter
+    pub fn piece_len(&self, piece: PieceIndex) -> u32 {
+        (self.total_len - self.piece_offset(piece)).min(self.piece_len as u64) as u32
+    }
+
+    /// Validates the offset of a block received from or requested by a peer
+    pub fn block(&self, piece: PieceIndex, offset: u32) -> Result<BlockOffset, ApplicationError> {
+        if offset >= self.piece_len(piece) || !offset.is_multiple_of(self.block_len) {
+            return Err(ApplicationError::ProtocolError(format!(
+                "invalid offset {} in piece {}",
+                offset, piece
+            )));
+        }
+        Ok(BlockOffset(offset))
+    }
+
+    /// Iterates over the blocks of `piece`, in order
+    pub fn blocks(&self, piece: PieceIndex) -> impl Iterator<Item = BlockOffset> + use<> {
+        (0..self.piece_len(piece))
+            .step_by(self.block_len as usize)
+            .map(BlockOffset)
+    }
+
+    /// Returns the length of a block; only the last one of a piece may be shorter
+    pub fn block_len(&self, piece: PieceIndex, block: BlockOffset) -> u32 {
+        (self.piece_len(piece) - block.0).min(self.block_len)
+    }
+
+    /// Validates the position and length of a whole block
+    pub fn check_block(&self, index: u32, begin: u32, length: usize) -> Result<(PieceIndex, BlockOffset), ApplicationError> {
+        let piece = self.piece(index)?;
+        let block = self.block(piece, begin)?;
+        if length != self.block_len(piece, block) as usize {
+            return Err(ApplicationError::ProtocolError(format!(
+                "block {} of piece {} is {} bytes, expected {}",
+                block,
+                piece,
+                length,
+                self.block_len(piece, block)
+            )));
+        }
+        Ok((piece, block))
+    }
+
+    /// Validates a bitfield message, which must not set the spare bits at its end
+    pub fn check_bitfield(&self, bytes: &[u8]) -> Result<(), ApplicationError> {
+        if bytes.len() != self.pieces_count().div_ceil(8) {
+            return Err(ApplicationError::ProtocolError(format!(
+                "bitfield of {} bytes for {} pieces",
+                bytes.len(),
+                self.pieces
+            )));
+        }
+        let spare = bytes.len() * 8 - self.pieces_count();
+        if spare > 0 && bytes.last().is_some_and(|b| b & ((1 << spare) - 1) != 0) {
+            return Err(ApplicationError::ProtocolError("bitfield sets spare bits".into()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use tokio::{process::Command, task};
+
+use crate::{
+    events::{Event, Events},
+    session::TorrentId,
+};
+
+/// Details about a torrent exposed to a hook through environment variables
+#[derive(Debug, Clone)]
+pub struct HookEnv {
+    pub torrent:   TorrentId,
+    pub name:      String,
+    /// Where the torrent's content lives on disk
+    pub path:      PathBuf,
+    pub info_hash: String,
+    pub label:     Option<String>,
+}
+
+/// Runs `command` through the shell without waiting for it to exit
+///
+/// The torrent is described by `TORRENTZ_NAME`, `TORRENTZ_PATH`,
+/// `TORRENTZ_HASH` and `TORRENTZ_LABEL` (empty when unset).
+pub fn spawn(command: &str, env: HookEnv, events: Events) {
+    let mut cmd = shell(command);
+    cmd.env("TORRENTZ_NAME", &env.name)
+        .env("TORRENTZ_PATH", &env.path)
+        .env("TORRENTZ_HASH", &env.info_hash)
+        .env("TORRENTZ_LABEL", env.label.as_deref().unwrap_or(""))
+        .kill_on_drop(false);
+
+    let command = command.to_string();
+    task::spawn(async move {
+        let message = match cmd.status().await {
+            Ok(status) if status.success() => return,
+            Ok(status) => format!("hook `{}` exited with {}", command, status),
+            Err(e)     => format!("hook `{}` failed to start: {}", command, e),
+        };
+        println!("{}", message);
+        events.emit(Event::Error {
+            torrent: Some(env.torrent),
+            message,
+        });
+    });
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
@@ -0,0 +1,35 @@
+use rand::{Rng, distributions::Alphanumeric};
+
+/// Azureus-style prefix identifying torrentz and its version
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-RU0001-";
+
+/// How the client presents itself to trackers and peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    /// Sent in the handshake and in announces
+    pub peer_id: [u8; 20],
+    /// Sent in announces so trackers can recognise us across IP changes
+    pub key:     u32,
+}
+
+impl Identity {
+    /// Generates a random peer id (keeping the client prefix) and a random key
+    pub fn generate() -> Self {
+        let mut rng     = rand::thread_rng();
+        let mut peer_id = [0u8; 20];
+        peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+        for byte in &mut peer_id[8..] {
+            *byte = rng.sample(Alphanumeric);
+        }
+
+        Self {
+            peer_id,
+            key: rng.r#gen(),
+        }
+    }
+
+    /// Returns the key formatted as sent to trackers
+    pub fn key_hex(&self) -> String {
+        format!("{:08X}", self.key)
+    }
+}
@@ -0,0 +1,66 @@
+use std::{fmt, sync::Arc};
+
+use crate::{peer::Peer, protocol::Message, recorder::Direction};
+
+/// What a peer connection does with a message an [`Inspector`] looked at
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Let the message through
+    Pass,
+    /// Discard the message, as if it was never sent or received
+    Drop,
+    /// Let the message through, then send these to the peer
+    Inject(Vec<Message>),
+}
+
+/// Observes, filters or adds to the messages exchanged with peers
+///
+/// Called from the peer tasks for every message, so implementations must be
+/// quick and must not block. Injected messages are not inspected again.
+pub trait Inspector: fmt::Debug + Send + Sync {
+    fn on_message(&self, peer: &Peer, direction: Direction, msg: &Message) -> Action;
+}
+
+/// The inspectors registered with a session, called in registration order
+#[derive(Debug, Clone, Default)]
+pub struct Inspectors {
+    list: Vec<Arc<dyn Inspector>>,
+}
+
+/// Outcome of running a message through every [`Inspector`]
+#[derive(Debug, Default)]
+pub struct Verdict {
+    /// The message goes on as usual
+    pub pass:   bool,
+    /// Messages to send to the peer afterwards
+    pub inject: Vec<Message>,
+}
+
+impl Inspectors {
+    pub fn add(&mut self, inspector: Arc<dyn Inspector>) {
+        self.list.push(inspector);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Runs `msg` through the inspectors; the first one dropping it ends the round
+    pub fn inspect(&self, peer: &Peer, direction: Direction, msg: &Message) -> Verdict {
+        let mut verdict = Verdict {
+            pass:   true,
+            inject: Vec::new(),
+        };
+        for inspector in &self.list {
+            match inspector.on_message(peer, direction, msg) {
+                Action::Pass          => {}
+                Action::Inject(extra) => verdict.inject.extend(extra),
+                Action::Drop          => {
+                    verdict.pass = false;
+                    break;
+                }
+            }
+        }
+        verdict
+    }
+}
@@ -0,0 +1,46 @@
+//! BitTorrent client library: a [`Session`] runs any number of torrents,
+//! each driven through its [`TorrentHandle`]
+
+pub mod config;
+pub mod engine;
+pub mod error;
+pub mod events;
+pub mod listen;
+pub mod magnet;
+pub mod peer;
+pub mod recorder;
+pub mod seal;
+pub mod session;
+pub mod stats;
+pub mod torrent;
+pub mod tracker;
+
+mod audit;
+mod banlist;
+mod bitfield;
+mod crossseed;
+mod dht;
+mod dial;
+mod geometry;
+mod hooks;
+mod identity;
+mod inspect;
+mod manager;
+mod metadata;
+mod netwatch;
+mod notify;
+mod piece;
+mod protocol;
+mod ratelimit;
+mod resume;
+mod storage;
+mod verify;
+
+pub use crate::{
+    config::{Config, Limits, TorrentOptions},
+    engine::{TorrentProgress, TorrentState},
+    error::ApplicationError,
+    magnet::Magnet,
+    session::{Session, TorrentHandle, TorrentId},
+    torrent::Torrent,
+};
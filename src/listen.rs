@@ -0,0 +1,145 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task, time,
+};
+
+use crate::{
+    config::PeerSockets,
+    dial,
+    error::ApplicationError,
+    protocol::{HANDSHAKE_LEN, Handshake},
+    session::Command,
+};
+
+/// How long a peer connecting to us may take to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections accepted by a listener before `accept` picks them up
+const BACKLOG: i32 = 1024;
+
+/// Where peers can connect to us, told to trackers and to peers
+///
+/// Only addresses we listen on specifically are known; with a wildcard
+/// listener the other side has to use the address it sees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Endpoints {
+    pub port: u16,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl Endpoints {
+    /// Collects the addresses of `listeners`, the first of each family winning
+    pub fn of(listeners: &[TcpListener]) -> Result<Self, ApplicationError> {
+        let mut endpoints = Self::default();
+        for listener in listeners {
+            let addr = listener
+                .local_addr()
+                .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
+            if endpoints.port == 0 {
+                endpoints.port = addr.port();
+            }
+            match addr.ip() {
+                ip if ip.is_unspecified() => {}
+                IpAddr::V4(ip)            => endpoints.ipv4 = endpoints.ipv4.or(Some(ip)),
+                IpAddr::V6(ip)            => endpoints.ipv6 = endpoints.ipv6.or(Some(ip)),
+            }
+        }
+        Ok(endpoints)
+    }
+}
+
+/// Opens the TCP port peers connect to (any if 0) on every address of `ips`,
+/// or on every IPv4 and IPv6 address if there are none
+///
+/// All listeners share the port the first one got. IPv6 ones only accept
+/// IPv6 connections, so they can sit next to IPv4 ones. Without `ips`, only
+/// the IPv4 listener has to open: the host may not have IPv6.
+pub fn bind(ips: &[IpAddr], port: u16) -> Result<Vec<TcpListener>, ApplicationError> {
+    if ips.is_empty() {
+        let mut listeners = bind(&[IpAddr::V4(Ipv4Addr::UNSPECIFIED)], port)?;
+        let port          = listeners[0]
+            .local_addr()
+            .map_err(|e| ApplicationError::PeerError(e.to_string()))?
+            .port();
+        match listen_on(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)) {
+            Ok(listener) => listeners.push(listener),
+            Err(e)       => println!("Not listening for IPv6 peers: {}", e),
+        }
+        return Ok(listeners);
+    }
+
+    let mut listeners = Vec::new();
+    let mut port      = port;
+    for ip in ips {
+        let addr     = SocketAddr::new(*ip, port);
+        let listener = listen_on(addr)
+            .map_err(|e| ApplicationError::PeerError(format!("listening on {}: {}", addr, e)))?;
+        if port == 0 {
+            port = listener
+                .local_addr()
+                .map_err(|e| ApplicationError::PeerError(e.to_string()))?
+                .port();
+        }
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn listen_on(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts peers until the session is gone, handing each connection to it
+/// once the peer's handshake tells which torrent it is for
+///
+/// Accepted sockets get `options`, like the ones we connect.
+pub async fn accept(listener: TcpListener, session: mpsc::WeakSender<Command>, options: PeerSockets) {
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        if let Err(e) = dial::tune(SockRef::from(&stream), &addr, &options) {
+            println!("Failed to set socket options for {}: {}", addr, e);
+        }
+        let Some(session) = session.upgrade() else {
+            return;
+        };
+        task::spawn(async move {
+            match time::timeout(HANDSHAKE_TIMEOUT, read_handshake(stream)).await {
+                Ok(Ok((stream, handshake))) => {
+                    let _ = session.send(Command::Incoming { stream, addr, handshake }).await;
+                }
+                Ok(Err(e)) => println!("Dropping incoming connection from {}: {:?}", addr, e),
+                Err(_)     => println!("Dropping incoming connection from {}: no handshake", addr),
+            }
+        });
+    }
+}
+
+async fn read_handshake(mut stream: TcpStream) -> Result<(TcpStream, Handshake), ApplicationError> {
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
+    Ok((stream, Handshake::decode(&buf)?))
+}
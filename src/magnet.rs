@@ -0,0 +1,207 @@
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use futures::{
+    future::{join, join_all},
+    stream::{FuturesUnordered, StreamExt},
+};
+use tokio::time;
+use url::Url;
+
+use crate::{
+    config::TrackerAuth,
+    dht::Dht,
+    error::ApplicationError,
+    identity::Identity,
+    peer::{ConnectionSettings, Peer, PeerConnection, PeerSource},
+    ratelimit::Throttle,
+    torrent::Torrent,
+    tracker::{AnnounceEvent, Progress, Tracker},
+};
+
+/// Number of peers metadata is requested from at the same time
+const METADATA_PEERS: usize = 8;
+
+/// How long a peer may take to connect and send the whole metadata
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes left reported to trackers while the size of the torrent is unknown;
+/// anything but 0, which would make us look like a seed
+const UNKNOWN_LEFT: u64 = 16 * 1024;
+
+/// A parsed `magnet:` URI pointing to a BitTorrent swarm
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name (`dn`), until the metadata tells the real one
+    pub name:      Option<String>,
+    /// Tracker URLs (`tr`), in the order given
+    pub trackers:  Vec<String>,
+    /// Peers to contact directly (`x.pe`)
+    pub peers:     Vec<SocketAddr>,
+    /// Size of the content in bytes (`xl`), if given
+    pub length:    Option<u64>,
+}
+
+impl Magnet {
+    /// Parses a magnet URI; it must carry a `urn:btih:` info hash, in hex or base32
+    pub fn parse(uri: &str) -> Result<Self, ApplicationError> {
+        let url = Url::parse(uri).map_err(|e| ApplicationError::ParserError(format!("{}: {}", uri, e)))?;
+        if url.scheme() != "magnet" {
+            return Err(ApplicationError::ParserError(format!("{}: not a magnet link", uri)));
+        }
+
+        let mut info_hash = None;
+        let mut magnet    = Self {
+            info_hash: [0; 20],
+            name:      None,
+            trackers:  Vec::new(),
+            peers:     Vec::new(),
+            length:    None,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash).ok_or_else(|| {
+                            ApplicationError::ParserError(format!("invalid info hash {}", hash))
+                        })?);
+                    }
+                }
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" if !magnet.trackers.iter().any(|url| *url == value) => {
+                    magnet.trackers.push(value.into_owned());
+                }
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddr>()),
+                "xl"   => magnet.length = value.parse().ok(),
+                _      => {}
+            }
+        }
+
+        magnet.info_hash = info_hash.ok_or_else(|| {
+            ApplicationError::ParserError(format!("{}: no BitTorrent info hash (xt=urn:btih:)", uri))
+        })?;
+        Ok(magnet)
+    }
+
+    /// Returns the display name, or the hex info hash if there is none
+    pub fn display_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| hex::encode(self.info_hash))
+    }
+}
+
+/// Decodes a 40-character hex or a 32-character base32 info hash
+fn parse_info_hash(value: &str) -> Option<[u8; 20]> {
+    match value.len() {
+        40 => hex::decode(value).ok()?.try_into().ok(),
+        32 => base32(value)?.try_into().ok(),
+        _  => None,
+    }
+}
+
+/// Decodes unpadded RFC 4648 base32, as used by older magnet links
+fn base32(value: &str) -> Option<Vec<u8>> {
+    let mut out  = Vec::with_capacity(value.len() * 5 / 8);
+    let mut bits = 0u32;
+    let mut len  = 0;
+    for c in value.bytes() {
+        let digit = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _               => return None,
+        };
+        bits = (bits << 5) | digit as u32;
+        len += 5;
+        if len >= 8 {
+            len -= 8;
+            out.push((bits >> len) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// What downloading the metadata of a magnet link needs from the session
+#[derive(Debug)]
+pub struct MetadataFetch {
+    pub tracker:  Tracker,
+    /// Searched for peers along with the trackers, if enabled
+    pub dht:      Option<Dht>,
+    pub identity: Identity,
+    /// Settings of the connections to peers, without geometry or metadata
+    pub settings: ConnectionSettings,
+    pub throttle: Throttle,
+}
+
+impl MetadataFetch {
+    /// Downloads the info dictionary of `magnet` and returns the torrent it describes
+    ///
+    /// Peers come from the magnet's trackers, the DHT and `x.pe` entries,
+    /// plus `peers`. They are asked [`METADATA_PEERS`] at a time; the first one
+    /// delivering metadata that matches the info hash wins.
+    pub async fn run(self, magnet: &Magnet, mut peers: Vec<Peer>) -> Result<Torrent, ApplicationError> {
+        let progress  = Progress {
+            uploaded:   0,
+            downloaded: 0,
+            left:       magnet.length.unwrap_or(UNKNOWN_LEFT),
+            event:      AnnounceEvent::Started,
+        };
+        let auth      = TrackerAuth::default();
+        let announces = join_all(
+            magnet
+                .trackers
+                .iter()
+                .map(|url| self.tracker.announce(url, &magnet.info_hash, progress, &self.identity, &auth)),
+        );
+        let search    = async {
+            match &self.dht {
+                Some(dht) => dht.get_peers(magnet.info_hash, self.tracker.endpoints.port).await,
+                None      => Vec::new(),
+            }
+        };
+        let (announces, found) = join(announces, search).await;
+        peers.extend(found);
+        for (url, result) in magnet.trackers.iter().zip(announces) {
+            match result {
+                Ok(announce) => peers.extend(announce.peers),
+                Err(e)       => println!("Tracker {} failed: {:?}", url, e),
+            }
+        }
+        peers.extend(magnet.peers.iter().map(|addr| Peer {
+            ip:      addr.ip(),
+            port:    addr.port(),
+            source:  PeerSource::Manual,
+            peer_id: None,
+            host:    None,
+        }));
+
+        let mut seen     = HashSet::new();
+        let mut pending  = peers.into_iter().filter(|p| seen.insert(p.addr()));
+        let mut attempts = FuturesUnordered::new();
+        let mut error    = ApplicationError::PeerError("no peer to fetch metadata from".into());
+        loop {
+            while attempts.len() < METADATA_PEERS
+                && let Some(peer) = pending.next()
+            {
+                attempts.push(self.fetch_from(peer));
+            }
+            let Some(result) = attempts.next().await else {
+                return Err(error);
+            };
+            match result {
+                Ok(info) => return Torrent::from_metadata(info, &magnet.trackers),
+                Err(e)   => error = e,
+            }
+        }
+    }
+
+    async fn fetch_from(&self, peer: Peer) -> Result<Vec<u8>, ApplicationError> {
+        let work = async {
+            let mut conn = PeerConnection::connect(&peer, &self.settings, self.throttle.clone()).await?;
+            let info     = conn.fetch_metadata(&self.settings.info_hash).await;
+            conn.close(true).await;
+            info
+        };
+        time::timeout(METADATA_TIMEOUT, work)
+            .await
+            .map_err(|_| ApplicationError::PeerError(format!("{}: metadata timed out", peer)))?
+    }
+}
@@ -1,132 +1,259 @@
-use crate::{
-    error::ApplicationError,
-    manager::PieceManager,
-    peer::{Peer, PeerConnection},
-    piece::Piece,
-    torrent::Torrent,
-    tracker::Tracker,
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, Instant},
 };
 
-use std::sync::Arc;
-use tokio::{
-    sync::{Mutex, Semaphore},
-    task,
+use clap::Parser;
+use futures::future::join_all;
+use tokio::signal;
+
+use torrentz::{
+    ApplicationError, Magnet, Session, Torrent, TorrentState,
+    config::{TrackerAuth, TrackerHttp, TrackerUdp},
+    listen::Endpoints,
+    recorder::{self, Direction},
+    stats::TorrentTotals,
+    tracker::{PEER_PORT, Scrape, Tracker},
 };
 
-mod error;
-mod manager;
-mod peer;
-mod piece;
-mod protocol;
-mod torrent;
-mod tracker;
+use crate::{
+    cli::{Cli, CliCommand, DownloadArgs, InfoArgs, PeerAddr, ReplayArgs, ScrapeArgs},
+    report::{Report, TorrentReport},
+};
 
-const BLOCK_SIZE: usize     = 16 * 1024;
-const CONCURRENCY: usize    = 10;
-const BATCH_SIZE: usize     = 20;
-const PEER_ID: [u8; 20]    = *b"-RU0001-123456789010";
+mod cli;
+mod reload;
+mod report;
 
 #[tokio::main]
-async fn main() -> Result<(), ApplicationError> {
-    // Load torrent file and fetch the peers
-    let torrent = Torrent::from_file("test.torrent")?;
-    let tracker = Tracker;
-    let peers   = tracker.announce(&torrent).await?;
-
-    // Log the torrent info
-    torrent.log_info();
+async fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        CliCommand::Download(args) => download(*args).await,
+        CliCommand::Info(args)     => info(args),
+        CliCommand::Replay(args)   => replay(args),
+        CliCommand::Scrape(args)   => scrape(args).await,
+    };
 
-    if peers.is_empty() {
-        return Err(ApplicationError::ProtocolError("no peers".into()));
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(e)   => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(report::exit_code(Some(&e)))
+        }
     }
+}
 
-    // Initialize piece manager
-    let manager  = PieceManager::new(&torrent, BLOCK_SIZE);
-    let pieces   = Arc::new(Mutex::new(manager.pieces));
-    let peers    = Arc::new(peers);
-    let sem      = Arc::new(Semaphore::new(CONCURRENCY));
-    let peer_idx = Arc::new(Mutex::new(0));
-    let info_hash= torrent.info_hash();
+async fn download(args: DownloadArgs) -> Result<u8, ApplicationError> {
+    let started = Instant::now();
 
-    // Start the main download loop
-    download_loop(pieces, peers, sem, peer_idx, info_hash).await;
+    // A directory adds every .torrent file it contains
+    let paths = if args.path.is_dir() {
+        Torrent::find_in_dir(&args.path)?
+    } else {
+        vec![args.path.clone()]
+    };
 
-    println!("Download complete!");
-    Ok(())
+    if paths.is_empty() {
+        return Err(ApplicationError::ParserError(format!(
+            "no .torrent files in {}",
+            args.path.display()
+        )));
+    }
+
+    // Hand every torrent to the same session
+    let session = Session::new(args.config()?)?;
+    if let Some(path) = &args.config_file {
+        tokio::spawn(reload::watch(path.clone(), args.limits(), session.clone()));
+    }
+    let result  = run(&session, &args, &paths, started).await;
+
+    // Let event sinks (log, webhook) deliver what is still queued
+    let _ = session.shutdown().await;
+    result
 }
 
-async fn download_loop(
-    pieces:   Arc<Mutex<Vec<Piece>>>,
-    peers:    Arc<Vec<Peer>>,
-    sem:      Arc<Semaphore>,
-    peer_idx: Arc<Mutex<usize>>,
-    info_hash:[u8; 20],
-) {
-    loop {
-        // Get a batch of pieces to download
-        let batch = get_batch(&pieces).await;
-        if batch.is_empty() {
-            break; // no more pieces to download
+async fn run(
+    session: &Session,
+    args:    &DownloadArgs,
+    paths:   &[PathBuf],
+    started: Instant,
+) -> Result<u8, ApplicationError> {
+    let mut handles = Vec::new();
+    for path in paths {
+        let torrent = match path.to_str().filter(|p| p.starts_with("magnet:")) {
+            Some(uri) => {
+                let magnet = Magnet::parse(uri)?;
+                println!("Fetching metadata of {}", magnet.display_name());
+                let mut peers = Vec::new();
+                for addr in &args.peers {
+                    peers.push(addr.resolve().await?);
+                }
+                session.fetch_metadata(magnet, peers).await?
+            }
+            None => Torrent::from_file(path)?,
+        };
+        torrent.log_info();
+
+        let name      = torrent.info.name.clone();
+        let info_hash = hex::encode(torrent.info_hash());
+        let options   = args.options(&torrent)?;
+        let handle    = session.add_torrent_with(torrent, options).await?;
+        for addr in &args.peers {
+            match addr {
+                PeerAddr::Ip(addr)         => handle.add_peer(*addr).await?,
+                PeerAddr::Host(host, port) => handle.add_peer_host(host, *port).await?,
+            }
+        }
+        handles.push((name, info_hash, handle));
+    }
+
+    // Wait for all of them to complete, stopping them if we are interrupted
+    let completed = join_all(handles.iter().map(|(_, _, h)| h.completed()));
+    let results   = tokio::select! {
+        results = completed => results,
+        _ = signal::ctrl_c() => {
+            println!("Stopping...");
+            for (_, _, handle) in &handles {
+                let _ = handle.stop().await;
+            }
+            join_all(handles.iter().map(|(_, _, h)| h.finished())).await
+        }
+    };
+    let mut first_error = None;
+    for ((name, _, handle), result) in handles.iter().zip(results) {
+        match result {
+            Ok(()) if matches!(handle.state(), TorrentState::Stopped) => println!("Download stopped: {}", name),
+            Ok(()) => println!("Download complete: {}", name),
+            Err(e) => {
+                println!("Download failed: {} ({:?})", name, e);
+                first_error.get_or_insert(e);
+            }
         }
+    }
 
-        let permit         = sem.clone().acquire_owned().await.unwrap();
-        let peers_clone    = peers.clone();
-        let peer_idx_clone = peer_idx.clone();
-        let batch_clone    = batch.clone();
-
-        // Spawn a new task to handle the peer download
-        task::spawn(async move {
-            let peer = select_peer(&peers_clone, &peer_idx_clone).await;
-            let _    = runtime(&peer, &batch_clone, info_hash, PEER_ID).await;
-            drop(permit);
-        });
+    // Seed until every torrent reaches its stop condition or we are interrupted
+    let finished = join_all(handles.iter().map(|(_, _, h)| h.finished()));
+    tokio::select! {
+        _ = finished => {}
+        _ = signal::ctrl_c() => {
+            println!("Stopping...");
+            for (_, _, handle) in &handles {
+                let _ = handle.stop().await;
+            }
+            join_all(handles.iter().map(|(_, _, h)| h.finished())).await;
+        }
     }
 
-    // Wait for all ongoing downloads to finish by acquiring all permits
-    for _ in 0..CONCURRENCY {
-        sem.acquire().await.unwrap().forget();
+    let totals = session.stats().await?;
+    println!(
+        "Downloaded {} bytes, uploaded {} bytes (ratio {:.2})",
+        totals.total_downloaded, totals.total_uploaded, totals.share_ratio,
+    );
+    for (name, _, handle) in &handles {
+        if let Ok(TorrentTotals { swarm: Some(swarm), .. }) = handle.stats().await {
+            let completed = swarm.downloads.map_or("?".to_string(), |d| d.to_string());
+            println!(
+                "Swarm of {}: {} seeders, {} leechers, {} completed (reported {}s ago)",
+                name, swarm.seeders, swarm.leechers, completed, swarm.age_secs,
+            );
+        }
     }
-}
 
-async fn get_batch(pieces: &Arc<Mutex<Vec<Piece>>>) -> Vec<Piece> {
-    let mut lock = pieces.lock().await;
-    if lock.is_empty() {
-        vec![]
-    } else {
-        let count = BATCH_SIZE.min(lock.len());
-        lock.drain(0..count).collect()
+    let exit_code = report::exit_code(first_error.as_ref());
+    if let Some(path) = &args.report {
+        let mut torrents = Vec::new();
+        for (name, info_hash, handle) in handles {
+            let (status, error) = match handle.state() {
+                TorrentState::Failed(e) => ("failed", Some(format!("{:?}", e))),
+                TorrentState::Stopped   => ("stopped", None),
+                _                       => ("completed", None),
+            };
+            torrents.push(TorrentReport {
+                name,
+                info_hash,
+                status,
+                error,
+                totals: handle.stats().await?,
+            });
+        }
+        Report::new(torrents, started.elapsed(), exit_code).write(path)?;
     }
+
+    Ok(exit_code)
 }
 
-async fn select_peer(peers: &Arc<Vec<Peer>>, peer_idx: &Arc<Mutex<usize>>) -> Peer {
-    let mut idx = peer_idx.lock().await;
-    let peer    = peers[*idx].clone();
-    *idx       = (*idx + 1) % peers.len();
-    peer
+fn info(args: InfoArgs) -> Result<u8, ApplicationError> {
+    Torrent::from_file(&args.path)?.log_info();
+    Ok(report::EXIT_SUCCESS)
 }
 
-/// Handles a single peer connection: connect, handshake, interested, and read messages.
-async fn runtime(
-    peer:      &Peer,
-    pieces:    &[Piece],
-    info_hash: [u8; 20],
-    peer_id:   [u8; 20],
-) -> Result<(), ApplicationError> {
-    let mut conn = PeerConnection::connect(peer, info_hash, peer_id).await?;
+fn replay(args: ReplayArgs) -> Result<u8, ApplicationError> {
+    let frames = recorder::load(&args.path)?;
+    let start  = frames.first().map_or(0, |f| f.timestamp);
 
-    println!(
-        "Connected to {}:{}, downloading pieces from {} to {}",
-        peer.ip,
-        peer.port,
-        pieces.first().unwrap().index,
-        pieces.last().unwrap().index,
-    );
+    recorder::replay(&frames, |frame, result, state| {
+        let arrow = match frame.direction {
+            Direction::Sent     => "->",
+            Direction::Received => "<-",
+        };
+        println!(
+            "+{:>8}ms #{} {} {} {}",
+            frame.timestamp - start,
+            frame.connection,
+            frame.peer,
+            arrow,
+            frame.decoded
+        );
+        if let Err(e) = result {
+            println!("           error: {:?}", e);
+        }
+        println!(
+            "           choked={} interested={} pieces={}",
+            state.choked,
+            state.interested,
+            state.available_pieces.count_ones()
+        );
+    });
+    Ok(report::EXIT_SUCCESS)
+}
 
-    conn.send_interested().await?;
+async fn scrape(args: ScrapeArgs) -> Result<u8, ApplicationError> {
+    let torrent  = Torrent::from_file(&args.path)?;
+    let trackers = torrent.trackers();
+    if trackers.is_empty() {
+        return Err(ApplicationError::TrackerError("torrent has no trackers".into()));
+    }
+
+    let http = TrackerHttp {
+        timeout: Duration::from_secs(args.tracker_timeout),
+        ..TrackerHttp::default()
+    };
+    let endpoints = Endpoints { port: PEER_PORT, ..Endpoints::default() };
+    let tracker   = Tracker::new(None, None, endpoints, &http, &TrackerUdp::default())?;
+    let info_hash = torrent.info_hash();
+    let auth      = TrackerAuth::default();
+    let results   = join_all(trackers.iter().map(|url| tracker.scrape(url, &info_hash, &auth))).await;
 
-    // // Print pieces that peer has available
-    // let available: Vec<_> = conn.available_pieces().iter().cloned().collect();
-    // println!("Peer {} has pieces {:?}", peer.ip, available);
+    println!("{:>8} {:>8} {:>10}  tracker", "seeds", "peers", "downloads");
+    let mut best: Option<Scrape> = None;
+    for (url, result) in trackers.iter().zip(results) {
+        match result {
+            Ok(scrape) => {
+                println!("{:>8} {:>8} {:>10}  {}", scrape.seeders, scrape.leechers, scrape.downloads, url);
+                let best       = best.get_or_insert_with(Scrape::default);
+                best.seeders   = best.seeders.max(scrape.seeders);
+                best.leechers  = best.leechers.max(scrape.leechers);
+                best.downloads = best.downloads.max(scrape.downloads);
+            }
+            Err(e) => println!("{:>8} {:>8} {:>10}  {} ({:?})", "-", "-", "-", url, e),
+        }
+    }
 
-    Ok(())
+    // Trackers share most of their peers, so the largest count is the best estimate
+    let Some(best) = best else {
+        return Ok(report::EXIT_TRACKER);
+    };
+    println!("{:>8} {:>8} {:>10}  (highest)", best.seeders, best.leechers, best.downloads);
+    Ok(report::EXIT_SUCCESS)
 }
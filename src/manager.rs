@@ -1,68 +1,179 @@
-use crate::piece::{Block, BlockState, Piece};
-use crate::torrent::Torrent;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::bitfield::Bitfield;
+use crate::geometry::{BlockOffset, Geometry, PieceIndex};
+use crate::piece::{BlockState, Piece};
+use crate::verify;
 
 pub struct PieceManager {
     pub pieces: Vec<Piece>,
-    pub len: usize,
-    pub last_len: usize,
-    pub block_size: usize,
+    geometry: Geometry,
+    /// Expected SHA-1 of every piece of the torrent, from the info dictionary
+    hashes: Arc<[[u8; 20]]>,
+    /// Data received so far of the pieces being downloaded
+    buffers: HashMap<PieceIndex, Vec<u8>>,
+    /// Number of connected peers having each piece of the torrent
+    availability: Vec<u32>,
+    /// Pieces of each file, set when the rarest files go first
+    files: Vec<Range<usize>>,
 }
 
 impl PieceManager {
-    pub fn new(torrent: &Torrent, block_size: usize) -> Self {
-        let len = torrent.piece_length() as usize;
-        let tot = torrent.total_size() as usize;
-        let cnt = torrent.pieces_count();
-        let last_len = if tot % len == 0 { len } else { tot % len };
-
-        let pieces = (0..cnt)
-            .map(|i| {
-                let piece_size = if i == cnt - 1 { last_len } else { len };
-                let blks = (0..piece_size)
-                    .step_by(block_size)
-                    .map(|off| {
-                        let blen = std::cmp::min(block_size, piece_size - off);
-                        Block {
-                            offset: off,
-                            length: blen,
-                            state: BlockState::NotRequested,
-                        }
-                    })
-                    .collect();
-
-                Piece {
-                    index: i,
-                    blocks: blks,
-                }
-            })
+    /// Splits every piece of `geometry` into blocks, all not requested yet
+    ///
+    /// Sizes come from the geometry, so a shorter last piece, a last block
+    /// cut by the end of its piece and single-piece torrents need no
+    /// special casing here or in the callers.
+    pub fn new(geometry: &Geometry, hashes: Arc<[[u8; 20]]>) -> Self {
+        let pieces = geometry
+            .pieces()
+            .map(|index| Piece::new(geometry, index))
             .collect();
 
+        Self::with_pieces(geometry, hashes, pieces)
+    }
+
+    /// Tracks only `pieces`, such as the batch handed to a peer
+    pub fn with_pieces(geometry: &Geometry, hashes: Arc<[[u8; 20]]>, pieces: Vec<Piece>) -> Self {
         Self {
             pieces,
-            len,
-            last_len,
-            block_size,
+            geometry: *geometry,
+            hashes,
+            buffers: HashMap::new(),
+            availability: vec![0; geometry.pieces_count()],
+            files: Vec::new(),
         }
     }
 
-    pub fn mark_block_requested(&mut self, pidx: usize, boff: usize) {
-        self.pieces
-            .get_mut(pidx)
+    /// Makes [`PieceManager::take_rarest`] hand out the pieces of the rarest
+    /// files first, `files` being the pieces of each file
+    ///
+    /// A file is as rare as the rarest of its pieces still queued: the swarm
+    /// can't complete it past that piece.
+    pub fn prefer_rare_files(&mut self, files: Vec<Range<usize>>) {
+        self.files = files;
+    }
+
+    /// Returns `true` once every piece was handed out
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    /// Returns the number of pieces not handed out yet
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Counts the pieces of a peer that just connected, or announced more,
+    /// in place of the ones it was known to have before
+    pub fn update_availability(&mut self, before: &Bitfield, after: &Bitfield) {
+        for index in before.iter_ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        for index in after.iter_ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Returns the number of connected peers known to have piece `index`
+    pub fn availability(&self, index: PieceIndex) -> u32 {
+        self.availability.get(index.get()).copied().unwrap_or(0)
+    }
+
+    /// Hands out up to `count` of the pieces a peer has, rarest first
+    ///
+    /// Equally rare pieces go in queue order, so pieces put back with
+    /// [`PieceManager::give_back`] or moved to the front for streaming come
+    /// before the others. With [`PieceManager::prefer_rare_files`], the
+    /// rarity of their files comes before their own.
+    pub fn take_rarest(&mut self, have: &Bitfield, count: usize) -> Vec<Piece> {
+        let files          = self.file_rarity();
+        let mut candidates = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| have.get(p.index.get()))
+            .map(|(pos, p)| (files.get(p.index.get()).copied().unwrap_or(0), self.availability(p.index), pos))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        candidates.truncate(count);
+
+        let mut chosen = candidates.into_iter().map(|(_, _, pos)| pos).collect::<Vec<_>>();
+        chosen.sort_unstable();
+        let (taken, kept) = std::mem::take(&mut self.pieces)
+            .into_iter()
+            .enumerate()
+            .partition::<Vec<_>, _>(|(pos, _)| chosen.binary_search(pos).is_ok());
+        self.pieces = kept.into_iter().map(|(_, p)| p).collect();
+        taken.into_iter().map(|(_, p)| p).collect()
+    }
+
+    /// Returns, for every piece, the rarity of the rarest file it is part
+    /// of; empty unless [`PieceManager::prefer_rare_files`] was called
+    fn file_rarity(&self) -> Vec<u32> {
+        if self.files.is_empty() {
+            return Vec::new();
+        }
+
+        let mut queued = vec![false; self.availability.len()];
+        for piece in &self.pieces {
+            queued[piece.index.get()] = true;
+        }
+        let mut rarity = vec![u32::MAX; self.availability.len()];
+        for file in &self.files {
+            let Some(file_rarity) = file.clone().filter(|&i| queued[i]).map(|i| self.availability[i]).min() else {
+                continue;
+            };
+            for index in file.clone() {
+                rarity[index] = rarity[index].min(file_rarity);
+            }
+        }
+        rarity
+    }
+
+    /// Puts back pieces a peer didn't download, ahead of the others
+    pub fn give_back(&mut self, pieces: Vec<Piece>) {
+        self.pieces.splice(0..0, pieces);
+    }
+
+    /// Returns the length of a piece; only the last one may be shorter
+    pub fn piece_size(&self, index: PieceIndex) -> usize {
+        self.geometry.piece_len(index) as usize
+    }
+
+    pub fn mark_block_requested(&mut self, pidx: PieceIndex, boff: BlockOffset) {
+        if let Some(b) = self
+            .pieces
+            .iter_mut()
+            .find(|p| p.index == pidx)
             .and_then(|p| p.blocks.iter_mut().find(|b| b.offset == boff))
             .filter(|b| matches!(b.state, BlockState::NotRequested))
-            .map(|b| b.state = BlockState::Requested);
+        {
+            b.state = BlockState::Requested;
+        }
     }
 
-    pub fn mark_block_downloaded(&mut self, pidx: usize, boff: usize) {
-        self.pieces
-            .get_mut(pidx)
+    pub fn mark_block_downloaded(&mut self, pidx: PieceIndex, boff: BlockOffset) {
+        if let Some(b) = self
+            .pieces
+            .iter_mut()
+            .find(|p| p.index == pidx)
             .and_then(|p| p.blocks.iter_mut().find(|b| b.offset == boff))
-            .map(|b| b.state = BlockState::Downloaded);
+        {
+            b.state = BlockState::Downloaded;
+        }
     }
 
-    pub fn is_piece_complete(&self, pidx: usize) -> bool {
+    pub fn is_piece_complete(&self, pidx: PieceIndex) -> bool {
         self.pieces
-            .get(pidx)
+            .iter()
+            .find(|p| p.index == pidx)
             .map(|p| {
                 p.blocks
                     .iter()
@@ -71,7 +182,86 @@ impl PieceManager {
             .unwrap_or(false)
     }
 
-    pub fn needed_blocks(&self) -> Vec<(usize, usize)> {
+    /// Stores a requested block, returning the piece's data once it is complete
+    ///
+    /// Blocks that were not requested, or were already received, are ignored.
+    pub fn store_block(&mut self, pidx: PieceIndex, boff: BlockOffset, data: &[u8]) -> Option<Vec<u8>> {
+        let requested = self
+            .pieces
+            .iter()
+            .find(|p| p.index == pidx)
+            .and_then(|p| p.blocks.iter().find(|b| b.offset == boff))
+            .is_some_and(|b| matches!(b.state, BlockState::Requested) && b.length == data.len());
+        if !requested {
+            return None;
+        }
+
+        let size   = self.piece_size(pidx);
+        let buffer = self.buffers.entry(pidx).or_insert_with(|| vec![0u8; size]);
+        buffer[boff.get()..boff.get() + data.len()].copy_from_slice(data);
+        self.mark_block_downloaded(pidx, boff);
+
+        if self.is_piece_complete(pidx) {
+            return self.buffers.remove(&pidx);
+        }
+        None
+    }
+
+    /// Returns `true` if `data`, the assembled blocks of a piece, has the
+    /// SHA-1 listed for it in the info dictionary
+    pub fn verify_piece(&self, pidx: PieceIndex, data: &[u8]) -> bool {
+        self.hashes
+            .get(pidx.get())
+            .is_some_and(|hash| verify::piece_matches(data, hash))
+    }
+
+    /// Forgets the data of a piece so all of its blocks get requested again
+    pub fn reset_piece(&mut self, pidx: PieceIndex) {
+        self.buffers.remove(&pidx);
+        if let Some(p) = self.pieces.iter_mut().find(|p| p.index == pidx) {
+            for b in &mut p.blocks {
+                b.state = BlockState::NotRequested;
+            }
+        }
+    }
+
+    /// Stops downloading a piece, returning the blocks requested and not
+    /// received yet
+    pub fn drop_piece(&mut self, pidx: PieceIndex) -> Vec<BlockOffset> {
+        self.buffers.remove(&pidx);
+        let Some(pos) = self.pieces.iter().position(|p| p.index == pidx) else {
+            return Vec::new();
+        };
+        self.pieces
+            .remove(pos)
+            .blocks
+            .iter()
+            .filter(|b| b.state == BlockState::Requested)
+            .map(|b| b.offset)
+            .collect()
+    }
+
+    /// Forgets every outstanding request, so their blocks get requested again
+    ///
+    /// Needed when the peer chokes us, as it discards the requests it got.
+    pub fn cancel_requests(&mut self) {
+        for b in self.pieces.iter_mut().flat_map(|p| p.blocks.iter_mut()) {
+            if b.state == BlockState::Requested {
+                b.state = BlockState::NotRequested;
+            }
+        }
+    }
+
+    /// Returns the number of blocks requested and not received yet
+    pub fn requested_blocks(&self) -> usize {
+        self.pieces
+            .iter()
+            .flat_map(|p| &p.blocks)
+            .filter(|b| matches!(b.state, BlockState::Requested))
+            .count()
+    }
+
+    pub fn needed_blocks(&self) -> Vec<(PieceIndex, BlockOffset)> {
         self.pieces
             .iter()
             .flat_map(|p| {
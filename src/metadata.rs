@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::error::ApplicationError;
+
+/// Size of a metadata piece; only the last one may be shorter (BEP 9)
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// Largest info dictionary we serve or accept from peers
+pub const MAX_METADATA_SIZE: usize = 4 * 1024 * 1024;
+
+/// Extended message id we receive `ut_metadata` messages on
+pub const UT_METADATA_ID: u8 = 3;
+
+/// Name of the extension in the `m` dictionary of extension handshakes
+pub const UT_METADATA: &str = "ut_metadata";
+
+const REQUEST: i64 = 0;
+const DATA: i64    = 1;
+const REJECT: i64  = 2;
+
+/// Header of a `ut_metadata` message; `data` messages append the piece after it
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type:   i64,
+    piece:      i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+/// The bencoded info dictionary of a torrent, served to peers that only
+/// know its info hash (BEP 9)
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    bytes: Arc<[u8]>,
+}
+
+impl Metadata {
+    /// Wraps the raw info dictionary; `None` if it exceeds [`MAX_METADATA_SIZE`]
+    pub fn new(info: &[u8]) -> Option<Self> {
+        (!info.is_empty() && info.len() <= MAX_METADATA_SIZE).then(|| Self { bytes: info.into() })
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns the chunk `index` of the metadata, if it exists
+    pub fn piece(&self, index: usize) -> Option<&[u8]> {
+        self.bytes.chunks(METADATA_PIECE_LEN).nth(index)
+    }
+}
+
+/// Builds the reply to a `ut_metadata` message received from a peer
+///
+/// Requests get the piece asked for, or a reject if it doesn't exist or we
+/// have no `metadata` yet. Other messages need no reply and return `None`.
+pub fn answer(metadata: Option<&Metadata>, payload: &[u8]) -> Result<Option<Vec<u8>>, ApplicationError> {
+    let msg = decode(payload)?;
+    if msg.msg_type != REQUEST {
+        return Ok(None);
+    }
+
+    let piece = usize::try_from(msg.piece)
+        .ok()
+        .and_then(|i| metadata?.piece(i));
+    let reply = MetadataMessage {
+        msg_type:   if piece.is_some() { DATA } else { REJECT },
+        piece:      msg.piece,
+        total_size: metadata.filter(|_| piece.is_some()).map(|m| m.size() as i64),
+    };
+    let mut out = encode(&reply)?;
+    out.extend_from_slice(piece.unwrap_or_default());
+    Ok(Some(out))
+}
+
+/// Builds the `ut_metadata` request for piece `index`
+pub fn request(index: usize) -> Vec<u8> {
+    let msg = MetadataMessage {
+        msg_type:   REQUEST,
+        piece:      index as i64,
+        total_size: None,
+    };
+    encode(&msg).unwrap_or_default()
+}
+
+fn decode(payload: &[u8]) -> Result<MetadataMessage, ApplicationError> {
+    // `data` messages carry the piece after the dictionary, which the
+    // decoder leaves unread
+    serde_bencode::from_bytes(payload)
+        .map_err(|e| ApplicationError::ProtocolError(format!("invalid ut_metadata message: {}", e)))
+}
+
+fn encode(msg: &MetadataMessage) -> Result<Vec<u8>, ApplicationError> {
+    serde_bencode::to_bytes(msg).map_err(|e| ApplicationError::ProtocolError(format!("ut_metadata: {}", e)))
+}
+
+/// An info dictionary being downloaded from a peer, piece by piece (BEP 9)
+#[derive(Debug)]
+pub struct MetadataDownload {
+    bytes:    Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl MetadataDownload {
+    /// Prepares for metadata of `size` bytes, as announced by the peer
+    pub fn new(size: usize) -> Result<Self, ApplicationError> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(ApplicationError::ProtocolError(format!("invalid metadata size {}", size)));
+        }
+        Ok(Self {
+            bytes:    vec![0; size],
+            received: vec![false; size.div_ceil(METADATA_PIECE_LEN)],
+        })
+    }
+
+    pub fn pieces(&self) -> usize {
+        self.received.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|r| *r)
+    }
+
+    /// Stores the piece carried by a `data` message; a reject ends the download
+    pub fn receive(&mut self, payload: &[u8]) -> Result<(), ApplicationError> {
+        let msg = decode(payload)?;
+        match msg.msg_type {
+            DATA   => {}
+            REJECT => {
+                return Err(ApplicationError::PeerError(format!("peer rejected metadata piece {}", msg.piece)));
+            }
+            _      => return Ok(()),
+        }
+        if msg.total_size.is_some_and(|size| size != self.bytes.len() as i64) {
+            return Err(ApplicationError::ProtocolError("metadata size changed".into()));
+        }
+
+        let index = usize::try_from(msg.piece)
+            .ok()
+            .filter(|i| *i < self.pieces())
+            .ok_or_else(|| ApplicationError::ProtocolError(format!("invalid metadata piece {}", msg.piece)))?;
+        let start = index * METADATA_PIECE_LEN;
+        let len   = METADATA_PIECE_LEN.min(self.bytes.len() - start);
+        if payload.len() < len {
+            return Err(ApplicationError::ProtocolError(format!("short metadata piece {}", index)));
+        }
+
+        self.bytes[start..start + len].copy_from_slice(&payload[payload.len() - len..]);
+        self.received[index] = true;
+        Ok(())
+    }
+
+    /// Returns the assembled info dictionary if it hashes to `info_hash`
+    pub fn finish(self, info_hash: &[u8; 20]) -> Result<Vec<u8>, ApplicationError> {
+        if Sha1::digest(&self.bytes).as_slice() != info_hash {
+            return Err(ApplicationError::ProtocolError("metadata doesn't match the info hash".into()));
+        }
+        Ok(self.bytes)
+    }
+}
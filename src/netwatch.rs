@@ -0,0 +1,261 @@
+use std::io;
+
+/// Whether the machine can reach other hosts, as far as its addresses tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    /// Some interface has a routable address
+    Up,
+    /// Only loopback and link-local addresses are left
+    Down,
+}
+
+/// Reports when the machine loses its last routable address or gets one
+/// back, e.g. when a laptop leaves a Wi-Fi network and joins another
+///
+/// Changes come from the operating system's address notifications, only
+/// supported on Linux (rtnetlink) for now.
+pub struct NetworkWatch {
+    #[cfg(target_os = "linux")]
+    inner: linux::Watch,
+}
+
+impl NetworkWatch {
+    #[cfg(target_os = "linux")]
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            inner: linux::Watch::open()?,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "network changes are only watched on Linux"))
+    }
+
+    /// Waits until the network goes down or comes back
+    ///
+    /// The state found when the watch starts is not reported, only the
+    /// changes after it.
+    #[cfg(target_os = "linux")]
+    pub async fn changed(&mut self) -> io::Result<Network> {
+        self.inner.changed().await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn changed(&mut self) -> io::Result<Network> {
+        std::future::pending().await
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        collections::HashSet,
+        io, mem,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    use tokio::io::{Interest, unix::AsyncFd};
+
+    use super::Network;
+
+    /// Size of a `nlmsghdr`
+    const HEADER_LEN: usize = 16;
+
+    /// Size of an `ifaddrmsg`
+    const IFADDRMSG_LEN: usize = 8;
+
+    /// Netlink messages and attributes are padded to this many bytes
+    const ALIGN: usize = 4;
+
+    /// A single read can carry many messages, the whole dump of a busy host
+    const RECV_BUFFER: usize = 32 * 1024;
+
+    /// Routable address of an interface, by interface index
+    type Address = (u32, Vec<u8>);
+
+    /// Netlink socket subscribed to IPv4 and IPv6 address changes
+    pub struct Watch {
+        socket:    AsyncFd<OwnedFd>,
+        addresses: HashSet<Address>,
+        /// Whether the answer to the address dump was read in full
+        synced:    bool,
+        up:        bool,
+        buffer:    Vec<u8>,
+    }
+
+    impl Watch {
+        pub fn open() -> io::Result<Self> {
+            // SAFETY: plain socket(2) call; the descriptor is owned right away
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` was just opened and nothing else owns it
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            // SAFETY: sockaddr_nl is plain data, valid when zeroed
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = (libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+            // SAFETY: `addr` outlives the call and its size is passed along
+            let bound = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if bound < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // SAFETY: the `OwnedFd` keeps the same descriptor until dropped
+            let socket = unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE) }?;
+            let watch  = Self {
+                socket,
+                addresses: HashSet::new(),
+                synced:    false,
+                up:        false,
+                buffer:    vec![0; RECV_BUFFER],
+            };
+            watch.request_dump()?;
+            Ok(watch)
+        }
+
+        pub async fn changed(&mut self) -> io::Result<Network> {
+            loop {
+                let len = match self.recv().await {
+                    Ok(len) => len,
+                    // Notifications were dropped, the address list is stale
+                    Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                        self.addresses.clear();
+                        self.synced = false;
+                        self.request_dump()?;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                let buffer = mem::take(&mut self.buffer);
+                let done   = self.apply(&buffer[..len]);
+                self.buffer = buffer;
+
+                let up = !self.addresses.is_empty();
+                if !self.synced {
+                    self.synced = done;
+                    self.up     = up;
+                } else if up != self.up {
+                    self.up = up;
+                    return Ok(if up { Network::Up } else { Network::Down });
+                }
+            }
+        }
+
+        /// Asks the kernel for every address currently assigned
+        fn request_dump(&self) -> io::Result<()> {
+            let mut request = Vec::with_capacity(HEADER_LEN + IFADDRMSG_LEN);
+            request.extend_from_slice(&((HEADER_LEN + IFADDRMSG_LEN) as u32).to_ne_bytes());
+            request.extend_from_slice(&libc::RTM_GETADDR.to_ne_bytes());
+            request.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+            request.extend_from_slice(&1u32.to_ne_bytes());
+            request.extend_from_slice(&0u32.to_ne_bytes());
+            // ifaddrmsg with AF_UNSPEC: addresses of every family
+            request.extend_from_slice(&[0; IFADDRMSG_LEN]);
+
+            // SAFETY: `request` outlives the call and its length is passed along
+            let sent = unsafe {
+                libc::send(
+                    self.socket.as_raw_fd(),
+                    request.as_ptr() as *const libc::c_void,
+                    request.len(),
+                    0,
+                )
+            };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> io::Result<usize> {
+            loop {
+                let mut guard = self.socket.readable().await?;
+                let buffer    = &mut self.buffer;
+                let read      = guard.try_io(|fd| {
+                    // SAFETY: at most `buffer.len()` bytes are written to it
+                    let len = unsafe {
+                        libc::recv(fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0)
+                    };
+                    if len < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(len as usize)
+                    }
+                });
+                if let Ok(result) = read {
+                    return result;
+                }
+            }
+        }
+
+        /// Applies the address messages in `data`; returns `true` if it ends a dump
+        fn apply(&mut self, mut data: &[u8]) -> bool {
+            let mut done = false;
+            while data.len() >= HEADER_LEN {
+                let len  = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+                if len < HEADER_LEN || len > data.len() {
+                    break;
+                }
+
+                let body = &data[HEADER_LEN..len];
+                match kind {
+                    libc::RTM_NEWADDR => {
+                        if let Some(address) = routable_address(body) {
+                            self.addresses.insert(address);
+                        }
+                    }
+                    libc::RTM_DELADDR => {
+                        if let Some(address) = routable_address(body) {
+                            self.addresses.remove(&address);
+                        }
+                    }
+                    kind if kind == libc::NLMSG_DONE as u16 || kind == libc::NLMSG_ERROR as u16 => done = true,
+                    _ => {}
+                }
+                data = &data[len.next_multiple_of(ALIGN).min(data.len())..];
+            }
+            done
+        }
+    }
+
+    /// Returns the interface and address an `ifaddrmsg` is about, `None` for
+    /// addresses that don't reach other hosts: loopback and link-local ones
+    fn routable_address(body: &[u8]) -> Option<Address> {
+        if body.len() < IFADDRMSG_LEN || body[3] != libc::RT_SCOPE_UNIVERSE {
+            return None;
+        }
+        let index = u32::from_ne_bytes(body[4..8].try_into().unwrap());
+
+        let mut attrs = &body[IFADDRMSG_LEN..];
+        while attrs.len() >= 4 {
+            let len  = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+            let kind = u16::from_ne_bytes(attrs[2..4].try_into().unwrap());
+            if len < 4 || len > attrs.len() {
+                break;
+            }
+            if kind == libc::IFA_ADDRESS {
+                return Some((index, attrs[4..len].to_vec()));
+            }
+            attrs = &attrs[len.next_multiple_of(ALIGN).min(attrs.len())..];
+        }
+        None
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::{process::Command, sync::mpsc};
+
+use crate::{
+    events::{Event, Record},
+    session::TorrentId,
+};
+
+/// How long a webhook request may take before it is abandoned
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Event sink reporting completions and failures to the user
+///
+/// Only torrent completion, torrent failure and tracker failure are
+/// forwarded; everything else is ignored.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    webhook: Option<Url>,
+    desktop: bool,
+    client:  Client,
+}
+
+/// Body of a webhook request: the event plus the torrent it refers to
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    record:    &'a Record,
+    name:      &'a str,
+    info_hash: &'a str,
+}
+
+impl Notifier {
+    pub fn new(webhook: Option<Url>, desktop: bool) -> Self {
+        Self {
+            webhook,
+            desktop,
+            client: Client::new(),
+        }
+    }
+
+    /// Delivers notifications until every [`Events`](crate::events::Events) handle is dropped
+    pub async fn run(self, mut rx: mpsc::UnboundedReceiver<Record>) {
+        // Events only carry torrent ids, names come from `TorrentAdded`
+        let mut torrents = HashMap::<TorrentId, (String, String)>::new();
+
+        while let Some(record) = rx.recv().await {
+            let (torrent, summary) = match &record.event {
+                Event::TorrentAdded { torrent, name, info_hash } => {
+                    torrents.insert(*torrent, (name.clone(), info_hash.clone()));
+                    continue;
+                }
+                Event::TorrentFinished { torrent } => (*torrent, "download complete".to_string()),
+                Event::TorrentFailed { torrent, message } => (*torrent, format!("failed: {}", message)),
+                Event::TrackerFailed { torrent, url, .. } => (*torrent, format!("tracker {} unreachable", url)),
+                _ => continue,
+            };
+
+            let (name, info_hash) = torrents
+                .get(&torrent)
+                .map(|(n, h)| (n.as_str(), h.as_str()))
+                .unwrap_or_default();
+
+            if let Some(url) = &self.webhook {
+                let payload = Payload { record: &record, name, info_hash };
+                self.post(url, &payload).await;
+            }
+            if self.desktop {
+                desktop(&format!("{}: {}", name, summary)).await;
+            }
+        }
+    }
+
+    async fn post(&self, url: &Url, payload: &Payload<'_>) {
+        let result = self
+            .client
+            .post(url.clone())
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(payload)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+
+        if let Err(e) = result {
+            println!("Webhook {} failed: {}", url, e);
+        }
+    }
+}
+
+/// Shows `message` through the desktop's notification service
+async fn desktop(message: &str) {
+    let status = if cfg!(target_os = "macos") {
+        let script = format!("display notification {:?} with title \"torrentz\"", message);
+        Command::new("osascript").arg("-e").arg(script).status().await
+    } else {
+        Command::new("notify-send").arg("torrentz").arg(message).status().await
+    };
+
+    if let Err(e) = status {
+        println!("Desktop notification failed: {}", e);
+    }
+}
@@ -1,84 +1,731 @@
-use std::{collections::HashSet, net::IpAddr};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
+use serde::Serialize;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf},
-    net::TcpStream,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf},
+    net::{TcpStream, lookup_host},
+    sync::{mpsc, oneshot},
+    time,
 };
 
 use crate::{
+    bitfield::Bitfield,
+    config::PeerSockets,
+    dial,
     error::ApplicationError,
-    protocol::{HANDSHAKE_LEN, Handshake, Message},
+    geometry::{BlockOffset, Geometry, PieceIndex},
+    inspect::Inspectors,
+    listen::Endpoints,
+    manager::PieceManager,
+    metadata::{self, MAX_METADATA_SIZE, METADATA_PIECE_LEN, Metadata, MetadataDownload, UT_METADATA, UT_METADATA_ID},
+    protocol::{EXTENSION_HANDSHAKE_ID, ExtensionHandshake, HANDSHAKE_LEN, Handshake, Message, client_name},
+    ratelimit::Throttle,
+    recorder::{ConnectionRecorder, Direction, Recorder},
 };
 
+/// How long to wait for more availability messages once the first one arrived
+const FOLLOW_UP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long a peer may take to send its extension handshake when we need
+/// its metadata
+const METADATA_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer may keep us choked once we are interested
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a peer may take to send any of the blocks requested from it
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest extended message accepted: a metadata piece and its header,
+/// with room to spare for large extension handshakes
+const MAX_EXTENDED_FRAME: usize = 2 * METADATA_PIECE_LEN;
+
+/// Outstanding requests we accept from a peer unless configured otherwise
+pub const DEFAULT_MAX_REQUESTS: usize = 250;
+
+/// Outstanding requests assumed for peers that don't advertise `reqq`,
+/// low enough for any client
+const FALLBACK_PEER_REQUESTS: usize = 16;
+
+/// Outstanding requests sent to a peer at most, however large its `reqq`
+const MAX_PEER_REQUESTS: usize = 500;
+
+/// Where the address of a peer was learned from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Manual,
+    Incoming,
+}
+
 /// Represents a peer in the BitTorrent network
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Two peers are equal when they share the same address, regardless of source.
+#[derive(Debug, Clone)]
 pub struct Peer {
-    pub ip:   IpAddr,
-    pub port: u16,
+    pub ip:      IpAddr,
+    pub port:    u16,
+    pub source:  PeerSource,
+    /// Peer id announced by the tracker, checked against the handshake
+    pub peer_id: Option<[u8; 20]>,
+    /// Host name the peer was given as; it is resolved again when dialing
+    /// and every address is tried, `ip` being the first one it resolved to
+    pub host:    Option<String>,
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.ip == other.ip && self.port == other.port
+    }
+}
+
+impl Eq for Peer {}
+
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            IpAddr::V4(ip) => write!(f, "{}:{}", ip, self.port),
+            IpAddr::V6(ip) => write!(f, "[{}]:{}", ip, self.port),
+        }
+    }
+}
+
+/// Why a connection with a peer ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// Everything the connection was opened for is done
+    Done,
+    /// The peer didn't tell which pieces it has, unchoke us or send a
+    /// block in time
+    Timeout,
+    /// The peer has none of the pieces it was asked for
+    Useless,
+    /// The peer's address was banned while connected
+    Banned,
+    /// We are seeding and so is the peer; neither needs the other
+    SeedToSeed,
+    /// Dropped to give the slot to a more useful peer
+    ChokePolicy,
+    /// The torrent was stopped
+    Stopped,
+    /// The torrent was paused
+    Paused,
+    /// The peer sent something the protocol doesn't allow
+    ProtocolViolation,
+    /// The connection failed or the peer closed it
+    ConnectionError,
+}
+
+impl DisconnectReason {
+    /// Classifies a connection that ended with `error`
+    pub fn from_error(error: &ApplicationError) -> Self {
+        match error {
+            ApplicationError::ProtocolError(_) => Self::ProtocolViolation,
+            _                                  => Self::ConnectionError,
+        }
+    }
+
+    /// Returns `true` if we chose to close the connection, rather than it
+    /// failing; pending messages are worth sending then
+    pub fn is_graceful(self) -> bool {
+        !matches!(self, Self::ProtocolViolation | Self::ConnectionError)
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Done              => "done",
+            Self::Timeout           => "timeout",
+            Self::Useless           => "useless",
+            Self::Banned            => "banned",
+            Self::SeedToSeed        => "seed-to-seed",
+            Self::ChokePolicy       => "choke policy",
+            Self::Stopped           => "stopped",
+            Self::Paused            => "paused",
+            Self::ProtocolViolation => "protocol violation",
+            Self::ConnectionError   => "connection error",
+        })
+    }
+}
+
+/// Snapshot of a connected peer, as shown to users
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub peer:          Peer,
+    /// Client name derived from the peer id sent in the handshake
+    pub client:        String,
+    /// The peer is choking us
+    pub choked:        bool,
+    /// We told the peer we are interested in its pieces
+    pub interested:    bool,
+    pub encrypted:     bool,
+    /// The peer connected to us rather than the other way around
+    pub incoming:      bool,
+    /// Fraction (0..=1) of the torrent's pieces the peer has
+    pub progress:      f64,
+    /// Average download rate since connecting, in bytes per second
+    pub download_rate: f64,
+    /// Average upload rate since connecting, in bytes per second
+    pub upload_rate:   f64,
+}
+
+/// Protocol state of a connection, driven only by the messages exchanged
+///
+/// Kept apart from the socket so recorded conversations can be replayed
+/// through it (see [`recorder::replay`](crate::recorder::replay)).
+#[derive(Debug, Clone)]
+pub struct WireState {
+    /// The peer is choking us
+    pub choked:           bool,
+    /// We told the peer we are interested in its pieces
+    pub interested:       bool,
+    /// We are choking the peer, ignoring its requests
+    pub choking:          bool,
+    /// The peer told us it is interested in our pieces
+    pub peer_interested:  bool,
+    pub available_pieces: Bitfield,
+    /// Outstanding requests the peer accepts, from its extension handshake
+    pub peer_requests:    Option<usize>,
+    /// Extended message id the peer receives `ut_metadata` messages on
+    pub metadata_id:      Option<u8>,
+    /// Size of the info dictionary the peer serves
+    pub metadata_size:    Option<usize>,
+    /// Layout used to validate piece indices and offsets; `None` accepts anything
+    pub geometry:         Option<Geometry>,
+}
+
+impl Default for WireState {
+    fn default() -> Self {
+        Self {
+            choked:           true,
+            interested:       false,
+            choking:          true,
+            peer_interested:  false,
+            available_pieces: Bitfield::default(),
+            peer_requests:    None,
+            metadata_id:      None,
+            metadata_size:    None,
+            geometry:         None,
+        }
+    }
+}
+
+impl WireState {
+    /// Creates the state of a new connection to a torrent laid out as `geometry`
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            available_pieces: Bitfield::new(geometry.pieces_count()),
+            geometry:         Some(geometry),
+            ..Self::default()
+        }
+    }
+
+    /// Applies a message received from the peer
+    pub fn received(&mut self, msg: &Message) -> Result<(), ApplicationError> {
+        match msg {
+            Message::Choke => {
+                self.choked = true;
+            }
+            Message::Unchoke => {
+                self.choked = false;
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+            }
+            Message::Bitfield(bytes) => {
+                if let Some(geometry) = &self.geometry {
+                    geometry.check_bitfield(bytes)?;
+                }
+                self.available_pieces = Bitfield::from_bytes(bytes);
+                if let Some(geometry) = &self.geometry {
+                    self.available_pieces.truncate_to(geometry.pieces_count());
+                }
+            }
+            Message::Have(index) => {
+                let index = match &self.geometry {
+                    Some(geometry) => geometry.piece(*index)?.get(),
+                    None           => *index as usize,
+                };
+                self.available_pieces.set(index, true);
+            }
+            Message::Piece { index, begin, block } => {
+                if let Some(geometry) = &self.geometry {
+                    geometry.check_block(*index, *begin, block.len())?;
+                }
+            }
+            Message::Extended { id: EXTENSION_HANDSHAKE_ID, payload } => {
+                let handshake = ExtensionHandshake::decode(payload)?;
+                if let Some(reqq) = handshake.reqq {
+                    self.peer_requests = usize::try_from(reqq).ok().filter(|n| *n > 0);
+                }
+                if let Some(id) = handshake.m.get(UT_METADATA) {
+                    self.metadata_id = u8::try_from(*id).ok().filter(|id| *id > 0);
+                }
+                if let Some(size) = handshake.metadata_size {
+                    self.metadata_size = usize::try_from(size).ok();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the peer has at least one of the `needed` pieces
+    pub fn has_needed(&self, needed: &Bitfield) -> bool {
+        self.available_pieces.intersects(needed)
+    }
+
+    /// Applies a message we sent to the peer
+    pub fn sent(&mut self, msg: &Message) {
+        match msg {
+            Message::Choke         => self.choking = true,
+            Message::Unchoke       => self.choking = false,
+            Message::Interested    => self.interested = true,
+            Message::NotInterested => self.interested = false,
+            _                      => {}
+        }
+    }
+}
+
+/// Everything a connection needs to know besides the peer and its limits
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    pub info_hash:  [u8; 20],
+    /// Our own peer id
+    pub peer_id:    [u8; 20],
+    /// Layout of the torrent; `None` while its metadata is fetched for a magnet link
+    pub geometry:   Option<Geometry>,
+    /// Local address outgoing connections are made from
+    pub bind:         Option<IpAddr>,
+    /// Where peers can connect to us, sent in the extension handshake
+    pub endpoints:    Endpoints,
+    /// Options of the TCP socket to the peer
+    pub sockets:      PeerSockets,
+    /// Outstanding requests we accept from the peer, advertised as `reqq`
+    pub max_requests: usize,
+    /// Info dictionary served to peers over `ut_metadata`, if small enough
+    pub metadata:     Option<Metadata>,
+    pub recorder:     Option<Recorder>,
+    pub inspectors:   Inspectors,
+}
+
+/// Progress reported by [`PeerConnection::download_pieces`]
+#[derive(Debug)]
+pub enum Download {
+    /// A block of this many bytes arrived
+    Block(usize),
+    /// A piece was downloaded in full
+    Piece(CompletedPiece),
+    /// The peer announced new pieces; all the pieces it has now
+    Available(Bitfield),
+}
+
+/// A piece downloaded in full by [`PeerConnection::download_pieces`]
+#[derive(Debug)]
+pub struct CompletedPiece {
+    pub index: PieceIndex,
+    pub data:  Vec<u8>,
+    /// The data matches the piece's hash; invalid pieces are queued again
+    pub valid: bool,
 }
 
 /// Manages the connection to a peer, including reading and writing
 pub struct PeerConnection<'a> {
-    peer:             &'a Peer,
-    choked:           bool,
-    reader:           BufReader<ReadHalf<TcpStream>>,
-    writer:           BufWriter<WriteHalf<TcpStream>>,
-    available_pieces: HashSet<usize>,
+    peer:         &'a Peer,
+    peer_id:      [u8; 20],
+    state:        WireState,
+    reader:       BufReader<ReadHalf<TcpStream>>,
+    writer:       BufWriter<WriteHalf<TcpStream>>,
+    throttle:     Throttle,
+    recorder:     Option<ConnectionRecorder>,
+    inspectors:   Inspectors,
+    max_requests: usize,
+    metadata:     Option<Metadata>,
+    geometry:     Option<Geometry>,
+    /// The peer connected to us
+    incoming:     bool,
+    connected_at: Instant,
+    downloaded:   u64,
+    uploaded:     u64,
 }
 
 impl<'a> PeerConnection<'a> {
     pub async fn connect(
-        peer:      &'a Peer,
-        info_hash: [u8; 20],
-        peer_id:   [u8; 20],
+        peer:     &'a Peer,
+        settings: &ConnectionSettings,
+        throttle: Throttle,
     ) -> Result<Self, ApplicationError> {
-        let stream = TcpStream::connect(format!("{}:{}", peer.ip, peer.port))
-            .await
-            .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
-
-        let (rh, wh) = tokio::io::split(stream);
-        let reader   = BufReader::new(rh);
-        let writer   = BufWriter::new(wh);
-
-        let mut conn = PeerConnection {
-            choked: true,
-            peer,
-            reader,
-            writer,
-            available_pieces: HashSet::new(),
+        let addrs = match &peer.host {
+            Some(host) => lookup_host((host.as_str(), peer.port))
+                .await
+                .map_err(|e| ApplicationError::PeerError(format!("{}: {}", host, e)))?
+                .collect(),
+            None => vec![peer.addr()],
         };
-
-        conn.writer
-            .write_all(&Handshake::new(info_hash, peer_id).encode())
+        let stream = dial::connect(&addrs, settings.bind, &settings.sockets)
             .await
             .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
 
-        conn.writer
-            .flush()
-            .await
-            .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
+        let mut conn = Self::new(peer, stream, settings, throttle, false);
+        conn.send_handshake(settings).await?;
 
         let mut buf = [0u8; HANDSHAKE_LEN];
+        conn.throttle.download.consume(HANDSHAKE_LEN as u64).await;
         conn.reader
             .read_exact(&mut buf)
             .await
             .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
 
         let handshake = Handshake::decode(&buf)?;
-        if handshake.info_hash != info_hash {
+        conn.handshake_received(&handshake, settings).await?;
+        Ok(conn)
+    }
+
+    /// Takes over a connection the peer opened to us, answering `handshake`,
+    /// which was read to find the torrent it is for
+    pub async fn accept(
+        peer:      &'a Peer,
+        stream:    TcpStream,
+        handshake: &Handshake,
+        settings:  &ConnectionSettings,
+        throttle:  Throttle,
+    ) -> Result<Self, ApplicationError> {
+        let mut conn = Self::new(peer, stream, settings, throttle, true);
+        conn.send_handshake(settings).await?;
+        conn.handshake_received(handshake, settings).await?;
+        Ok(conn)
+    }
+
+    fn new(
+        peer:     &'a Peer,
+        stream:   TcpStream,
+        settings: &ConnectionSettings,
+        throttle: Throttle,
+        incoming: bool,
+    ) -> Self {
+        let (rh, wh) = tokio::io::split(stream);
+        PeerConnection {
+            peer,
+            peer_id:      [0u8; 20],
+            state:        settings.geometry.map_or_else(WireState::default, WireState::new),
+            reader:       BufReader::new(rh),
+            writer:       BufWriter::new(wh),
+            throttle,
+            recorder:     settings.recorder.as_ref().map(|r| r.connection(peer)),
+            inspectors:   settings.inspectors.clone(),
+            max_requests: settings.max_requests,
+            metadata:     settings.metadata.clone(),
+            geometry:     settings.geometry,
+            incoming,
+            connected_at: Instant::now(),
+            downloaded:   0,
+            uploaded:     0,
+        }
+    }
+
+    async fn send_handshake(&mut self, settings: &ConnectionSettings) -> Result<(), ApplicationError> {
+        let handshake = Handshake::new(settings.info_hash, settings.peer_id);
+        if let Some(recorder) = &self.recorder {
+            recorder.handshake(Direction::Sent, &handshake);
+        }
+        self.throttle.upload.consume(HANDSHAKE_LEN as u64).await;
+        self.writer
+            .write_all(&handshake.encode())
+            .await
+            .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
+
+        self.writer
+            .flush()
+            .await
+            .map_err(|e| ApplicationError::PeerError(e.to_string()))
+    }
+
+    /// Checks the peer's handshake, then sends our extension handshake if
+    /// the peer supports extensions
+    async fn handshake_received(
+        &mut self,
+        handshake: &Handshake,
+        settings:  &ConnectionSettings,
+    ) -> Result<(), ApplicationError> {
+        if let Some(recorder) = &self.recorder {
+            recorder.handshake(Direction::Received, handshake);
+        }
+        if handshake.info_hash != settings.info_hash {
             return Err(ApplicationError::ProtocolError("invalid info_hash".into()));
         }
+        if handshake.peer_id == settings.peer_id {
+            return Err(ApplicationError::ProtocolError("connected to ourselves".into()));
+        }
+        if self.peer.peer_id.is_some_and(|id| id != handshake.peer_id) {
+            return Err(ApplicationError::ProtocolError(
+                "peer id differs from the one announced by the tracker".into(),
+            ));
+        }
+        self.peer_id = handshake.peer_id;
 
-        Ok(conn)
+        if handshake.supports_extensions() {
+            self.send_extension_handshake(&settings.endpoints).await?;
+        }
+        Ok(())
+    }
+
+    /// Advertises how many outstanding requests we accept and `ut_metadata`,
+    /// with the size of the metadata we serve if any, and where to reach us
+    async fn send_extension_handshake(&mut self, endpoints: &Endpoints) -> Result<(), ApplicationError> {
+        let mut handshake = ExtensionHandshake {
+            reqq:          Some(self.max_requests as i64),
+            v:             Some(format!("torrentz {}", env!("CARGO_PKG_VERSION"))),
+            metadata_size: self.metadata.as_ref().map(|m| m.size() as i64),
+            p:             Some(endpoints.port.into()),
+            ipv4:          endpoints.ipv4.map(|ip| ip.octets().to_vec()),
+            ipv6:          endpoints.ipv6.map(|ip| ip.octets().to_vec()),
+            ..ExtensionHandshake::default()
+        };
+        handshake.m.insert(UT_METADATA.into(), UT_METADATA_ID.into());
+        self.send(&Message::Extended {
+            id:      EXTENSION_HANDSHAKE_ID,
+            payload: handshake.encode(),
+        })
+        .await
+    }
+
+    /// Answers a `ut_metadata` request, rejecting it while we have no metadata
+    async fn serve_metadata(&mut self, payload: &[u8]) -> Result<(), ApplicationError> {
+        let Some(id) = self.state.metadata_id else {
+            return Ok(());
+        };
+        if let Some(reply) = metadata::answer(self.metadata.as_ref(), payload)? {
+            self.send(&Message::Extended { id, payload: reply }).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns how many requests may be outstanding with the peer at once
+    ///
+    /// The peer's `reqq`, so its queue doesn't overflow, up to
+    /// [`MAX_PEER_REQUESTS`]. Our own `max_requests` only bounds what the peer
+    /// asks of us.
+    pub fn request_limit(&self) -> usize {
+        self.state
+            .peer_requests
+            .unwrap_or(FALLBACK_PEER_REQUESTS)
+            .min(MAX_PEER_REQUESTS)
     }
 
-    pub fn available_pieces(&self) -> &HashSet<usize> {
-        &self.available_pieces
+    pub fn peer(&self) -> &Peer {
+        self.peer
+    }
+
+    /// Returns the layout of the torrent, which downloading pieces needs
+    fn geometry(&self) -> Result<Geometry, ApplicationError> {
+        self.geometry
+            .ok_or_else(|| ApplicationError::WorkerError("torrent metadata not known yet".into()))
+    }
+
+    /// Downloads the info dictionary from the peer (BEP 9) and checks it
+    /// against `info_hash`
+    ///
+    /// Every piece is requested at once: at most 256 of them, as metadata
+    /// is capped at [`MAX_METADATA_SIZE`](metadata::MAX_METADATA_SIZE).
+    pub async fn fetch_metadata(&mut self, info_hash: &[u8; 20]) -> Result<Vec<u8>, ApplicationError> {
+        if !self.read_availability(METADATA_HANDSHAKE_TIMEOUT).await? {
+            return Err(ApplicationError::PeerError("peer sent no extension handshake".into()));
+        }
+        let (Some(id), Some(size)) = (self.state.metadata_id, self.state.metadata_size) else {
+            return Err(ApplicationError::PeerError("peer doesn't serve metadata".into()));
+        };
+
+        let mut download = MetadataDownload::new(size)?;
+        for piece in 0..download.pieces() {
+            self.send(&Message::Extended {
+                id,
+                payload: metadata::request(piece),
+            })
+            .await?;
+        }
+        while !download.is_complete() {
+            let msg = self
+                .read_message()
+                .await?
+                .ok_or_else(|| ApplicationError::PeerError("peer closed the connection".into()))?;
+            self.state.received(&msg)?;
+            if let Message::Extended { id: UT_METADATA_ID, payload } = &msg {
+                download.receive(payload)?;
+            }
+        }
+        download.finish(info_hash)
+    }
+
+    pub fn available_pieces(&self) -> &Bitfield {
+        &self.state.available_pieces
+    }
+
+    /// Returns a snapshot of the connection for a torrent of `pieces_count` pieces
+    pub fn info(&self, pieces_count: usize) -> PeerInfo {
+        let secs = self.connected_at.elapsed().as_secs_f64().max(1.0);
+        PeerInfo {
+            peer:          self.peer.clone(),
+            client:        client_name(&self.peer_id),
+            choked:        self.state.choked,
+            interested:    self.state.interested,
+            encrypted:     false,
+            incoming:      self.incoming,
+            progress:      if pieces_count == 0 {
+                0.0
+            } else {
+                self.state.available_pieces.count_ones() as f64 / pieces_count as f64
+            },
+            download_rate: self.downloaded as f64 / secs,
+            upload_rate:   self.uploaded as f64 / secs,
+        }
     }
 
     pub async fn send_interested(&mut self) -> Result<(), ApplicationError> {
+        self.send(&Message::Interested).await
+    }
+
+    /// Returns `true` if the peer told us it wants our pieces
+    pub fn peer_interested(&self) -> bool {
+        self.state.peer_interested
+    }
+
+    /// Lets the peer request blocks (`unchoke`), or stops serving it (`choke`)
+    pub async fn set_choking(&mut self, choking: bool) -> Result<(), ApplicationError> {
+        if choking == self.state.choking {
+            return Ok(());
+        }
+        self.send(if choking { &Message::Choke } else { &Message::Unchoke }).await
+    }
+
+    /// Tells the peer every piece we have
+    pub async fn send_bitfield(&mut self, have: &Bitfield) -> Result<(), ApplicationError> {
+        self.send(&Message::Bitfield(have.as_bytes().to_vec())).await
+    }
+
+    /// Tells the peer we got a new piece
+    pub async fn send_have(&mut self, piece: PieceIndex) -> Result<(), ApplicationError> {
+        self.send(&Message::Have(piece.to_wire())).await
+    }
+
+    /// Sends a block the peer requested
+    pub async fn send_block(&mut self, piece: PieceIndex, block: BlockOffset, data: Vec<u8>) -> Result<(), ApplicationError> {
+        self.send(&Message::Piece {
+            index: piece.to_wire(),
+            begin: block.to_wire(),
+            block: data,
+        })
+        .await
+    }
+
+    /// Waits until the peer sent a message, or closed the connection,
+    /// without reading it
+    ///
+    /// Unlike the methods reading messages this is cancel safe, so it can
+    /// wait in `select!` next to other events; read the message with
+    /// [`next_message`](Self::next_message) once it returns. Keep-alives are
+    /// dropped on the way, so idle peers sending them don't wake the caller.
+    pub async fn readable(&mut self) -> Result<(), ApplicationError> {
+        loop {
+            let buf = self
+                .reader
+                .fill_buf()
+                .await
+                .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
+            if !buf.starts_with(&[0; 4]) {
+                return Ok(());
+            }
+            self.reader.consume(4);
+        }
+    }
+
+    /// Reads the next message and applies it to the connection's state;
+    /// `None` once the peer closed the connection
+    pub async fn next_message(&mut self) -> Result<Option<Message>, ApplicationError> {
+        let msg = self.read_message().await?;
+        if let Some(msg) = &msg {
+            self.state.received(msg)?;
+        }
+        Ok(msg)
+    }
+
+    /// Reads the extension handshake, bitfield and `have` messages peers send
+    /// right after the handshake
+    ///
+    /// Waits up to `timeout` for the first one, then keeps reading as long as
+    /// more arrive back to back. Stops early at any other message. Returns
+    /// `false` if nothing arrived within `timeout`.
+    pub async fn read_availability(&mut self, timeout: Duration) -> Result<bool, ApplicationError> {
+        let mut wait  = timeout;
+        let mut heard = false;
+        while let Ok(msg) = time::timeout(wait, self.read_message()).await {
+            let Some(msg) = msg? else {
+                break;
+            };
+            heard = true;
+            self.state.received(&msg)?;
+            if !matches!(msg, Message::Bitfield(_) | Message::Have(_) | Message::Extended { .. }) {
+                break;
+            }
+            wait = FOLLOW_UP_TIMEOUT;
+        }
+        Ok(heard)
+    }
+
+    /// Tells the peer whether we are interested, according to the pieces it has
+    ///
+    /// Sends `Interested` once the peer has one of the `needed` pieces and
+    /// `NotInterested` once it no longer does; nothing if unchanged.
+    pub async fn update_interest(&mut self, needed: &Bitfield) -> Result<(), ApplicationError> {
+        let wants = self.state.has_needed(needed);
+        match (wants, self.state.interested) {
+            (true, false) => self.send(&Message::Interested).await,
+            (false, true) => self.send(&Message::NotInterested).await,
+            _             => Ok(()),
+        }
+    }
+
+    /// Sends `msg` and whatever the inspectors add to it, unless they drop it
+    async fn send(&mut self, msg: &Message) -> Result<(), ApplicationError> {
+        let verdict = self.inspectors.inspect(self.peer, Direction::Sent, msg);
+        if verdict.pass {
+            self.write_message(msg).await?;
+        }
+        for msg in &verdict.inject {
+            self.write_message(msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_message(&mut self, msg: &Message) -> Result<(), ApplicationError> {
+        let buf = msg.encode();
+        if let Some(recorder) = &self.recorder {
+            recorder.message(Direction::Sent, &buf, Some(msg));
+        }
+        self.state.sent(msg);
+        self.throttle.upload.consume(buf.len() as u64).await;
+        self.uploaded += buf.len() as u64;
+
         self.writer
-            .write_all(&Message::Interested.encode())
+            .write_all(&buf)
             .await
             .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
 
@@ -88,66 +735,234 @@ impl<'a> PeerConnection<'a> {
             .map_err(|e| ApplicationError::PeerError(e.to_string()))
     }
 
-    pub async fn read_messages(&mut self) -> Result<(), ApplicationError> {
-        while let Some(msg) = Self::read_message(&mut self.reader).await? {
+    /// Waits until the peer unchokes us; returns `false` if it doesn't within `timeout`
+    pub async fn wait_unchoke(&mut self, timeout: Duration) -> Result<bool, ApplicationError> {
+        let deadline = Instant::now() + timeout;
+        while self.state.choked {
+            let Ok(msg) = time::timeout_at(deadline.into(), self.read_message()).await else {
+                return Ok(false);
+            };
+            let msg = msg?.ok_or_else(|| ApplicationError::PeerError("peer closed the connection".into()))?;
+            self.state.received(&msg)?;
+        }
+        Ok(true)
+    }
 
-            /*
-             * 
-             * 
-             * Read incoming messages
-             * 
-             * 
-             */
+    /// Asks the peer for a block
+    pub async fn request(&mut self, piece: PieceIndex, block: BlockOffset, length: u32) -> Result<(), ApplicationError> {
+        self.send(&Message::Request {
+            index:  piece.to_wire(),
+            begin:  block.to_wire(),
+            length,
+        })
+        .await
+    }
 
+    /// Withdraws a request for a block we no longer need
+    pub async fn cancel(&mut self, piece: PieceIndex, block: BlockOffset, length: u32) -> Result<(), ApplicationError> {
+        self.send(&Message::Cancel {
+            index:  piece.to_wire(),
+            begin:  block.to_wire(),
+            length,
+        })
+        .await
+    }
 
+    /// Reads messages until the next block arrives; `None` if the peer chokes us first
+    pub async fn next_block(&mut self) -> Result<Option<(PieceIndex, BlockOffset, Vec<u8>)>, ApplicationError> {
+        let geometry = self.geometry()?;
+        loop {
+            let msg = self
+                .read_message()
+                .await?
+                .ok_or_else(|| ApplicationError::PeerError("peer closed the connection".into()))?;
+            self.state.received(&msg)?;
             match msg {
-                Message::Choke => {
-                    return Err(ApplicationError::ProtocolError("peer choked us".into()));
-                }
-                Message::Unchoke => {
-                    self.choked = false;
-                }
-                Message::Bitfield(bytes) => {
-                    for (i, byte) in bytes.iter().enumerate() {
-                        for bit in 0..8 {
-                            if byte & (0b1000_0000 >> bit) != 0 {
-                                self.available_pieces.insert(i * 8 + bit);
-                            }
-                        }
-                    }
+                Message::Piece { index, begin, block } => {
+                    let (piece, offset) = geometry.check_block(index, begin, block.len())?;
+                    return Ok(Some((piece, offset, block)));
                 }
-                Message::Have(index) => {
-                    self.available_pieces.insert(index as usize);
+                Message::Choke => return Ok(None),
+                _              => {}
+            }
+        }
+    }
+
+    /// Downloads the pieces of `manager` the peer has, reporting each block,
+    /// completed piece and newly announced piece to `progress`
+    ///
+    /// Keeps up to [`request_limit`](Self::request_limit) block requests in
+    /// flight. A choke drops them, as the peer discards pending requests; they
+    /// are sent again once the peer unchokes us. Pieces failing verification
+    /// are reset and downloaded again. Returns once every piece is complete,
+    /// or the peer stays choking or silent for too long; `progress` can end
+    /// the download early by returning an error. Pieces sent on `cancelled`,
+    /// which another peer delivered first, are given up and their pending
+    /// requests cancelled. A reason sent on `stop` cancels every pending
+    /// request and returns it.
+    pub async fn download_pieces<F>(
+        &mut self,
+        manager:      &mut PieceManager,
+        cancelled:    &mut mpsc::UnboundedReceiver<PieceIndex>,
+        stop:         &mut oneshot::Receiver<DisconnectReason>,
+        mut progress: F,
+    ) -> Result<DisconnectReason, ApplicationError>
+    where
+        F: FnMut(Download) -> Result<(), ApplicationError>,
+    {
+        let geometry = self.geometry()?;
+        loop {
+            while let Ok(piece) = cancelled.try_recv() {
+                self.give_up(manager, piece).await?;
+            }
+            if let Ok(reason) = stop.try_recv() {
+                return self.withdraw(manager, reason).await;
+            }
+
+            if self.state.choked {
+                manager.cancel_requests();
+                tokio::select! {
+                    unchoked = self.wait_unchoke(UNCHOKE_TIMEOUT) => if !unchoked? {
+                        return Ok(DisconnectReason::Timeout);
+                    },
+                    Ok(reason) = &mut *stop => return self.withdraw(manager, reason).await,
                 }
-                Message::Piece { index, begin, block } => {
-                    println!(
-                        "Received piece {} (offset {}), {} bytes",
-                        index,
-                        begin,
-                        block.len()
-                    );
+            }
+
+            let free = self.request_limit().saturating_sub(manager.requested_blocks());
+            for (piece, block) in manager.needed_blocks().into_iter().take(free) {
+                self.request(piece, block, geometry.block_len(piece, block)).await?;
+                manager.mark_block_requested(piece, block);
+            }
+            if manager.requested_blocks() == 0 {
+                return Ok(DisconnectReason::Done);
+            }
+
+            // Pieces delivered by others are given up without waiting for
+            // this peer's next block
+            tokio::select! {
+                readable = time::timeout(BLOCK_TIMEOUT, self.readable()) => match readable {
+                    Ok(readable) => readable?,
+                    Err(_)       => return Ok(DisconnectReason::Timeout),
+                },
+                Some(piece) = cancelled.recv() => {
+                    self.give_up(manager, piece).await?;
+                    continue;
                 }
-                _ => {}
+                Ok(reason) = &mut *stop => return self.withdraw(manager, reason).await,
             }
+
+            let known = self.state.available_pieces.count_ones();
+            let Ok(received) = time::timeout(BLOCK_TIMEOUT, self.next_block()).await else {
+                return Ok(DisconnectReason::Timeout);
+            };
+            let received = received?;
+            if self.state.available_pieces.count_ones() != known {
+                progress(Download::Available(self.state.available_pieces.clone()))?;
+            }
+            let Some((piece, block, data)) = received else {
+                continue;
+            };
+            progress(Download::Block(data.len()))?;
+            let Some(data) = manager.store_block(piece, block, &data) else {
+                continue;
+            };
+            let valid = manager.verify_piece(piece, &data);
+            if !valid {
+                manager.reset_piece(piece);
+            }
+            progress(Download::Piece(CompletedPiece { index: piece, data, valid }))?;
+        }
+    }
+
+    /// Stops downloading a piece, cancelling its pending requests
+    async fn give_up(&mut self, manager: &mut PieceManager, piece: PieceIndex) -> Result<(), ApplicationError> {
+        let geometry = self.geometry()?;
+        for block in manager.drop_piece(piece) {
+            self.cancel(piece, block, geometry.block_len(piece, block)).await?;
         }
         Ok(())
     }
 
-    async fn read_message(
-        reader: &mut BufReader<ReadHalf<TcpStream>>,
-    ) -> Result<Option<Message>, ApplicationError> {
-        let mut length = [0u8; 4];
-        if reader.read_exact(&mut length).await.is_err() {
-            return Ok(None);
+    /// Cancels every pending request and tells the peer we are no longer
+    /// interested, so it stops sending before we close for `reason`
+    async fn withdraw(&mut self, manager: &mut PieceManager, reason: DisconnectReason) -> Result<DisconnectReason, ApplicationError> {
+        println!("Disconnecting from {} ({})", self.peer, reason);
+        let pieces = manager.pieces.iter().map(|p| p.index).collect::<Vec<_>>();
+        for piece in pieces {
+            self.give_up(manager, piece).await?;
         }
+        if self.state.interested {
+            self.send(&Message::NotInterested).await?;
+        }
+        Ok(reason)
+    }
 
-        let size = u32::from_be_bytes(length);
-        if size == 0 {
-            return Ok(None);
+    /// Closes the connection, sending what is still buffered first if `flush`
+    pub async fn close(mut self, flush: bool) {
+        let _ = if flush {
+            self.writer.shutdown().await
+        } else {
+            self.writer.get_mut().shutdown().await
+        };
+    }
+
+    /// Reads the next message the inspectors let through, sending what they inject
+    async fn read_message(&mut self) -> Result<Option<Message>, ApplicationError> {
+        loop {
+            let Some(msg) = self.read_frame().await? else {
+                return Ok(None);
+            };
+            let verdict = self.inspectors.inspect(self.peer, Direction::Received, &msg);
+            for injected in &verdict.inject {
+                self.write_message(injected).await?;
+            }
+            if !verdict.pass {
+                continue;
+            }
+            if let Message::Extended { id: UT_METADATA_ID, payload } = &msg {
+                self.serve_metadata(payload).await?;
+            }
+            return Ok(Some(msg));
         }
+    }
+
+    /// Returns the length of the longest message a peer may send: a block
+    /// we asked for, its bitfield or an extended message
+    ///
+    /// Until the metadata is known, the bitfield may be that of the largest
+    /// torrent metadata can describe.
+    fn max_frame_len(&self) -> usize {
+        let (block, pieces) = match &self.geometry {
+            Some(geometry) => (geometry.max_block_len() as usize, geometry.pieces_count()),
+            None           => (0, MAX_METADATA_SIZE / 20),
+        };
+        (9 + block).max(1 + pieces.div_ceil(8)).max(MAX_EXTENDED_FRAME)
+    }
+
+    /// Reads the next message, skipping keep-alives; `None` once the peer
+    /// closed the connection
+    async fn read_frame(&mut self) -> Result<Option<Message>, ApplicationError> {
+        let mut length = [0u8; 4];
+        let size = loop {
+            if self.reader.read_exact(&mut length).await.is_err() {
+                return Ok(None);
+            }
+            match u32::from_be_bytes(length) {
+                0    => continue,
+                size => break size,
+            }
+        };
+        let max = self.max_frame_len();
+        if size as usize > max {
+            return Err(ApplicationError::PeerError(format!("{} byte message, at most {} expected", size, max)));
+        }
+
+        self.throttle.download.consume(4 + size as u64).await;
+        self.downloaded += 4 + size as u64;
 
         let mut msg_buf = vec![0u8; size as usize];
-        reader
+        self.reader
             .read_exact(&mut msg_buf)
             .await
             .map_err(|e| ApplicationError::PeerError(e.to_string()))?;
@@ -155,6 +970,10 @@ impl<'a> PeerConnection<'a> {
         let mut full_buf = length.to_vec();
         full_buf.extend_from_slice(&msg_buf);
 
-        Message::decode(&full_buf)
+        let msg = Message::decode(&full_buf)?;
+        if let Some(recorder) = &self.recorder {
+            recorder.message(Direction::Received, &full_buf, msg.as_ref());
+        }
+        Ok(msg)
     }
 }
@@ -1,3 +1,5 @@
+use crate::geometry::{BlockOffset, Geometry, PieceIndex};
+
 /// Represents the current state of a block within a piece
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BlockState {
@@ -13,7 +15,7 @@ pub enum BlockState {
 #[derive(Debug, Clone)]
 pub struct Block {
     /// Offset (in bytes) from the start of the piece
-    pub offset: usize,
+    pub offset: BlockOffset,
     /// Length of the block in bytes
     pub length: usize,
     /// Current state of the block (not requested, requested, or downloaded)
@@ -24,7 +26,23 @@ pub struct Block {
 #[derive(Debug, Clone)]
 pub struct Piece {
     /// Index of the piece (0-based)
-    pub index: usize,
+    pub index: PieceIndex,
     /// List of blocks that make up this piece
     pub blocks: Vec<Block>,
 }
+
+impl Piece {
+    /// Splits `index` into blocks as laid out by `geometry`, none requested yet
+    pub fn new(geometry: &Geometry, index: PieceIndex) -> Self {
+        let blocks = geometry
+            .blocks(index)
+            .map(|offset| Block {
+                offset,
+                length: geometry.block_len(index, offset) as usize,
+                state: BlockState::NotRequested,
+            })
+            .collect();
+
+        Self { index, blocks }
+    }
+}
@@ -1,6 +1,6 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{self, Read, Write};
-use tokio::io::AsyncRead;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Read};
 
 use crate::error::ApplicationError;
 
@@ -10,11 +10,21 @@ pub const PROTOCOL_STR: &str = "BitTorrent protocol";
 /// Length of the full handshake message (always 68 bytes)
 pub const HANDSHAKE_LEN: usize = 68;
 
+/// Reserved byte and bit announcing support for the extension protocol (BEP 10)
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8     = 0x10;
+
+/// Id of the extended message carrying the extension handshake
+pub const EXTENSION_HANDSHAKE_ID: u8 = 0;
+
 /// Represents a BitTorrent handshake message.
 ///
 /// A handshake is the first message sent in a connection and is always 68 bytes.
 /// It identifies the torrent being requested (`info_hash`) and the client (`peer_id`).
+#[derive(Debug, PartialEq, Eq)]
 pub struct Handshake {
+    /// Feature bits, see [`Handshake::supports_extensions`]
+    pub reserved: [u8; 8],
     /// SHA-1 hash of the info dictionary from the .torrent file
     pub info_hash: [u8; 20],
     /// 20-byte string used to identify the client
@@ -22,9 +32,17 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    /// Creates a new `Handshake` with the given `info_hash` and `peer_id`.
+    /// Creates a new `Handshake` with the given `info_hash` and `peer_id`,
+    /// announcing support for the extension protocol.
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
-        Self { info_hash, peer_id }
+        let mut reserved = [0u8; 8];
+        reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+        Self { reserved, info_hash, peer_id }
+    }
+
+    /// Returns `true` if the sender understands extended messages (BEP 10)
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
     }
 
     /// Encodes the handshake into a 68-byte array.
@@ -34,7 +52,7 @@ impl Handshake {
         let mut buf = [0u8; HANDSHAKE_LEN];
         buf[0] = PROTOCOL_STR.len() as u8;
         buf[1..1 + PROTOCOL_STR.len()].copy_from_slice(PROTOCOL_STR.as_bytes());
-        // reserved bytes [1+len..1+len+8] are zero by default
+        buf[20..28].copy_from_slice(&self.reserved);
         buf[28..48].copy_from_slice(&self.info_hash);
         buf[48..68].copy_from_slice(&self.peer_id);
         buf
@@ -63,20 +81,86 @@ impl Handshake {
             ));
         }
 
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&buf[20..28]);
+
         let mut info_hash = [0u8; 20];
         info_hash.copy_from_slice(&buf[28..48]);
 
         let mut peer_id = [0u8; 20];
         peer_id.copy_from_slice(&buf[48..68]);
 
-        Ok(Self { info_hash, peer_id })
+        Ok(Self { reserved, info_hash, peer_id })
+    }
+}
+
+/// Payload of the extension handshake, the first extended message (BEP 10)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// Extended message ids supported by the sender, by name
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Number of outstanding requests the sender accepts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Size of the info dictionary, for peers serving `ut_metadata` (BEP 9)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+    /// TCP port the sender accepts peers on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<i64>,
+    /// IPv4 address the sender accepts peers on, 4 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub ipv4: Option<Vec<u8>>,
+    /// IPv6 address the sender accepts peers on, 16 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub ipv6: Option<Vec<u8>>,
+}
+
+impl ExtensionHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).unwrap_or_default()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, ApplicationError> {
+        serde_bencode::from_bytes(payload)
+            .map_err(|e| ApplicationError::ProtocolError(format!("invalid extension handshake: {}", e)))
+    }
+}
+
+/// Returns a human readable client name from an Azureus-style peer id
+///
+/// Azureus-style ids look like `-qB4630-...`: two letters identifying the
+/// client followed by four version characters. Other ids are shown as `unknown`.
+pub fn client_name(peer_id: &[u8; 20]) -> String {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return "unknown".into();
     }
+
+    let code    = String::from_utf8_lossy(&peer_id[1..3]);
+    let version = String::from_utf8_lossy(&peer_id[3..7]);
+    let name    = match code.as_ref() {
+        "AZ" => "Vuze",
+        "BT" => "BitTorrent",
+        "DE" => "Deluge",
+        "LT" => "libtorrent",
+        "lt" => "libTorrent",
+        "qB" => "qBittorrent",
+        "RU" => "torrentz",
+        "TR" => "Transmission",
+        "UT" => "µTorrent",
+        other => return format!("{} {}", other, version),
+    };
+    format!("{} {}", name, version)
 }
 
 /// Represents a protocol message exchanged after the handshake.
 ///
 /// These messages follow the BitTorrent peer wire protocol.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     /// `choke` message: tells the peer it will not receive requests
     Choke,
@@ -100,6 +184,8 @@ pub enum Message {
     },
     /// `cancel` message: cancels a previously sent request
     Cancel { index: u32, begin: u32, length: u32 },
+    /// `extended` message (BEP 10): `id` 0 is the extension handshake
+    Extended { id: u8, payload: Vec<u8> },
 }
 
 impl Message {
@@ -168,6 +254,13 @@ impl Message {
                 buf.write_u32::<BigEndian>(*begin).unwrap();
                 buf.write_u32::<BigEndian>(*length).unwrap();
             }
+            Message::Extended { id, payload } => {
+                buf.write_u32::<BigEndian>((2 + payload.len()) as u32)
+                    .unwrap();
+                buf.write_u8(20).unwrap();
+                buf.write_u8(*id).unwrap();
+                buf.extend_from_slice(payload);
+            }
         }
         buf
     }
@@ -290,6 +383,20 @@ impl Message {
                     length,
                 }))
             }
+            20 => {
+                if payload_len < 1 {
+                    return Err(ApplicationError::ParserError(
+                        "invalid extended message length".into(),
+                    ));
+                }
+                let id = buf
+                    .read_u8()
+                    .map_err(|e| ApplicationError::ParserError(format!("protocol: {}", e)))?;
+                let mut payload = vec![0u8; payload_len - 1];
+                buf.read_exact(&mut payload)
+                    .map_err(|e| ApplicationError::ParserError(format!("protocol: {}", e)))?;
+                Ok(Some(Message::Extended { id, payload }))
+            }
             _ => Err(ApplicationError::ParserError(format!(
                 "unknown message id: {}",
                 id
@@ -297,3 +404,143 @@ impl Message {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*, sample::Index};
+
+    use super::*;
+
+    /// What decoding a frame must give: a message, `None` for a keep-alive,
+    /// or `Err(())` for a rejected frame
+    type Decoded = Result<Option<Message>, ()>;
+
+    /// Inputs that once mattered, with how they must decode
+    const MESSAGE_CORPUS: &[(&[u8], Decoded)] = &[
+        (&[], Err(())),
+        (&[0, 0, 0], Err(())),
+        (&[0, 0, 0, 0], Ok(None)),
+        (&[0, 0, 0, 1, 0], Ok(Some(Message::Choke))),
+        // Bytes past the announced length belong to the next frame
+        (&[0, 0, 0, 1, 1, 0xff, 0xff], Ok(Some(Message::Unchoke))),
+        // Payloads of messages that carry none are ignored
+        (&[0, 0, 0, 2, 2, 7], Ok(Some(Message::Interested))),
+        (&[0, 0, 0, 1], Err(())),
+        (&[0xff, 0xff, 0xff, 0xff, 0], Err(())),
+        (&[0, 0, 0, 5, 4, 0, 0, 0, 9], Ok(Some(Message::Have(9)))),
+        (&[0, 0, 0, 4, 4, 0, 0, 0], Err(())),
+        (&[0, 0, 0, 6, 4, 0, 0, 0, 9, 0], Err(())),
+        (&[0, 0, 0, 1, 5], Ok(Some(Message::Bitfield(Vec::new())))),
+        (&[0, 0, 0, 12, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0], Err(())),
+        (&[0, 0, 0, 8, 7, 0, 0, 0, 1, 0, 0, 0], Err(())),
+        (
+            &[0, 0, 0, 9, 7, 0, 0, 0, 1, 0, 0, 0, 2],
+            Ok(Some(Message::Piece { index: 1, begin: 2, block: Vec::new() })),
+        ),
+        (&[0, 0, 0, 14, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0], Err(())),
+        (&[0, 0, 0, 1, 20], Err(())),
+        (&[0, 0, 0, 2, 20, 0], Ok(Some(Message::Extended { id: 0, payload: Vec::new() }))),
+        (&[0, 0, 0, 1, 21], Err(())),
+    ];
+
+    fn message() -> impl Strategy<Value = Message> {
+        let block = || vec(any::<u8>(), 0..64);
+        prop_oneof![
+            Just(Message::Choke),
+            Just(Message::Unchoke),
+            Just(Message::Interested),
+            Just(Message::NotInterested),
+            any::<u32>().prop_map(Message::Have),
+            block().prop_map(Message::Bitfield),
+            any::<(u32, u32, u32)>().prop_map(|(index, begin, length)| Message::Request { index, begin, length }),
+            (any::<u32>(), any::<u32>(), block()).prop_map(|(index, begin, block)| Message::Piece { index, begin, block }),
+            any::<(u32, u32, u32)>().prop_map(|(index, begin, length)| Message::Cancel { index, begin, length }),
+            (any::<u8>(), block()).prop_map(|(id, payload)| Message::Extended { id, payload }),
+        ]
+    }
+
+    fn handshake() -> impl Strategy<Value = Handshake> {
+        any::<([u8; 8], [u8; 20], [u8; 20])>()
+            .prop_map(|(reserved, info_hash, peer_id)| Handshake { reserved, info_hash, peer_id })
+    }
+
+    #[test]
+    fn message_corpus() {
+        for (input, expected) in MESSAGE_CORPUS {
+            let decoded = Message::decode(input).map_err(|_| ());
+            assert_eq!(&decoded, expected, "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn handshake_corpus() {
+        let valid = Handshake::new([1; 20], [2; 20]).encode();
+        assert!(Handshake::decode(&valid).is_ok_and(|h| h.supports_extensions()));
+        assert!(Handshake::decode(&valid[..HANDSHAKE_LEN - 1]).is_err());
+        assert!(Handshake::decode(&[&valid[..], &[0]].concat()).is_err());
+
+        let mut long_pstr = valid;
+        long_pstr[0] += 1;
+        assert!(Handshake::decode(&long_pstr).is_err());
+
+        let mut other_pstr = valid;
+        other_pstr[1] = b'b';
+        assert!(Handshake::decode(&other_pstr).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn message_round_trips(message in message(), trailing in vec(any::<u8>(), 0..8)) {
+            let mut encoded = message.encode();
+            prop_assert_eq!(Message::decode(&encoded).ok(), Some(Some(message.clone())));
+            encoded.extend_from_slice(&trailing);
+            prop_assert_eq!(Message::decode(&encoded).ok(), Some(Some(message)));
+        }
+
+        #[test]
+        fn truncated_message_is_rejected(message in message(), cut in any::<Index>()) {
+            let encoded = message.encode();
+            prop_assert!(Message::decode(&encoded[..cut.index(encoded.len())]).is_err());
+        }
+
+        #[test]
+        fn garbage_message_does_not_panic(input in vec(any::<u8>(), 0..80)) {
+            let _ = Message::decode(&input);
+        }
+
+        #[test]
+        fn any_frame_decodes_stably(id in any::<u8>(), payload in vec(any::<u8>(), 0..32)) {
+            let mut input = ((payload.len() + 1) as u32).to_be_bytes().to_vec();
+            input.push(id);
+            input.extend_from_slice(&payload);
+            if let Ok(Some(message)) = Message::decode(&input) {
+                prop_assert_eq!(Message::decode(&message.encode()).ok(), Some(Some(message)));
+            }
+            prop_assert!(Message::decode(&input[..input.len() - 1]).is_err());
+        }
+
+        #[test]
+        fn handshake_round_trips(handshake in handshake()) {
+            prop_assert_eq!(Handshake::decode(&handshake.encode()).ok(), Some(handshake));
+        }
+
+        #[test]
+        fn truncated_handshake_is_rejected(handshake in handshake(), cut in any::<Index>()) {
+            let encoded = handshake.encode();
+            prop_assert!(Handshake::decode(&encoded[..cut.index(encoded.len())]).is_err());
+        }
+
+        #[test]
+        fn corrupt_handshake_needs_the_protocol_string(handshake in handshake(), at in any::<Index>(), byte in any::<u8>()) {
+            let mut input = handshake.encode();
+            let at        = at.index(HANDSHAKE_LEN);
+            // Byte 0 is the length of the protocol string that follows it
+            let intact    = at > PROTOCOL_STR.len() || input[at] == byte;
+            input[at]     = byte;
+            match Handshake::decode(&input) {
+                Ok(decoded) => prop_assert!(intact && decoded.encode() == input),
+                Err(_)      => prop_assert!(!intact),
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+
+/// Token bucket refilled continuously at `rate` bytes per second
+///
+/// Limiters form a hierarchy (global → torrent → peer): consuming from a
+/// limiter also consumes from all of its ancestors, so a transfer proceeds
+/// only as fast as the most restrictive level allows. A limiter without a
+/// rate never waits on its own, but still forwards to its parent.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    state:  Mutex<BucketState>,
+    parent: Option<RateLimiter>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Bytes per second, `None` meaning unlimited
+    rate:   Option<u64>,
+    /// Available tokens; negative while consumers are paying off a debt
+    tokens: f64,
+    last:   Instant,
+}
+
+impl RateLimiter {
+    /// Creates a root limiter
+    pub fn new(rate: Option<u64>) -> Self {
+        Self::with_parent(rate, None)
+    }
+
+    /// Creates a limiter nested under `self`
+    pub fn child(&self, rate: Option<u64>) -> Self {
+        Self::with_parent(rate, Some(self.clone()))
+    }
+
+    fn with_parent(rate: Option<u64>, parent: Option<RateLimiter>) -> Self {
+        let state = BucketState {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last:   Instant::now(),
+        };
+        Self {
+            inner: Arc::new(Bucket {
+                state: Mutex::new(state),
+                parent,
+            }),
+        }
+    }
+
+    /// Returns the configured rate in bytes per second
+    pub fn rate(&self) -> Option<u64> {
+        self.inner.state.lock().unwrap().rate
+    }
+
+    /// Changes the rate; takes effect for the next call to [`RateLimiter::consume`]
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.refill();
+        state.rate   = rate;
+        state.tokens = state.tokens.min(rate.unwrap_or(0) as f64);
+    }
+
+    /// Waits until `n` bytes may be transferred at this level and every level above it
+    pub async fn consume(&self, n: u64) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            let wait = current.inner.state.lock().unwrap().take(n);
+            if let Some(wait) = wait {
+                time::sleep(wait).await;
+            }
+            limiter = current.inner.parent.as_ref();
+        }
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self) {
+        let now     = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last   = now;
+
+        if let Some(rate) = self.rate {
+            // At most one second worth of burst
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+    }
+
+    /// Takes `n` tokens, returning how long the caller has to wait to pay off any debt
+    fn take(&mut self, n: u64) -> Option<Duration> {
+        self.refill();
+
+        let rate = self.rate.filter(|r| *r > 0)?;
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(-self.tokens / rate as f64))
+    }
+}
+
+/// Download and upload limiters of one level of the hierarchy
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    pub download: RateLimiter,
+    pub upload:   RateLimiter,
+}
+
+impl Throttle {
+    /// Creates the root of a hierarchy
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::new(download),
+            upload:   RateLimiter::new(upload),
+        }
+    }
+
+    /// Creates a level nested under `self`
+    pub fn child(&self, download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: self.download.child(download),
+            upload:   self.upload.child(upload),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+use crate::{
+    error::ApplicationError,
+    peer::{Peer, WireState},
+    protocol::{Handshake, Message, client_name},
+};
+
+/// Which side of the connection a recorded message came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// What a recorded frame contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    Handshake,
+    Message,
+}
+
+/// A single peer-wire frame, as stored in a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    /// Milliseconds since the UNIX epoch
+    pub timestamp:  u128,
+    /// Distinguishes several connections to the same peer
+    pub connection: u64,
+    pub peer:       String,
+    pub direction:  Direction,
+    pub kind:       FrameKind,
+    /// Hex of the bytes on the wire, length prefix included
+    pub raw:        String,
+    /// Decoded form, for reading the file by eye; ignored on replay
+    pub decoded:    String,
+}
+
+/// Cloneable handle appending every peer-wire frame to a JSON-lines file
+///
+/// Like [`Events`](crate::events::Events), recording never blocks: frames
+/// are written by a background task.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    tx:          mpsc::UnboundedSender<Frame>,
+    connections: Arc<AtomicU64>,
+}
+
+/// A [`Recorder`] bound to a single peer connection
+#[derive(Debug, Clone)]
+pub struct ConnectionRecorder {
+    recorder:   Recorder,
+    connection: u64,
+    peer:       String,
+}
+
+impl Recorder {
+    /// Opens `path` in append mode and spawns the writer task
+    pub fn open(path: &Path) -> Result<(Self, JoinHandle<()>), ApplicationError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| ApplicationError::WorkerError(format!("wire recording: {}", e)))?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let task     = task::spawn(write_frames(File::from_std(file), rx));
+        let recorder = Self {
+            tx,
+            connections: Arc::new(AtomicU64::new(0)),
+        };
+        Ok((recorder, task))
+    }
+
+    /// Returns a recorder for a new connection to `peer`
+    pub fn connection(&self, peer: &Peer) -> ConnectionRecorder {
+        ConnectionRecorder {
+            recorder:   self.clone(),
+            connection: self.connections.fetch_add(1, Ordering::Relaxed),
+            peer:       peer.to_string(),
+        }
+    }
+}
+
+impl ConnectionRecorder {
+    pub fn handshake(&self, direction: Direction, handshake: &Handshake) {
+        let decoded = format!(
+            "Handshake {{ info_hash: {}, client: {} }}",
+            hex::encode(handshake.info_hash),
+            client_name(&handshake.peer_id)
+        );
+        self.record(direction, FrameKind::Handshake, &handshake.encode(), decoded);
+    }
+
+    pub fn message(&self, direction: Direction, raw: &[u8], message: Option<&Message>) {
+        let decoded = match message {
+            Some(Message::Piece { index, begin, block }) => {
+                format!("Piece {{ index: {}, begin: {}, length: {} }}", index, begin, block.len())
+            }
+            Some(Message::Bitfield(bits)) => format!("Bitfield({})", hex::encode(bits)),
+            Some(Message::Extended { id, payload }) => {
+                format!("Extended {{ id: {}, payload: {} }}", id, String::from_utf8_lossy(payload))
+            }
+            Some(message) => format!("{:?}", message),
+            None          => "KeepAlive".into(),
+        };
+        self.record(direction, FrameKind::Message, raw, decoded);
+    }
+
+    fn record(&self, direction: Direction, kind: FrameKind, raw: &[u8], decoded: String) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let _ = self.recorder.tx.send(Frame {
+            timestamp,
+            connection: self.connection,
+            peer: self.peer.clone(),
+            direction,
+            kind,
+            raw: hex::encode(raw),
+            decoded,
+        });
+    }
+}
+
+async fn write_frames(mut file: File, mut rx: mpsc::UnboundedReceiver<Frame>) {
+    while let Some(frame) = rx.recv().await {
+        let mut line = match serde_json::to_vec(&frame) {
+            Ok(line) => line,
+            Err(_)   => continue,
+        };
+        line.push(b'\n');
+
+        if let Err(e) = file.write_all(&line).await {
+            println!("Failed to write wire recording: {}", e);
+            return;
+        }
+    }
+    let _ = file.flush().await;
+}
+
+/// Loads a recording written by [`Recorder`]
+pub fn load(path: &Path) -> Result<Vec<Frame>, ApplicationError> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| ApplicationError::ParserError(format!("wire recording: {}", e)))?;
+
+    data.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            serde_json::from_str(l)
+                .map_err(|e| ApplicationError::ParserError(format!("wire recording: {}", e)))
+        })
+        .collect()
+}
+
+/// Feeds recorded frames back through the decoder and the connection state machine
+///
+/// Frames are re-decoded from their raw bytes, so a recording reproduces the
+/// exact parsing path. Each connection gets its own [`WireState`]. `step` is called
+/// after every frame with the frame, the outcome of processing it and the
+/// state of that peer's connection.
+pub fn replay(
+    frames:   &[Frame],
+    mut step: impl FnMut(&Frame, Result<(), ApplicationError>, &WireState),
+) {
+    let mut states = HashMap::<u64, WireState>::new();
+
+    for frame in frames {
+        let state  = states.entry(frame.connection).or_default();
+        let result = replay_frame(frame, state);
+        step(frame, result, state);
+    }
+}
+
+fn replay_frame(frame: &Frame, state: &mut WireState) -> Result<(), ApplicationError> {
+    let raw = hex::decode(&frame.raw)
+        .map_err(|e| ApplicationError::ParserError(format!("wire recording: {}", e)))?;
+
+    match frame.kind {
+        FrameKind::Handshake => Handshake::decode(&raw).map(|_| ()),
+        FrameKind::Message   => {
+            let Some(message) = Message::decode(&raw)? else {
+                return Ok(());
+            };
+            match frame.direction {
+                Direction::Sent     => {
+                    state.sent(&message);
+                    Ok(())
+                }
+                Direction::Received => state.received(&message),
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::time;
+
+use torrentz::{config::Limits, session::Session};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Applies the limits of the `--config` file to `session` whenever the file
+/// changes or the process gets SIGHUP, until the session is gone
+///
+/// Limits missing from the file fall back to `base`, those of the flags. A
+/// file that can't be read or parsed leaves the current limits in place.
+pub async fn watch(path: PathBuf, base: Limits, session: Session) {
+    let mut hangup   = Hangup::new();
+    let mut poll     = time::interval(POLL_INTERVAL);
+    let mut changed  = modified(&path);
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                let current = modified(&path);
+                if current == changed {
+                    continue;
+                }
+                changed = current;
+            }
+            _ = hangup.recv() => {}
+        }
+
+        let limits = match Limits::load(&path) {
+            Ok(limits) => limits.or(base),
+            Err(e)     => {
+                println!("Keeping the current limits: {:?}", e);
+                continue;
+            }
+        };
+        if session.reconfigure(limits).await.is_err() {
+            return;
+        }
+        println!("Reloaded {}", path.display());
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Stream of SIGHUP signals; never yields where there are none
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    #[cfg(unix)]
+    fn new() -> Self {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        Self {
+            signal: signal(SignalKind::hangup()).ok(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        match &mut self.signal {
+            Some(signal) => {
+                signal.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
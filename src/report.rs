@@ -0,0 +1,80 @@
+use std::{fs, path::Path, time::Duration};
+
+use serde::Serialize;
+
+use torrentz::{error::ApplicationError, stats::TorrentTotals};
+
+/// Exit code when every torrent completed
+pub const EXIT_SUCCESS: u8      = 0;
+/// Exit code for any failure not covered by a more specific code
+pub const EXIT_FAILURE: u8      = 1;
+/// Exit code when a torrent could not obtain peers from its tracker
+pub const EXIT_TRACKER: u8      = 2;
+/// Exit code when downloaded data failed verification
+pub const EXIT_VERIFICATION: u8 = 3;
+
+/// Outcome of a single torrent
+#[derive(Debug, Serialize)]
+pub struct TorrentReport {
+    pub name:      String,
+    pub info_hash: String,
+    /// `completed`, `stopped` or `failed`
+    pub status:    &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error:     Option<String>,
+    #[serde(flatten)]
+    pub totals:    TorrentTotals,
+}
+
+/// Machine-readable summary printed or written when the process ends
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub downloaded:    u64,
+    pub uploaded:      u64,
+    pub duration_secs: f64,
+    /// Average download rate over the whole run, in bytes per second
+    pub average_rate:  f64,
+    pub pieces_failed: usize,
+    pub peers_used:    usize,
+    pub exit_code:     u8,
+    pub torrents:      Vec<TorrentReport>,
+}
+
+impl Report {
+    pub fn new(torrents: Vec<TorrentReport>, duration: Duration, exit_code: u8) -> Self {
+        let downloaded = torrents.iter().map(|t| t.totals.downloaded).sum::<u64>();
+        let secs       = duration.as_secs_f64();
+        Self {
+            downloaded,
+            uploaded:      torrents.iter().map(|t| t.totals.uploaded).sum(),
+            duration_secs: secs,
+            average_rate:  if secs > 0.0 { downloaded as f64 / secs } else { 0.0 },
+            pieces_failed: torrents.iter().map(|t| t.totals.pieces_failed).sum(),
+            peers_used:    torrents.iter().map(|t| t.totals.peers_used).sum(),
+            exit_code,
+            torrents,
+        }
+    }
+
+    /// Writes the report as JSON to `path`, or to stdout if `path` is `-`
+    pub fn write(&self, path: &Path) -> Result<(), ApplicationError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| ApplicationError::WorkerError(format!("report: {}", e)))?;
+
+        if path == Path::new("-") {
+            println!("{}", json);
+            return Ok(());
+        }
+        fs::write(path, json).map_err(|e| ApplicationError::WorkerError(format!("report: {}", e)))
+    }
+}
+
+/// Maps the error that ended a run to the process exit code
+pub fn exit_code(error: Option<&ApplicationError>) -> u8 {
+    match error {
+        None                                        => EXIT_SUCCESS,
+        Some(ApplicationError::TrackerError(_))      => EXIT_TRACKER,
+        Some(ApplicationError::VerificationError(_)) => EXIT_VERIFICATION,
+        Some(_)                                     => EXIT_FAILURE,
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bitfield::Bitfield,
+    error::ApplicationError,
+    seal::{self, Passphrase},
+    storage::Storage,
+    verify::HashWorkers,
+};
+
+/// Suffix added to a torrent's output path to name its resume file
+const SUFFIX: &str = ".torrentz.resume";
+
+/// Verified pieces of a torrent, as stored on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeData {
+    /// Hex-encoded info hash, so a file left by another torrent is ignored
+    info_hash: String,
+    pieces:    usize,
+    /// Hex-encoded bitfield of the verified pieces, in wire order
+    verified:  String,
+}
+
+/// File next to a torrent's output recording which pieces were verified,
+/// so a restarted download only fetches the missing ones
+#[derive(Debug, Clone)]
+pub struct ResumeFile {
+    path:       PathBuf,
+    /// Encrypts the file when set
+    passphrase: Option<Passphrase>,
+}
+
+impl ResumeFile {
+    /// Names the resume file of the torrent written to `root`
+    pub fn new(root: &Path, passphrase: Option<Passphrase>) -> Self {
+        let mut path = OsString::from(root);
+        path.push(SUFFIX);
+        Self {
+            path: path.into(),
+            passphrase,
+        }
+    }
+
+    /// Moves the file next to `root`, where the torrent's files were moved to
+    pub fn relocate(&mut self, root: &Path) -> Result<(), ApplicationError> {
+        let moved = Self::new(root, self.passphrase.clone());
+        match fs::rename(&self.path, &moved.path) {
+            Ok(())                                                => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound    => {}
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                fs::copy(&self.path, &moved.path)
+                    .and_then(|_| fs::remove_file(&self.path))
+                    .map_err(|e| ApplicationError::WorkerError(format!("{}: {}", self.path.display(), e)))?;
+            }
+            Err(e) => return Err(ApplicationError::WorkerError(format!("{}: {}", self.path.display(), e))),
+        }
+        *self = moved;
+        Ok(())
+    }
+
+    /// Returns the pieces listed as verified
+    ///
+    /// `None` if the file is missing, unreadable, or was written for another
+    /// torrent: the caller then has to find out from the data on disk.
+    pub fn load(&self, info_hash: &[u8; 20], pieces: usize) -> Option<Bitfield> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                println!("Ignoring resume file {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        match self.parse(&data, info_hash, pieces) {
+            Ok(verified) => Some(verified),
+            Err(e)       => {
+                println!("Ignoring resume file {}: {:?}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn parse(&self, data: &[u8], info_hash: &[u8; 20], pieces: usize) -> Result<Bitfield, ApplicationError> {
+        let data = match &self.passphrase {
+            Some(passphrase) => seal::open(passphrase, data)?,
+            None             => data.to_vec(),
+        };
+        let resume: ResumeData = serde_json::from_slice(&data)
+            .map_err(|e| ApplicationError::ParserError(format!("resume file: {}", e)))?;
+
+        if resume.info_hash != hex::encode(info_hash) || resume.pieces != pieces {
+            return Err(ApplicationError::ParserError("resume file of another torrent".into()));
+        }
+        let bytes = hex::decode(&resume.verified)
+            .map_err(|e| ApplicationError::ParserError(format!("resume file: {}", e)))?;
+        let mut verified = Bitfield::from_bytes(&bytes);
+        verified.truncate_to(pieces);
+        Ok(verified)
+    }
+
+    /// Replaces the file with `verified`
+    ///
+    /// Written to a temporary file first, so an interrupted save leaves the
+    /// previous state in place.
+    pub fn save(&self, info_hash: &[u8; 20], verified: &Bitfield) -> Result<(), ApplicationError> {
+        let resume   = ResumeData {
+            info_hash: hex::encode(info_hash),
+            pieces:    verified.len(),
+            verified:  hex::encode(verified.as_bytes()),
+        };
+        let mut data = serde_json::to_vec(&resume)
+            .map_err(|e| ApplicationError::WorkerError(format!("resume file: {}", e)))?;
+        if let Some(passphrase) = &self.passphrase {
+            data = seal::seal(passphrase, &data)?;
+        }
+
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".part");
+        fs::write(&partial, data)
+            .and_then(|_| fs::rename(&partial, &self.path))
+            .map_err(|e| ApplicationError::WorkerError(format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+/// Finds the pieces of a torrent already on disk
+///
+/// Pieces listed in the resume file are trusted, or hashed again with
+/// `recheck`. Without a resume file, files that existed before the torrent
+/// was added are hashed in full; freshly created ones hold nothing. Pieces
+/// in `reused`, verified by another torrent, are never hashed. This blocks:
+/// call it from `spawn_blocking` inside async code.
+pub fn restore(
+    resume:    &ResumeFile,
+    storage:   &Storage,
+    info_hash: &[u8; 20],
+    hashes:    &[[u8; 20]],
+    reused:    &Bitfield,
+    recheck:   bool,
+    workers:   &HashWorkers,
+) -> Result<Bitfield, ApplicationError> {
+    let pieces   = hashes.len();
+    let listed   = resume.load(info_hash, pieces);
+    let verified = match listed {
+        Some(listed) if !recheck   => &listed | reused,
+        Some(listed)               => &storage.check(&(&listed & &!reused), hashes, workers)? | reused,
+        None if storage.is_fresh() => return Ok(Bitfield::new(pieces)),
+        None                       => &storage.check(&!reused, hashes, workers)? | reused,
+    };
+    if reused.count_ones() > 0 {
+        println!("Reusing {} pieces verified by another torrent", reused.count_ones());
+    }
+
+    println!(
+        "Resuming {}: {} of {} pieces already verified",
+        storage.root().display(),
+        verified.count_ones(),
+        pieces
+    );
+    Ok(verified)
+}
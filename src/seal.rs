@@ -0,0 +1,83 @@
+use std::{fmt, sync::Arc};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit},
+};
+use rand::RngCore;
+
+use crate::error::ApplicationError;
+
+/// Marks a file written by [`seal`]
+const MAGIC: &[u8; 8]  = b"TZSEAL1\n";
+const SALT_LEN: usize  = 16;
+const NONCE_LEN: usize = 12;
+
+/// Secret protecting state files at rest
+///
+/// Never printed, so it can't leak through logs or `{:?}`.
+#[derive(Clone)]
+pub struct Passphrase(Arc<str>);
+
+impl Passphrase {
+    pub fn new(passphrase: &str) -> Self {
+        Self(passphrase.into())
+    }
+
+    /// Derives the file key for `salt` with Argon2id
+    fn key(&self, salt: &[u8]) -> Result<Key, ApplicationError> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(self.0.as_bytes(), salt, &mut key)
+            .map_err(|e| ApplicationError::WorkerError(format!("state encryption: {}", e)))?;
+        Ok(key)
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// Encrypts `plain` with ChaCha20-Poly1305 under a key derived from `passphrase`
+///
+/// Every call picks a fresh salt and nonce, stored in the header.
+pub fn seal(passphrase: &Passphrase, plain: &[u8]) -> Result<Vec<u8>, ApplicationError> {
+    let mut salt  = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(&passphrase.key(&salt)?);
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), plain)
+        .map_err(|_| ApplicationError::WorkerError("state encryption failed".into()))?;
+
+    let mut out = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + sealed.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// Decrypts data written by [`seal`]
+///
+/// Data without the header is returned unchanged, so files written before
+/// encryption was enabled still load and get sealed on their next save.
+pub fn open(passphrase: &Passphrase, data: &[u8]) -> Result<Vec<u8>, ApplicationError> {
+    let Some(rest) = data.strip_prefix(MAGIC.as_slice()) else {
+        return Ok(data.to_vec());
+    };
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        return Err(ApplicationError::ParserError("encrypted state: truncated file".into()));
+    }
+    let (salt, rest)    = rest.split_at(SALT_LEN);
+    let (nonce, sealed) = rest.split_at(NONCE_LEN);
+
+    ChaCha20Poly1305::new(&passphrase.key(salt)?)
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| ApplicationError::ParserError("encrypted state: wrong passphrase or corrupted file".into()))
+}
@@ -0,0 +1,1120 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    future,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use tokio::{
+    net::{TcpStream, lookup_host},
+    sync::{Semaphore, mpsc, oneshot, watch},
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::{
+    audit::AuditLog,
+    banlist::BanList,
+    bitfield::Bitfield,
+    config::{Config, Limits, PeerSockets, SeedLimits, TorrentOptions},
+    crossseed::{self, SeedSource},
+    dht::{DEFAULT_BOOTSTRAP, Dht},
+    engine::{
+        BLOCK_SIZE, DEFAULT_MAX_CONNECTIONS, DEFAULT_UPLOAD_SLOTS, TorrentActor, TorrentCommand, TorrentProgress,
+        TorrentResources, TorrentState,
+    },
+    error::ApplicationError,
+    events::{Event, Events},
+    geometry::Geometry,
+    identity::Identity,
+    inspect::Inspectors,
+    listen::{self, Endpoints},
+    notify::Notifier,
+    magnet::{Magnet, MetadataFetch},
+    netwatch::{Network, NetworkWatch},
+    peer::{ConnectionSettings, DEFAULT_MAX_REQUESTS, Peer, PeerInfo, PeerSource},
+    protocol::Handshake,
+    ratelimit::Throttle,
+    recorder::Recorder,
+    resume::{self, ResumeFile},
+    seal::Passphrase,
+    stats::{
+        HISTORY_LEN, SessionStats, SourceStats, StatsCollector, TorrentStats, TorrentTotals, USAGE_LEN,
+        UsageInterval, UsageLog,
+    },
+    storage::Storage,
+    torrent::Torrent,
+    tracker::{Tracker, TrackerStatus},
+    verify::HashWorkers,
+};
+
+/// How often the session samples transfer statistics
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Length of the intervals recorded in each torrent's [`UsageLog`]
+const USAGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long [`Session::shutdown`] waits for torrents to stop, then for
+/// event sinks to drain
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Identifier of a torrent within a session
+pub type TorrentId = usize;
+
+/// Commands accepted by the session actor
+#[derive(Debug)]
+pub enum Command {
+    AddTorrent {
+        torrent:     Box<Torrent>,
+        geometry:    Geometry,
+        storage:     Storage,
+        resume:      ResumeFile,
+        /// Pieces already on disk
+        verified:    Bitfield,
+        /// Where the files are moved to once complete
+        destination: Option<PathBuf>,
+        options:     Box<TorrentOptions>,
+        reply:       oneshot::Sender<(TorrentId, watch::Receiver<TorrentState>)>,
+    },
+    PauseTorrent {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    ResumeTorrent {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    StopTorrent {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    RemoveTorrent {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    GetTorrentStats {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<TorrentTotals, ApplicationError>>,
+    },
+    GetTorrentUsage {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<Vec<UsageInterval>, ApplicationError>>,
+    },
+    GetPeers {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<Vec<PeerInfo>, ApplicationError>>,
+    },
+    GetPeerSources {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<HashMap<PeerSource, SourceStats>, ApplicationError>>,
+    },
+    GetTrackers {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<Vec<TrackerStatus>, ApplicationError>>,
+    },
+    GetProgress {
+        id:    TorrentId,
+        reply: oneshot::Sender<Result<TorrentProgress, ApplicationError>>,
+    },
+    AddPeer {
+        id:    TorrentId,
+        peer:  Peer,
+        reply: oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    /// Returns the torrents whose verified pieces may be reused, see [`crossseed`]
+    GetSeedSources {
+        reply: oneshot::Sender<Vec<SeedSource>>,
+    },
+    FindTorrent {
+        info_hash: [u8; 20],
+        reply:     oneshot::Sender<Option<(TorrentId, watch::Receiver<TorrentState>)>>,
+    },
+    GetStats {
+        reply: oneshot::Sender<SessionStats>,
+    },
+    FetchMetadata {
+        magnet: Box<Magnet>,
+        peers:  Vec<Peer>,
+        reply:  oneshot::Sender<Result<Torrent, ApplicationError>>,
+    },
+    BanPeer {
+        ip:       IpAddr,
+        duration: Option<Duration>,
+        reply:    oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    UnbanPeer {
+        ip:    IpAddr,
+        reply: oneshot::Sender<Result<(), ApplicationError>>,
+    },
+    PauseAll {
+        reply: oneshot::Sender<()>,
+    },
+    ResumeAll {
+        reply: oneshot::Sender<()>,
+    },
+    Reconfigure {
+        limits: Limits,
+        reply:  oneshot::Sender<()>,
+    },
+    /// A peer connected to us and sent its handshake
+    Incoming {
+        stream:    TcpStream,
+        addr:      SocketAddr,
+        handshake: Handshake,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// Cloneable handle to the session actor
+///
+/// Every method sends a [`Command`] and waits for the actor's response.
+#[derive(Debug, Clone)]
+pub struct Session {
+    tx:             mpsc::Sender<Command>,
+    download_dir:   PathBuf,
+    /// Where torrents are downloaded to before being moved to `download_dir`
+    incomplete_dir: Option<PathBuf>,
+    label_quotas:   Arc<HashMap<String, u64>>,
+    /// Threads hashing the pieces found on disk when a torrent is added
+    hash_workers:   HashWorkers,
+    /// Hash the pieces listed in resume files instead of trusting them
+    recheck:        bool,
+    /// Bytes asked of a peer per request
+    block_size:     u32,
+    /// Encrypts the resume files when set
+    passphrase:     Option<Passphrase>,
+}
+
+/// Handle to a torrent that was added to a [`Session`]
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    id:      TorrentId,
+    session: Session,
+    state:   watch::Receiver<TorrentState>,
+}
+
+struct TorrentEntry {
+    info_hash: [u8; 20],
+    name:      String,
+    tx:        mpsc::UnboundedSender<TorrentCommand>,
+    state:     watch::Receiver<TorrentState>,
+    stats:     Arc<TorrentStats>,
+    usage:     UsageLog,
+    seed:      SeedSource,
+}
+
+impl TorrentEntry {
+    /// Returns `true` if the torrent is neither paused nor over
+    fn running(&self) -> bool {
+        matches!(
+            *self.state.borrow(),
+            TorrentState::Announcing | TorrentState::Downloading | TorrentState::Seeding
+        )
+    }
+}
+
+/// The actor owning every torrent and the aggregate statistics
+struct SessionActor {
+    torrents:   HashMap<TorrentId, TorrentEntry>,
+    next_id:    TorrentId,
+    collector:  StatsCollector,
+    events:     Events,
+    throttle:   Throttle,
+    bans:       Arc<RwLock<BanList>>,
+    /// Identity used by every torrent when `Config::shared_identity` is set
+    identity:   Option<Identity>,
+    tracker:    Tracker,
+    dht:        Option<Dht>,
+    seeding:    Option<SeedLimits>,
+    /// Completion hook of torrents that don't set their own
+    hook:       Option<String>,
+    recorder:   Option<Recorder>,
+    audit:      Option<AuditLog>,
+    /// Local address of every outgoing connection
+    bind:       Option<IpAddr>,
+    /// Where peers can connect to us
+    endpoints:  Endpoints,
+    sockets:    PeerSockets,
+    /// Current limits, watched by every torrent
+    limits:     watch::Sender<Limits>,
+    /// Peers that may be uploaded to at once, shared by every torrent
+    slots:      Arc<Semaphore>,
+    /// One permit per open peer connection, shared by every torrent
+    conn_slots: Arc<Semaphore>,
+    inspectors: Inspectors,
+    usage_csv:  Option<PathBuf>,
+    /// Addresses of the machine, `None` if not watched
+    network:    Option<NetworkWatch>,
+    /// Torrents paused because the network went down, resumed once it's back
+    offline:    HashSet<TorrentId>,
+    /// Tasks draining the event sinks, awaited on shutdown
+    sinks:      Vec<JoinHandle<()>>,
+    rx:         mpsc::Receiver<Command>,
+}
+
+impl Session {
+    /// Spawns the session actor and returns a handle to it
+    pub fn new(config: Config) -> Result<Self, ApplicationError> {
+        let mut events = Events::default();
+        let mut sinks  = Vec::new();
+        if let Some(path) = &config.event_log {
+            sinks.push(events.add_log(path, config.event_categories)?);
+        }
+        if config.webhook.is_some() || config.desktop_notifications {
+            let notifier = Notifier::new(config.webhook.clone(), config.desktop_notifications);
+            sinks.push(events.add_notifier(notifier));
+        }
+        let recorder = match &config.record_wire {
+            Some(path) => {
+                let (recorder, task) = Recorder::open(path)?;
+                sinks.push(task);
+                Some(recorder)
+            }
+            None => None,
+        };
+        let audit = match &config.audit_log {
+            Some(path) => {
+                let (audit, task) = AuditLog::open(path)?;
+                sinks.push(task);
+                Some(audit)
+            }
+            None => None,
+        };
+
+        let bans = match &config.ban_list {
+            Some(path) => BanList::load(path, config.state_passphrase.clone())?,
+            None       => BanList::default(),
+        };
+
+        let dht = if config.dht {
+            let bootstrap = if config.dht_bootstrap.is_empty() {
+                DEFAULT_BOOTSTRAP.iter().map(|node| node.to_string()).collect()
+            } else {
+                config.dht_bootstrap.clone()
+            };
+            Some(Dht::start(config.bind_ip, config.dht_port, bootstrap)?)
+        } else {
+            None
+        };
+
+        let listen_ips = match &config.listen_ips[..] {
+            []  => config.bind_ip.into_iter().collect(),
+            ips => ips.to_vec(),
+        };
+        let listeners  = listen::bind(&listen_ips, config.listen_port)?;
+        let endpoints  = Endpoints::of(&listeners)?;
+
+        let network = if config.network_watch {
+            NetworkWatch::new()
+                .inspect_err(|e| println!("Not watching network changes: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        let (tx, rx) = mpsc::channel(32);
+        for listener in listeners {
+            task::spawn(listen::accept(listener, tx.downgrade(), config.peer_sockets));
+        }
+        let actor    = SessionActor {
+            torrents:   HashMap::new(),
+            next_id:    0,
+            collector:  StatsCollector::new(HISTORY_LEN),
+            events,
+            throttle:   Throttle::new(config.limits.download_limit, config.limits.upload_limit),
+            bans:       Arc::new(RwLock::new(bans)),
+            identity:   config.shared_identity.then(Identity::generate),
+            tracker:    Tracker::new(
+                config.announce_ip,
+                config.bind_ip,
+                endpoints,
+                &config.tracker_http,
+                &config.tracker_udp,
+            )?,
+            dht,
+            seeding:    (!config.stop_after_download).then_some(config.seed_limits),
+            hook:       config.exec_on_complete,
+            recorder,
+            audit,
+            bind:       config.bind_ip,
+            endpoints,
+            sockets:    config.peer_sockets,
+            limits:     watch::Sender::new(config.limits),
+            slots:      Arc::new(Semaphore::new(upload_slots(&config.limits))),
+            conn_slots: Arc::new(Semaphore::new(max_connections(&config.limits))),
+            inspectors: config.inspectors,
+            usage_csv:  config.usage_csv,
+            network,
+            offline:    HashSet::new(),
+            sinks,
+            rx,
+        };
+        task::spawn(actor.run());
+        Ok(Self {
+            tx,
+            download_dir:   config.download_dir,
+            incomplete_dir: config.incomplete_dir,
+            label_quotas:   Arc::new(config.label_quotas),
+            hash_workers:   HashWorkers::new(config.hash_workers, config.hash_cores),
+            recheck:        config.recheck,
+            block_size:     config.block_size.unwrap_or(BLOCK_SIZE as u32),
+            passphrase:     config.state_passphrase,
+        })
+    }
+
+    /// Adds a torrent and immediately starts downloading it
+    ///
+    /// Fails if the torrent's piece layout is inconsistent or its files
+    /// can't be created.
+    pub async fn add_torrent(&self, torrent: Torrent) -> Result<TorrentHandle, ApplicationError> {
+        self.add_torrent_with(torrent, TorrentOptions::default()).await
+    }
+
+    /// Like [`Session::add_torrent`], with per-torrent settings
+    ///
+    /// Fails without creating any file if the selected content exceeds the
+    /// torrent's quota, or the quota of its label.
+    pub async fn add_torrent_with(
+        &self,
+        torrent: Torrent,
+        options: TorrentOptions,
+    ) -> Result<TorrentHandle, ApplicationError> {
+        let quota = options.quota.or_else(|| {
+            let label = options.label.as_ref()?;
+            self.label_quotas.get(label).copied()
+        });
+        let size  = torrent.selected_size(options.range.as_ref());
+        if let Some(quota) = quota
+            && size > quota
+        {
+            return Err(ApplicationError::WorkerError(format!(
+                "{} needs {} bytes, over its quota of {} bytes; select less of it with --file or --range",
+                torrent.info.name, size, quota
+            )));
+        }
+
+        let geometry    = Geometry::new(&torrent, self.block_size)?;
+        // Torrents already in the download directory are completed in place
+        let incomplete  = self
+            .incomplete_dir
+            .as_ref()
+            .filter(|_| !self.download_dir.join(&torrent.info.name).exists());
+        let destination = incomplete.map(|_| self.download_dir.clone());
+        let storage     = Storage::new(&torrent, incomplete.unwrap_or(&self.download_dir), geometry)?;
+        let resume      = ResumeFile::new(&storage.root(), self.passphrase.clone());
+        let sources     = self.request(|reply| Command::GetSeedSources { reply }).await?;
+        let (storage, verified) = self.restore(&torrent, storage, resume.clone(), sources).await?;
+        let (id, state) = self
+            .request(|reply| Command::AddTorrent {
+                torrent: Box::new(torrent),
+                geometry,
+                storage,
+                resume,
+                verified,
+                destination,
+                options: Box::new(options),
+                reply,
+            })
+            .await?;
+
+        Ok(TorrentHandle {
+            id,
+            session: self.clone(),
+            state,
+        })
+    }
+
+    /// Finds the pieces of `torrent` already in `storage`, see [`resume::restore`]
+    ///
+    /// Pieces the torrents in `sources` verified in files shared with this
+    /// one are not hashed again, see [`crossseed::reusable`].
+    async fn restore(
+        &self,
+        torrent: &Torrent,
+        storage: Storage,
+        resume:  ResumeFile,
+        sources: Vec<SeedSource>,
+    ) -> Result<(Storage, Bitfield), ApplicationError> {
+        let info_hash = torrent.info_hash();
+        let hashes    = torrent.piece_hashes();
+        let recheck   = self.recheck;
+        let workers   = self.hash_workers.clone();
+        task::spawn_blocking(move || {
+            let reused   = crossseed::reusable(&storage, &hashes, &sources);
+            let verified = resume::restore(&resume, &storage, &info_hash, &hashes, &reused, recheck, &workers)?;
+            Ok((storage, verified))
+        })
+        .await
+        .map_err(|e| ApplicationError::WorkerError(format!("checking pieces on disk: {}", e)))?
+    }
+
+    /// Looks up a torrent by its info hash
+    pub async fn find(&self, info_hash: [u8; 20]) -> Result<Option<TorrentHandle>, ApplicationError> {
+        let found = self
+            .request(|reply| Command::FindTorrent { info_hash, reply })
+            .await?;
+
+        Ok(found.map(|(id, state)| TorrentHandle {
+            id,
+            session: self.clone(),
+            state,
+        }))
+    }
+
+    /// Downloads the metadata of a magnet link from its swarm
+    ///
+    /// `peers` are tried along with the ones the magnet's trackers return.
+    /// The torrent is not added; pass it to [`Session::add_torrent_with`].
+    pub async fn fetch_metadata(&self, magnet: Magnet, peers: Vec<Peer>) -> Result<Torrent, ApplicationError> {
+        let magnet = Box::new(magnet);
+        self.request(|reply| Command::FetchMetadata { magnet, peers, reply })
+            .await?
+    }
+
+    /// Returns statistics aggregated across all torrents
+    pub async fn stats(&self) -> Result<SessionStats, ApplicationError> {
+        self.request(|reply| Command::GetStats { reply }).await
+    }
+
+    /// Refuses connections to `ip`, forever or for the given duration
+    pub async fn ban_peer(&self, ip: IpAddr, duration: Option<Duration>) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::BanPeer { ip, duration, reply })
+            .await?
+    }
+
+    /// Stops a torrent and forgets it; its files stay on disk
+    pub async fn remove_torrent(&self, id: TorrentId) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::RemoveTorrent { id, reply }).await?
+    }
+
+    /// Lifts a ban placed with [`Session::ban_peer`]
+    pub async fn unban_peer(&self, ip: IpAddr) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::UnbanPeer { ip, reply }).await?
+    }
+
+    /// Pauses every running torrent, see [`TorrentHandle::pause`]
+    pub async fn pause_all(&self) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::PauseAll { reply }).await
+    }
+
+    /// Resumes every paused torrent, those paused while the network was
+    /// down included
+    pub async fn resume_all(&self) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::ResumeAll { reply }).await
+    }
+
+    /// Replaces the session's rate limits, connection caps and queue sizes
+    /// while torrents keep running, see [`Limits`]
+    pub async fn reconfigure(&self, limits: Limits) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::Reconfigure { limits, reply }).await
+    }
+
+    /// Stops every torrent, then the session actor once queued events have
+    /// been delivered
+    ///
+    /// Torrents still running close their connections cleanly, cancelling
+    /// pending requests. Gives up waiting on either after a timeout.
+    pub async fn shutdown(&self) -> Result<(), ApplicationError> {
+        self.request(|reply| Command::Shutdown { reply }).await
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> Result<T, ApplicationError> {
+        let (reply, response) = oneshot::channel();
+        self.tx
+            .send(command(reply))
+            .await
+            .map_err(|_| ApplicationError::WorkerError("session is closed".into()))?;
+        response
+            .await
+            .map_err(|_| ApplicationError::WorkerError("session dropped the request".into()))
+    }
+}
+
+impl TorrentHandle {
+    pub fn id(&self) -> TorrentId {
+        self.id
+    }
+
+    /// Returns the current state of the torrent
+    pub fn state(&self) -> TorrentState {
+        self.state.borrow().clone()
+    }
+
+    /// Stops handing out new work to peers
+    pub async fn pause(&self) -> Result<(), ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::PauseTorrent { id, reply })
+            .await?
+    }
+
+    /// Resumes a paused torrent
+    pub async fn resume(&self) -> Result<(), ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::ResumeTorrent { id, reply })
+            .await?
+    }
+
+    /// Stops downloading or seeding; the torrent then reaches a final state
+    pub async fn stop(&self) -> Result<(), ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::StopTorrent { id, reply })
+            .await?
+    }
+
+    /// Returns the transfer totals of this torrent
+    pub async fn stats(&self) -> Result<TorrentTotals, ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::GetTorrentStats { id, reply })
+            .await?
+    }
+
+    /// Returns how much of the torrent is downloaded and verified
+    pub async fn progress(&self) -> Result<TorrentProgress, ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::GetProgress { id, reply })
+            .await?
+    }
+
+    /// Returns the bytes transferred during each of the last intervals, oldest first
+    pub async fn usage(&self) -> Result<Vec<UsageInterval>, ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::GetTorrentUsage { id, reply })
+            .await?
+    }
+
+    /// Returns the peers the torrent is currently connected to
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::GetPeers { id, reply })
+            .await?
+    }
+
+    /// Adds a peer address directly to the torrent's peer pool
+    pub async fn add_peer(&self, addr: SocketAddr) -> Result<(), ApplicationError> {
+        let id   = self.id;
+        let peer = Peer {
+            ip:      addr.ip(),
+            port:    addr.port(),
+            source:  PeerSource::Manual,
+            peer_id: None,
+            host:    None,
+        };
+        self.session
+            .request(|reply| Command::AddPeer { id, peer, reply })
+            .await?
+    }
+
+    /// Adds a peer given by host name; every address it resolves to is tried when dialing
+    pub async fn add_peer_host(&self, host: &str, port: u16) -> Result<(), ApplicationError> {
+        let addr = lookup_host((host, port))
+            .await
+            .map_err(|e| ApplicationError::PeerError(format!("{}: {}", host, e)))?
+            .next()
+            .ok_or_else(|| ApplicationError::PeerError(format!("{}: no address", host)))?;
+
+        let id   = self.id;
+        let peer = Peer {
+            ip:      addr.ip(),
+            port,
+            source:  PeerSource::Manual,
+            peer_id: None,
+            host:    Some(host.to_string()),
+        };
+        self.session
+            .request(|reply| Command::AddPeer { id, peer, reply })
+            .await?
+    }
+
+    /// Returns how many peers each discovery mechanism contributed
+    pub async fn peer_sources(&self) -> Result<HashMap<PeerSource, SourceStats>, ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::GetPeerSources { id, reply })
+            .await?
+    }
+
+    /// Returns the state of each of the torrent's trackers
+    pub async fn trackers(&self) -> Result<Vec<TrackerStatus>, ApplicationError> {
+        let id = self.id;
+        self.session
+            .request(|reply| Command::GetTrackers { id, reply })
+            .await?
+    }
+
+    /// Waits until the download is complete, i.e. the torrent is seeding or over
+    pub async fn completed(&self) -> Result<(), ApplicationError> {
+        self.wait_for(|s| {
+            matches!(
+                s,
+                TorrentState::Seeding
+                    | TorrentState::Finished
+                    | TorrentState::Stopped
+                    | TorrentState::Failed(_)
+            )
+        })
+        .await
+    }
+
+    /// Waits until the torrent is over: finished, stopped or failed
+    pub async fn finished(&self) -> Result<(), ApplicationError> {
+        self.wait_for(|s| {
+            matches!(
+                s,
+                TorrentState::Finished | TorrentState::Stopped | TorrentState::Failed(_)
+            )
+        })
+        .await
+    }
+
+    async fn wait_for(&self, done: impl Fn(&TorrentState) -> bool) -> Result<(), ApplicationError> {
+        let mut state = self.state.clone();
+        let state     = state
+            .wait_for(done)
+            .await
+            .map_err(|_| ApplicationError::WorkerError("torrent actor is gone".into()))?;
+
+        match &*state {
+            TorrentState::Failed(e) => Err(e.clone()),
+            _                       => Ok(()),
+        }
+    }
+}
+
+impl SessionActor {
+    async fn run(mut self) {
+        let mut ticker = time::interval(STATS_INTERVAL);
+        let mut usage  = time::interval_at(time::Instant::now() + USAGE_INTERVAL, USAGE_INTERVAL);
+        let shutdown   = loop {
+            tokio::select! {
+                cmd = self.rx.recv() => match cmd {
+                    Some(Command::Shutdown { reply }) => break Some(reply),
+                    Some(cmd) => self.handle(cmd),
+                    None      => break None,
+                },
+                _ = ticker.tick() => {
+                    self.collector.sample();
+                }
+                _ = usage.tick() => self.close_usage(),
+                change = network_change(&mut self.network) => match change {
+                    Ok(network) => self.network_changed(network),
+                    Err(e)      => {
+                        println!("Stopped watching network changes: {}", e);
+                        self.network = None;
+                    }
+                },
+            }
+        };
+
+        self.stop_torrents().await;
+
+        // Account for the last, partial interval
+        self.close_usage();
+
+        // Sinks finish once every handle to them, including ours, is dropped
+        let sinks = std::mem::take(&mut self.sinks);
+        drop(self);
+        let _ = time::timeout(SHUTDOWN_TIMEOUT, join_all(sinks)).await;
+
+        if let Some(reply) = shutdown {
+            let _ = reply.send(());
+        }
+    }
+
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::AddTorrent { torrent, geometry, storage, resume, verified, destination, options, reply } => {
+                let id           = self.next_id;
+                let info_hash    = torrent.info_hash();
+                let torrent_name = torrent.info.name.clone();
+                let stats     = Arc::new(TorrentStats::default());
+                let (state_tx, state_rx) = watch::channel(TorrentState::Announcing);
+                self.events.emit(Event::TorrentAdded {
+                    torrent:   id,
+                    name:      torrent.info.name.clone(),
+                    info_hash: hex::encode(info_hash),
+                });
+
+                let resources = TorrentResources {
+                    geometry,
+                    storage,
+                    resume,
+                    verified,
+                    destination,
+                    stats:            stats.clone(),
+                    events:           self.events.clone(),
+                    throttle:         self.throttle.child(None, None),
+                    bans:             self.bans.clone(),
+                    identity:         self.identity.unwrap_or_else(Identity::generate),
+                    tracker:          self.tracker.clone(),
+                    dht:              self.dht.clone(),
+                    seeding:          self.seeding,
+                    label:            options.label,
+                    hook:             options.exec_on_complete.or_else(|| self.hook.clone()),
+                    recorder:         self.recorder.clone(),
+                    audit:            self.audit.clone(),
+                    bind:             self.bind,
+                    endpoints:        self.endpoints,
+                    sockets:          self.sockets,
+                    caps:             self.limits.subscribe(),
+                    upload_slots:     self.slots.clone(),
+                    conn_slots:       self.conn_slots.clone(),
+                    inspectors:       self.inspectors.clone(),
+                    tracker_auth:     options.tracker_auth,
+                    range:            options.range,
+                    first_last_first: options.first_last_first,
+                    rare_files_first: options.rare_files_first,
+                };
+                let (actor, tx) = TorrentActor::new(id, *torrent, state_tx, resources);
+                let seed        = actor.seed_source();
+
+                self.next_id += 1;
+                self.collector.register(stats.clone());
+                self.torrents.insert(id, TorrentEntry {
+                    info_hash,
+                    name: torrent_name,
+                    tx,
+                    state: state_rx.clone(),
+                    stats,
+                    usage: UsageLog::new(USAGE_LEN),
+                    seed,
+                });
+                task::spawn(actor.run());
+
+                let _ = reply.send((id, state_rx));
+            }
+            Command::PauseTorrent { id, reply } => {
+                self.offline.remove(&id);
+                let _ = reply.send(self.forward(id, TorrentCommand::Pause));
+            }
+            Command::ResumeTorrent { id, reply } => {
+                self.offline.remove(&id);
+                let _ = reply.send(self.forward(id, TorrentCommand::Resume));
+            }
+            Command::StopTorrent { id, reply } => {
+                let _ = reply.send(self.forward(id, TorrentCommand::Stop));
+            }
+            Command::RemoveTorrent { id, reply } => {
+                self.offline.remove(&id);
+                let removed = self
+                    .torrents
+                    .remove(&id)
+                    .map(|t| {
+                        self.collector.unregister(&t.stats);
+                        let _ = t.tx.send(TorrentCommand::Stop);
+                    })
+                    .ok_or_else(|| ApplicationError::WorkerError(format!("unknown torrent {}", id)));
+                let _ = reply.send(removed);
+            }
+            Command::GetTorrentStats { id, reply } => {
+                let totals = self
+                    .torrents
+                    .get(&id)
+                    .map(|t| t.stats.totals())
+                    .ok_or_else(|| ApplicationError::WorkerError(format!("unknown torrent {}", id)));
+                let _ = reply.send(totals);
+            }
+            Command::GetTorrentUsage { id, reply } => {
+                let usage = self
+                    .torrents
+                    .get(&id)
+                    .map(|t| t.usage.intervals())
+                    .ok_or_else(|| ApplicationError::WorkerError(format!("unknown torrent {}", id)));
+                let _ = reply.send(usage);
+            }
+            Command::GetPeers { id, reply } => {
+                self.query(id, reply, TorrentCommand::GetPeers);
+            }
+            Command::GetProgress { id, reply } => {
+                self.query(id, reply, TorrentCommand::GetProgress);
+            }
+            Command::GetPeerSources { id, reply } => {
+                self.query(id, reply, TorrentCommand::GetPeerSources);
+            }
+            Command::GetTrackers { id, reply } => {
+                self.query(id, reply, TorrentCommand::GetTrackers);
+            }
+            Command::AddPeer { id, peer, reply } => {
+                let _ = reply.send(self.forward(id, TorrentCommand::AddPeers(vec![peer])));
+            }
+            Command::FetchMetadata { magnet, peers, reply } => {
+                let identity = self.identity.unwrap_or_else(Identity::generate);
+                let fetch    = MetadataFetch {
+                    tracker:  self.tracker.clone(),
+                    dht:      self.dht.clone(),
+                    identity,
+                    settings: ConnectionSettings {
+                        info_hash:    magnet.info_hash,
+                        peer_id:      identity.peer_id,
+                        geometry:     None,
+                        bind:         self.bind,
+                        endpoints:    self.endpoints,
+                        sockets:      self.sockets,
+                        max_requests: self.limits.borrow().max_requests.map_or(DEFAULT_MAX_REQUESTS, NonZeroUsize::get),
+                        metadata:     None,
+                        recorder:     self.recorder.clone(),
+                        inspectors:   self.inspectors.clone(),
+                    },
+                    throttle: self.throttle.child(None, None),
+                };
+                task::spawn(async move {
+                    let _ = reply.send(fetch.run(&magnet, peers).await);
+                });
+            }
+            Command::GetSeedSources { reply } => {
+                let _ = reply.send(self.torrents.values().map(|t| t.seed.clone()).collect());
+            }
+            Command::FindTorrent { info_hash, reply } => {
+                let found = self
+                    .torrents
+                    .iter()
+                    .find(|(_, t)| t.info_hash == info_hash)
+                    .map(|(id, t)| (*id, t.state.clone()));
+                let _ = reply.send(found);
+            }
+            Command::GetStats { reply } => {
+                let _ = reply.send(self.collector.stats());
+            }
+            Command::Incoming { stream, addr, handshake } => {
+                // Connections for torrents we don't have are dropped
+                if let Some((id, _)) = self.torrents.iter().find(|(_, t)| t.info_hash == handshake.info_hash) {
+                    let peer = Peer {
+                        ip:      addr.ip(),
+                        port:    addr.port(),
+                        source:  PeerSource::Incoming,
+                        peer_id: None,
+                        host:    None,
+                    };
+                    let _ = self.forward(*id, TorrentCommand::Incoming(stream, peer, handshake));
+                }
+            }
+            Command::BanPeer { ip, duration, reply } => {
+                let result = self.bans.write().unwrap().ban(ip, duration);
+                if result.is_ok() {
+                    self.events.emit(Event::PeerBanned {
+                        ip:      ip.to_string(),
+                        seconds: duration.map(|d| d.as_secs()),
+                    });
+                }
+                let _ = reply.send(result);
+            }
+            Command::UnbanPeer { ip, reply } => {
+                let _ = reply.send(self.bans.write().unwrap().unban(ip));
+            }
+            Command::PauseAll { reply } => {
+                self.offline.clear();
+                for entry in self.torrents.values().filter(|t| t.running()) {
+                    let _ = entry.tx.send(TorrentCommand::Pause);
+                }
+                let _ = reply.send(());
+            }
+            Command::ResumeAll { reply } => {
+                self.offline.clear();
+                for entry in self.torrents.values() {
+                    if *entry.state.borrow() == TorrentState::Paused {
+                        let _ = entry.tx.send(TorrentCommand::Resume);
+                    }
+                }
+                let _ = reply.send(());
+            }
+            Command::Reconfigure { limits, reply } => {
+                self.reconfigure(limits);
+                let _ = reply.send(());
+            }
+            Command::Shutdown { .. } => unreachable!("handled by the run loop"),
+        }
+    }
+
+    /// Applies new limits without interrupting any transfer
+    ///
+    /// Rates change right away, those of each peer connection included.
+    /// Removed upload slots and connections are taken back as the peers
+    /// holding them disconnect; the other caps apply the next time a torrent
+    /// connects a peer.
+    fn reconfigure(&mut self, limits: Limits) {
+        self.throttle.download.set_rate(limits.download_limit);
+        self.throttle.upload.set_rate(limits.upload_limit);
+
+        let current = *self.limits.borrow();
+        resize(&self.slots, upload_slots(&current), upload_slots(&limits));
+        resize(&self.conn_slots, max_connections(&current), max_connections(&limits));
+        self.limits.send_replace(limits);
+    }
+
+    /// Pauses the running torrents when the network goes away, then resumes
+    /// them once it is back, announcing again as our address likely changed
+    fn network_changed(&mut self, network: Network) {
+        self.events.emit(Event::NetworkChanged { up: network == Network::Up });
+        match network {
+            Network::Down => {
+                println!("Network is down, pausing torrents");
+                for (id, entry) in &self.torrents {
+                    if entry.running() && entry.tx.send(TorrentCommand::Pause).is_ok() {
+                        self.offline.insert(*id);
+                    }
+                }
+            }
+            Network::Up => {
+                println!("Network is back, resuming torrents");
+                for id in std::mem::take(&mut self.offline) {
+                    let _ = self.forward(id, TorrentCommand::Resume);
+                    let _ = self.forward(id, TorrentCommand::Reannounce);
+                }
+            }
+        }
+    }
+
+    /// Ends the current usage interval of every torrent and exports it
+    fn close_usage(&mut self) {
+        let mut ids = self.torrents.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        let mut rows = String::new();
+        for id in ids {
+            let entry    = self.torrents.get_mut(&id).unwrap();
+            let interval = entry.usage.close(&entry.stats);
+            rows.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                interval.start,
+                interval.seconds,
+                id,
+                hex::encode(entry.info_hash),
+                csv_field(&entry.name),
+                interval.downloaded,
+                interval.uploaded,
+            ));
+            self.events.emit(Event::Transfer {
+                torrent:    id,
+                seconds:    interval.seconds,
+                downloaded: interval.downloaded,
+                uploaded:   interval.uploaded,
+            });
+        }
+
+        if let Some(path) = &self.usage_csv
+            && let Err(e) = append_usage(path, &rows)
+        {
+            println!("Failed to write usage report: {}", e);
+        }
+    }
+
+    /// Forwards a query to a torrent actor and relays its answer to `reply`
+    ///
+    /// The answer is awaited on a separate task so the session loop never
+    /// waits on a torrent.
+    fn query<T: Send + 'static>(
+        &self,
+        id:      TorrentId,
+        reply:   oneshot::Sender<Result<T, ApplicationError>>,
+        command: impl FnOnce(oneshot::Sender<T>) -> TorrentCommand,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        if let Err(e) = self.forward(id, command(tx)) {
+            let _ = reply.send(Err(e));
+            return;
+        }
+        task::spawn(async move {
+            let answer = rx
+                .await
+                .map_err(|_| ApplicationError::WorkerError("torrent actor is gone".into()));
+            let _ = reply.send(answer);
+        });
+    }
+
+    /// Stops every torrent still running and waits for them to close their connections
+    async fn stop_torrents(&mut self) {
+        let mut states = Vec::new();
+        for entry in self.torrents.values() {
+            if entry.tx.send(TorrentCommand::Stop).is_ok() {
+                states.push(entry.state.clone());
+            }
+        }
+        let stopped = join_all(states.into_iter().map(|mut state| async move {
+            let _ = state
+                .wait_for(|s| matches!(s, TorrentState::Finished | TorrentState::Stopped | TorrentState::Failed(_)))
+                .await;
+        }));
+        let _ = time::timeout(SHUTDOWN_TIMEOUT, stopped).await;
+    }
+
+    fn forward(&self, id: TorrentId, cmd: TorrentCommand) -> Result<(), ApplicationError> {
+        self.torrents
+            .get(&id)
+            .ok_or_else(|| ApplicationError::WorkerError(format!("unknown torrent {}", id)))?
+            .tx
+            .send(cmd)
+            .map_err(|_| ApplicationError::WorkerError(format!("torrent {} is stopped", id)))
+    }
+}
+
+/// Waits for the next change `watch` reports; never returns without one
+async fn network_change(watch: &mut Option<NetworkWatch>) -> io::Result<Network> {
+    match watch {
+        Some(watch) => watch.changed().await,
+        None        => future::pending().await,
+    }
+}
+
+/// Returns the number of upload slots `limits` allows
+fn upload_slots(limits: &Limits) -> usize {
+    limits.upload_slots.map_or(DEFAULT_UPLOAD_SLOTS, NonZeroUsize::get)
+}
+
+/// Returns the number of peer connections `limits` allows across all torrents
+fn max_connections(limits: &Limits) -> usize {
+    limits.max_connections.map_or(DEFAULT_MAX_CONNECTIONS, NonZeroUsize::get)
+}
+
+/// Changes the number of permits of `semaphore` from `before` to `after`
+///
+/// Permits in use when some are removed are taken back as they are released.
+fn resize(semaphore: &Arc<Semaphore>, before: usize, after: usize) {
+    if after > before {
+        semaphore.add_permits(after - before);
+    } else if after < before {
+        let missing = before - after - semaphore.forget_permits(before - after);
+        if missing > 0 {
+            let semaphore = semaphore.clone();
+            task::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(missing as u32).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+
+/// Appends `rows` to the usage CSV at `path`, writing the header to new files
+fn append_usage(path: &Path, rows: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(b"start,seconds,torrent,info_hash,name,downloaded,uploaded\n")?;
+    }
+    file.write_all(rows.as_bytes())
+}
+
+/// Quotes a CSV field when it contains a separator, a quote or a newline
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Number of samples kept by default in a [`RateHistory`]
+pub const HISTORY_LEN: usize = 300;
+
+/// Number of intervals kept by default in a [`UsageLog`] (a day of minutes)
+pub const USAGE_LEN: usize = 24 * 60;
+
+/// Transfer counters of a single torrent, shared by all of its peer tasks
+#[derive(Debug, Default)]
+pub struct TorrentStats {
+    downloaded:    AtomicU64,
+    uploaded:      AtomicU64,
+    connections:   AtomicUsize,
+    peers_used:    AtomicUsize,
+    pieces_failed: AtomicUsize,
+    /// Last swarm totals a tracker reported, with when it did (UNIX seconds)
+    swarm:         Mutex<Option<(SwarmCounts, u64)>>,
+}
+
+/// Size of a torrent's swarm as reported by a tracker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwarmCounts {
+    pub seeders:   u64,
+    pub leechers:  u64,
+    /// Completed downloads; announces rarely include them, scrapes do
+    pub downloads: Option<u64>,
+}
+
+/// Last known swarm totals of a torrent and how old they are
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SwarmSnapshot {
+    pub seeders:   u64,
+    pub leechers:  u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<u64>,
+    /// Seconds since a tracker reported them
+    pub age_secs:  u64,
+}
+
+/// Point-in-time copy of a torrent's [`TorrentStats`]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TorrentTotals {
+    pub downloaded:    u64,
+    pub uploaded:      u64,
+    /// Number of successful peer connections over the torrent's lifetime
+    pub peers_used:    usize,
+    /// Number of pieces that had to be downloaded again
+    pub pieces_failed: usize,
+    /// `None` until a tracker reports the swarm's size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swarm:         Option<SwarmSnapshot>,
+}
+
+impl TorrentStats {
+    /// Adds `bytes` to the amount of data received from peers
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Adds `bytes` to the amount of data sent to peers
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Marks a new peer connection as open
+    pub fn peer_connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.peers_used.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks a previously opened peer connection as closed
+    pub fn peer_disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Records a piece that has to be downloaded again
+    pub fn record_piece_failed(&self) {
+        self.pieces_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the swarm totals a tracker just reported
+    ///
+    /// A report without completed downloads keeps the previous count.
+    pub fn record_swarm(&self, mut counts: SwarmCounts) {
+        let mut swarm = self.swarm.lock().unwrap();
+        if counts.downloads.is_none() {
+            counts.downloads = swarm.and_then(|(previous, _)| previous.downloads);
+        }
+        *swarm = Some((counts, unix_now()));
+    }
+
+    /// Returns the total number of bytes received so far
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes sent so far
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of currently open peer connections
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn totals(&self) -> TorrentTotals {
+        TorrentTotals {
+            downloaded:    self.downloaded(),
+            uploaded:      self.uploaded(),
+            peers_used:    self.peers_used.load(Ordering::Relaxed),
+            pieces_failed: self.pieces_failed.load(Ordering::Relaxed),
+            swarm:         self.swarm.lock().unwrap().map(|(counts, at)| SwarmSnapshot {
+                seeders:   counts.seeders,
+                leechers:  counts.leechers,
+                downloads: counts.downloads,
+                age_secs:  unix_now().saturating_sub(at),
+            }),
+        }
+    }
+}
+
+/// How many peers a single [`PeerSource`](crate::peer::PeerSource) contributed to a torrent
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SourceStats {
+    /// Addresses added to the peer pool
+    pub discovered: usize,
+    /// Successful connections to those addresses
+    pub connected:  usize,
+}
+
+/// Point-in-time totals aggregated across every torrent
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionStats {
+    /// Download rate in bytes per second, measured since the previous sample
+    pub download_rate:    f64,
+    /// Upload rate in bytes per second, measured since the previous sample
+    pub upload_rate:      f64,
+    /// All-time number of bytes received
+    pub total_downloaded: u64,
+    /// All-time number of bytes sent
+    pub total_uploaded:   u64,
+    /// Ratio between uploaded and downloaded bytes (0 if nothing was downloaded)
+    pub share_ratio:      f64,
+    /// Number of peer connections currently open
+    pub open_connections: usize,
+}
+
+/// A single entry of a [`RateHistory`]
+#[derive(Debug, Clone, Copy)]
+pub struct RateSample {
+    pub at:            Instant,
+    pub download_rate: f64,
+    pub upload_rate:   f64,
+}
+
+/// Fixed-capacity ring buffer of rate samples, oldest first
+///
+/// Meant to be polled by UIs that draw rate graphs.
+#[derive(Debug)]
+pub struct RateHistory {
+    samples:  VecDeque<RateSample>,
+    capacity: usize,
+}
+
+impl RateHistory {
+    /// Creates an empty history holding at most `capacity` samples
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Appends a sample, evicting the oldest one when full
+    pub fn push(&mut self, sample: RateSample) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Iterates over the stored samples, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RateSample> {
+        self.samples.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+/// Bytes transferred by a torrent during one accounting interval
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UsageInterval {
+    /// Start of the interval, in seconds since the UNIX epoch
+    pub start:      u64,
+    /// Length of the interval in seconds
+    pub seconds:    u64,
+    pub downloaded: u64,
+    pub uploaded:   u64,
+}
+
+/// Per-interval transfer totals of one torrent, oldest first
+///
+/// [`UsageLog::close`] is expected to be called at the end of every interval;
+/// it turns the growth of the torrent's counters into a new entry.
+#[derive(Debug)]
+pub struct UsageLog {
+    intervals:  VecDeque<UsageInterval>,
+    capacity:   usize,
+    start:      u64,
+    downloaded: u64,
+    uploaded:   u64,
+}
+
+impl UsageLog {
+    /// Creates an empty log holding at most `capacity` intervals
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            intervals:  VecDeque::with_capacity(capacity),
+            capacity,
+            start:      unix_now(),
+            downloaded: 0,
+            uploaded:   0,
+        }
+    }
+
+    /// Ends the current interval, records it and returns it
+    pub fn close(&mut self, stats: &TorrentStats) -> UsageInterval {
+        let now      = unix_now();
+        let interval = UsageInterval {
+            start:      self.start,
+            seconds:    now.saturating_sub(self.start),
+            downloaded: stats.downloaded().saturating_sub(self.downloaded),
+            uploaded:   stats.uploaded().saturating_sub(self.uploaded),
+        };
+
+        self.start      = now;
+        self.downloaded = stats.downloaded();
+        self.uploaded   = stats.uploaded();
+
+        if self.capacity > 0 {
+            if self.intervals.len() == self.capacity {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(interval);
+        }
+        interval
+    }
+
+    /// Returns the recorded intervals, oldest first
+    pub fn intervals(&self) -> Vec<UsageInterval> {
+        self.intervals.iter().copied().collect()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Aggregates the [`TorrentStats`] of every torrent into [`SessionStats`]
+///
+/// Rates are derived from the difference between two consecutive calls to
+/// [`StatsCollector::sample`], which is expected to be called periodically.
+#[derive(Debug)]
+pub struct StatsCollector {
+    torrents: Vec<Arc<TorrentStats>>,
+    last:     Option<(Instant, u64, u64)>,
+    current:  SessionStats,
+    history:  RateHistory,
+}
+
+impl StatsCollector {
+    pub fn new(history_len: usize) -> Self {
+        Self {
+            torrents: Vec::new(),
+            last:     None,
+            current:  SessionStats::default(),
+            history:  RateHistory::new(history_len),
+        }
+    }
+
+    /// Adds a torrent's counters to the aggregate
+    pub fn register(&mut self, stats: Arc<TorrentStats>) {
+        self.torrents.push(stats);
+    }
+
+    /// Removes a torrent's counters from the aggregate
+    pub fn unregister(&mut self, stats: &Arc<TorrentStats>) {
+        self.torrents.retain(|s| !Arc::ptr_eq(s, stats));
+    }
+
+    /// Takes a new sample: refreshes totals, computes rates and records them
+    pub fn sample(&mut self) -> SessionStats {
+        let now        = Instant::now();
+        let downloaded = self.torrents.iter().map(|t| t.downloaded()).sum::<u64>();
+        let uploaded   = self.torrents.iter().map(|t| t.uploaded()).sum::<u64>();
+
+        let (download_rate, upload_rate) = match self.last {
+            Some((at, down, up)) => {
+                let secs = now.duration_since(at).as_secs_f64();
+                if secs > 0.0 {
+                    (
+                        downloaded.saturating_sub(down) as f64 / secs,
+                        uploaded.saturating_sub(up) as f64 / secs,
+                    )
+                } else {
+                    (self.current.download_rate, self.current.upload_rate)
+                }
+            }
+            None => (0.0, 0.0),
+        };
+
+        self.last    = Some((now, downloaded, uploaded));
+        self.current = SessionStats {
+            download_rate,
+            upload_rate,
+            total_downloaded: downloaded,
+            total_uploaded:   uploaded,
+            share_ratio:      if downloaded == 0 { 0.0 } else { uploaded as f64 / downloaded as f64 },
+            open_connections: self.torrents.iter().map(|t| t.connections()).sum(),
+        };
+
+        self.history.push(RateSample {
+            at: now,
+            download_rate,
+            upload_rate,
+        });
+
+        self.current
+    }
+
+    /// Returns the most recent sample
+    pub fn stats(&self) -> SessionStats {
+        self.current
+    }
+
+    /// Returns the history of rate samples
+    pub fn history(&self) -> &RateHistory {
+        &self.history
+    }
+}
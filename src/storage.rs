@@ -0,0 +1,255 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+};
+
+use crate::{
+    bitfield::Bitfield,
+    error::ApplicationError,
+    geometry::{BlockOffset, Geometry, PieceIndex},
+    torrent::Torrent,
+    verify::{self, HashWorkers},
+};
+
+/// Bytes read from disk at once when checking pieces
+const CHECK_CHUNK: usize = 64 * 1024 * 1024;
+
+/// A file of the torrent and the bytes of the content it holds
+#[derive(Debug)]
+struct StorageFile {
+    /// Path relative to the storage's directory
+    path:  PathBuf,
+    range: Range<u64>,
+}
+
+/// Writes verified pieces at their place in the files of a torrent
+///
+/// Pieces may span several files and files may hold several pieces; the
+/// torrent's content is treated as the files laid back to back, in order.
+#[derive(Debug)]
+pub struct Storage {
+    /// Directory the torrent is stored in, changed by [`Storage::relocate`]
+    dir:      RwLock<PathBuf>,
+    /// File of a single-file torrent, or directory of a multi-file one,
+    /// relative to `dir`
+    name:     PathBuf,
+    files:    Vec<StorageFile>,
+    geometry: Geometry,
+    /// Whether every file was created empty, so nothing of the torrent is on disk
+    fresh:    bool,
+}
+
+impl Storage {
+    /// Creates the files of `torrent` below `dir`, pre-allocated to their full size
+    ///
+    /// Existing files are kept; only ones shorter than expected are extended.
+    pub fn new(torrent: &Torrent, dir: &Path, geometry: Geometry) -> Result<Self, ApplicationError> {
+        let mut files = Vec::new();
+        let mut fresh = true;
+        for (entry, range) in torrent.files().into_iter().zip(torrent.file_ranges()) {
+            // Paths come from the torrent: never let them leave `dir`
+            if !entry.path.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(ApplicationError::ParserError(format!(
+                    "unsafe file path {}",
+                    entry.path.display()
+                )));
+            }
+            fresh &= allocate(&dir.join(&entry.path), range.end - range.start)? == 0;
+            files.push(StorageFile { path: entry.path, range });
+        }
+
+        Ok(Self {
+            dir: RwLock::new(dir.to_path_buf()),
+            name: PathBuf::from(&torrent.info.name),
+            files,
+            geometry,
+            fresh,
+        })
+    }
+
+    /// Returns the file of a single-file torrent, or the directory of a multi-file one
+    pub fn root(&self) -> PathBuf {
+        self.dir.read().unwrap().join(&self.name)
+    }
+
+    /// Moves the torrent's files to `dir`, returning the new [`root`](Self::root)
+    ///
+    /// Files are renamed, or copied then removed when `dir` is on another
+    /// file system; reads and writes wait meanwhile. Fails without touching
+    /// anything if `dir` already holds a file of the same name. This blocks.
+    pub fn relocate(&self, dir: &Path) -> Result<PathBuf, ApplicationError> {
+        let mut current = self.dir.write().unwrap();
+        let from        = current.join(&self.name);
+        let to          = dir.join(&self.name);
+        if to.exists() {
+            return Err(ApplicationError::WorkerError(format!("{} already exists", to.display())));
+        }
+        fs::create_dir_all(dir).map_err(|e| storage_error(dir, e))?;
+        move_path(&from, &to)?;
+        *current = dir.to_path_buf();
+        Ok(to)
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// Returns where each file of the torrent is on disk and the bytes of
+    /// the content it holds
+    pub fn files(&self) -> Vec<(PathBuf, Range<u64>)> {
+        let dir = self.dir.read().unwrap();
+        self.files
+            .iter()
+            .map(|file| (dir.join(&file.path), file.range.clone()))
+            .collect()
+    }
+
+    /// Returns `true` if no file of the torrent held any data before
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Writes `data`, the verified content of `piece`, into the files it overlaps
+    pub fn write_piece(&self, piece: PieceIndex, data: &[u8]) -> Result<(), ApplicationError> {
+        let start = self.geometry.piece_offset(piece);
+        let end   = start + data.len() as u64;
+        let dir   = self.dir.read().unwrap();
+
+        for file in self.files.iter().filter(|f| f.range.start < end && start < f.range.end) {
+            let from = start.max(file.range.start);
+            let to   = end.min(file.range.end);
+            let path = dir.join(&file.path);
+            let mut out = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|e| storage_error(&path, e))?;
+            out.seek(SeekFrom::Start(from - file.range.start))
+                .and_then(|_| out.write_all(&data[(from - start) as usize..(to - start) as usize]))
+                .map_err(|e| storage_error(&path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Reads `len` bytes of `piece` from `block` on, to serve a peer's request
+    pub fn read_block(&self, piece: PieceIndex, block: BlockOffset, len: usize) -> Result<Vec<u8>, ApplicationError> {
+        self.read(self.geometry.piece_offset(piece) + block.get() as u64, len)
+    }
+
+    /// Reads `len` bytes of the content starting at `start`
+    fn read(&self, start: u64, len: usize) -> Result<Vec<u8>, ApplicationError> {
+        let end      = start + len as u64;
+        let mut data = vec![0u8; len];
+        let dir      = self.dir.read().unwrap();
+
+        for file in self.files.iter().filter(|f| f.range.start < end && start < f.range.end) {
+            let from = start.max(file.range.start);
+            let to   = end.min(file.range.end);
+            let path = dir.join(&file.path);
+            let mut input = OpenOptions::new()
+                .read(true)
+                .open(&path)
+                .map_err(|e| storage_error(&path, e))?;
+            input.seek(SeekFrom::Start(from - file.range.start))
+                .and_then(|_| input.read_exact(&mut data[(from - start) as usize..(to - start) as usize]))
+                .map_err(|e| storage_error(&path, e))?;
+        }
+        Ok(data)
+    }
+
+    /// Hashes the pieces of `candidates` found on disk, returning the ones
+    /// matching `hashes`
+    ///
+    /// Consecutive candidates are read in chunks of about [`CHECK_CHUNK`]
+    /// bytes and each chunk hashed in parallel by `workers`. This blocks.
+    pub fn check(
+        &self,
+        candidates: &Bitfield,
+        hashes:     &[[u8; 20]],
+        workers:    &HashWorkers,
+    ) -> Result<Bitfield, ApplicationError> {
+        let candidates = self
+            .geometry
+            .pieces()
+            .filter(|p| candidates.get(p.get()) && p.get() < hashes.len())
+            .collect::<Vec<_>>();
+
+        let mut verified = Bitfield::new(hashes.len());
+        let mut rest     = candidates.as_slice();
+        while let Some(&first) = rest.first() {
+            // A run of consecutive pieces, read and hashed together; only the
+            // last piece of the torrent is shorter, and it ends a run
+            let size  = self.geometry.piece_len(first) as usize;
+            let count = rest
+                .iter()
+                .take((CHECK_CHUNK / size).max(1))
+                .enumerate()
+                .take_while(|(i, p)| p.get() == first.get() + i)
+                .count();
+            let (run, tail) = rest.split_at(count);
+            rest = tail;
+
+            let last  = run[count - 1];
+            let start = self.geometry.piece_offset(first);
+            let end   = self.geometry.piece_offset(last) + self.geometry.piece_len(last) as u64;
+            let data  = self.read(start, (end - start) as usize)?;
+            let valid = verify::verify_pieces(&data, size, &hashes[first.get()..=last.get()], workers);
+            for (piece, ok) in run.iter().zip(valid) {
+                verified.set(piece.get(), ok);
+            }
+        }
+        Ok(verified)
+    }
+}
+
+/// Creates `path` and its parent directories, growing the file to `len` bytes
+///
+/// Returns the length the file had before.
+fn allocate(path: &Path, len: u64) -> Result<u64, ApplicationError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| storage_error(parent, e))?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| storage_error(path, e))?;
+    let current = file.metadata().map_err(|e| storage_error(path, e))?.len();
+    if current < len {
+        file.set_len(len).map_err(|e| storage_error(path, e))?;
+    }
+    Ok(current)
+}
+
+/// Renames `from` to `to`, copying it over when they are on different file systems
+fn move_path(from: &Path, to: &Path) -> Result<(), ApplicationError> {
+    match fs::rename(from, to) {
+        Ok(())                                         => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            copy_path(from, to)?;
+            let removed = if from.is_dir() { fs::remove_dir_all(from) } else { fs::remove_file(from) };
+            removed.map_err(|e| storage_error(from, e))
+        }
+        Err(e) => Err(storage_error(from, e)),
+    }
+}
+
+/// Copies a file, or a directory and everything below it
+fn copy_path(from: &Path, to: &Path) -> Result<(), ApplicationError> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ()).map_err(|e| storage_error(from, e));
+    }
+    fs::create_dir_all(to).map_err(|e| storage_error(to, e))?;
+    for entry in fs::read_dir(from).map_err(|e| storage_error(from, e))? {
+        let entry = entry.map_err(|e| storage_error(from, e))?;
+        copy_path(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn storage_error(path: &Path, error: std::io::Error) -> ApplicationError {
+    ApplicationError::WorkerError(format!("{}: {}", path.display(), error))
+}
@@ -3,15 +3,24 @@ use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use crate::error::ApplicationError;
 
 /// Represents a parsed .torrent file
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Torrent {
-    pub announce: String,
-    pub info:     Info,
+    /// Tracker URL; empty for trackerless torrents
+    #[serde(default)]
+    pub announce:       String,
+    /// Tiers of backup tracker URLs (BEP 12)
+    #[serde(default, rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    pub announce_list:  Option<Vec<Vec<String>>>,
+    /// DHT nodes (host, port) suggested by trackerless torrents (BEP 5)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes:          Option<Vec<(String, i64)>>,
+    pub info:           Info,
     #[serde(skip)]
     pub info_raw_bytes: Vec<u8>,
 }
@@ -25,6 +34,9 @@ pub struct Info {
     pub pieces: ByteBuf,
     pub length: Option<i64>,
     pub files:  Option<Vec<TorrentFile>>,
+    /// Set to 1 by torrents whose peers may only come from their trackers (BEP 27)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
 }
 
 /// A file entry in a multi-file torrent
@@ -43,7 +55,7 @@ pub struct FileEntry {
 
 impl Torrent {
     /// Reads a `.torrent` file from disk and parses it into a [`Torrent`] struct
-    pub fn from_file(path: &str) -> Result<Self, ApplicationError> {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ApplicationError> {
 
         // Read into buffer from file
         let data = fs::read(path)
@@ -56,7 +68,7 @@ impl Torrent {
 
         // Get the info
         let info_value = bencoded_map.get("info").ok_or_else(|| {
-            ApplicationError::ParserError(format!("missing info"))
+            ApplicationError::ParserError("missing info".into())
         })?;
 
         // Convert the info bytes and encode to bencode
@@ -73,6 +85,21 @@ impl Torrent {
         })
     }
 
+    /// Builds a torrent from an info dictionary fetched from peers and the
+    /// trackers of the magnet link it came from, all in one tier
+    pub fn from_metadata(info_raw_bytes: Vec<u8>, trackers: &[String]) -> Result<Self, ApplicationError> {
+        let info: Info = serde_bencode::from_bytes(&info_raw_bytes)
+            .map_err(|e| ApplicationError::ParserError(format!("invalid metadata: {}", e)))?;
+
+        Ok(Torrent {
+            announce:       trackers.first().cloned().unwrap_or_default(),
+            announce_list:  (trackers.len() > 1).then(|| vec![trackers.to_vec()]),
+            nodes:          None,
+            info,
+            info_raw_bytes,
+        })
+    }
+
     /// Computes the SHA1 hash of the bencoded `info` dictionary
     pub fn info_hash(&self) -> [u8; 20] {
         let digest = Sha1::digest(&self.info_raw_bytes);
@@ -81,6 +108,11 @@ impl Torrent {
         arr
     }
 
+    /// Whether peers may only come from the torrent's trackers, not the DHT (BEP 27)
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
     // /// Returns the SHA1 info hash as a hexadecimal string
     // pub fn info_hash_hex(&self) -> String {
     //     hex::encode(self.info_hash())
@@ -91,6 +123,67 @@ impl Torrent {
     //     &self.info.name
     // }
 
+    /// Collects every `.torrent` file directly inside `dir`, sorted by path
+    pub fn find_in_dir(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, ApplicationError> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| ApplicationError::ParserError(format!("{}", e)))?;
+
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "torrent"))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Returns every tracker URL without duplicates, `announce` first
+    pub fn trackers(&self) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        let listed = self.announce_list.iter().flatten().flatten();
+        for url in std::iter::once(&self.announce).chain(listed) {
+            if !url.is_empty() && !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
+    /// Returns the tiers of trackers to announce to, each URL once
+    ///
+    /// As BEP 12 asks, `announce` is only used when there is no announce list.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        let tiers = match &self.announce_list {
+            Some(list) if !list.is_empty() => list.clone(),
+            _                              => vec![vec![self.announce.clone()]],
+        };
+
+        let mut seen: Vec<String> = Vec::new();
+        tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter(|url| {
+                        let new = !url.is_empty() && !seen.contains(url);
+                        if new {
+                            seen.push(url.clone());
+                        }
+                        new
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect()
+    }
+
+    /// Returns the DHT nodes listed in the torrent, skipping invalid ports
+    pub fn dht_nodes(&self) -> Vec<(String, u16)> {
+        self.nodes
+            .iter()
+            .flatten()
+            .filter_map(|(host, port)| Some((host.clone(), u16::try_from(*port).ok()?)))
+            .collect()
+    }
+
     /// Calculates the total size of all files described by the torrent
     pub fn total_size(&self) -> i64 {
         self.files().iter().map(|f| f.length).sum()
@@ -137,22 +230,70 @@ impl Torrent {
         self.info.piece_length
     }
 
-    // /// Returns the SHA1 hash of each piece as a vector of `[u8; 20]`
-    // pub fn piece_hashes(&self) -> Vec<[u8; 20]> {
-    //     self.info
-    //         .pieces
-    //         .chunks(20)
-    //         .filter_map(|chunk| {
-    //             if chunk.len() == 20 {
-    //                 let mut arr = [0u8; 20];
-    //                 arr.copy_from_slice(chunk);
-    //                 Some(arr)
-    //             } else {
-    //                 None
-    //             }
-    //         })
-    //         .collect()
-    // }
+    /// Returns the SHA1 hash of each piece as a vector of `[u8; 20]`
+    pub fn piece_hashes(&self) -> Vec<[u8; 20]> {
+        self.info
+            .pieces
+            .chunks_exact(20)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect()
+    }
+
+    /// Returns the byte range a file occupies within the torrent's content
+    ///
+    /// `path` may include the torrent name as its first component, as in
+    /// [`Torrent::files`], or start right below it.
+    pub fn file_range(&self, path: &Path) -> Option<Range<u64>> {
+        self.files()
+            .into_iter()
+            .zip(self.file_ranges())
+            .find(|(file, _)| {
+                let inner = file.path.strip_prefix(&self.info.name).unwrap_or(&file.path);
+                file.path == path || inner == path
+            })
+            .map(|(_, range)| range)
+    }
+
+    /// Returns the indices of the pieces overlapping `range` of the content
+    pub fn pieces_in(&self, range: &Range<u64>) -> Range<usize> {
+        let piece_len = self.piece_length() as u64;
+        if range.is_empty() || piece_len == 0 {
+            return 0..0;
+        }
+        let first = (range.start / piece_len) as usize;
+        let last  = ((range.end - 1) / piece_len) as usize;
+        first.min(self.pieces_count())..(last + 1).min(self.pieces_count())
+    }
+
+    /// Returns the bytes written to disk when downloading `range` of the
+    /// content, or all of it if `None`
+    ///
+    /// Whole pieces are written, so this includes the parts of the first and
+    /// last piece outside of `range`.
+    pub fn selected_size(&self, range: Option<&Range<u64>>) -> u64 {
+        let total = self.total_size() as u64;
+        let Some(range) = range else {
+            return total;
+        };
+        let piece_len = self.piece_length() as u64;
+        let pieces    = self.pieces_in(range);
+        (pieces.end as u64 * piece_len)
+            .min(total)
+            .saturating_sub(pieces.start as u64 * piece_len)
+    }
+
+    /// Returns the byte range of every file, in the order of [`Torrent::files`]
+    pub fn file_ranges(&self) -> Vec<Range<u64>> {
+        let mut offset = 0u64;
+        self.files()
+            .iter()
+            .map(|file| {
+                let start = offset;
+                offset   += file.length as u64;
+                start..offset
+            })
+            .collect()
+    }
 
     // /// Maps each file in the torrent to the set of piece indices it spans
     // ///
@@ -182,6 +323,9 @@ impl Torrent {
         println!("Torrent Info:");
         println!("  Name: {}", self.info.name);
         println!("  Announce URL: {}", self.announce);
+        if let Some(nodes) = &self.nodes {
+            println!("  DHT Nodes: {}", nodes.len());
+        }
         println!("  Piece Length: {} bytes", self.piece_length());
         println!("  Total Pieces: {}", self.pieces_count());
         println!("  Total Size: {} bytes", self.total_size());
@@ -1,30 +1,487 @@
+use crate::config::{TrackerAuth, TrackerHttp, TrackerUdp};
 use crate::error::ApplicationError;
-use crate::peer::Peer;
-use crate::torrent::Torrent;
-use reqwest::Client;
-use serde::Deserialize;
+use crate::identity::Identity;
+use crate::listen::Endpoints;
+use crate::peer::{Peer, PeerSource};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Certificate, Client, RequestBuilder, redirect::Policy};
+use serde::{Deserialize, Serialize};
 use serde_bencode::de;
 use serde_bencode::value::{Value};
-use std::net::{IpAddr, Ipv4Addr};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::time;
 use url::Url;
 
 /// Handles communication with a BitTorrent tracker
-pub struct Tracker;
+///
+/// Clones share the HTTP client, and with it pooled connections, so
+/// repeated announces to the same tracker reuse them.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    /// Address reported to the tracker through the `ip` parameter instead of
+    /// letting it use the source address of the request
+    pub announce_ip: Option<IpAddr>,
+    /// Port we accept peers on, announced to trackers and the DHT, and the
+    /// addresses told to trackers through `ipv4` and `ipv6` (BEP 7)
+    pub endpoints:   Endpoints,
+    client:          Client,
+    /// Local address UDP announces are sent from
+    bind_ip:         Option<IpAddr>,
+    /// Retransmissions of an unanswered UDP request
+    udp_retries:     u32,
+}
 
 /// Represents the response returned by a tracker announce request
 #[derive(Debug, Deserialize)]
 pub struct AnnounceResponse {
     #[serde(rename = "peers")]
-    pub peers_data: Value,
+    pub peers_data: Option<Value>,
+    /// IPv6 peers in compact form, 18 bytes each (BEP 7)
+    pub peers6:     Option<Value>,
     pub interval:   Option<i64>,
+    /// Number of peers with the complete torrent
+    pub complete:   Option<i64>,
+    /// Number of peers still downloading
+    pub incomplete: Option<i64>,
+    /// Number of completed downloads, sent by some trackers
+    pub downloaded: Option<i64>,
+    #[serde(rename = "failure reason")]
+    pub failure:    Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning:    Option<String>,
+}
+
+/// Bytes left as they are in query values: the unreserved set of RFC 3986
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Tracker protocols this client speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerProtocol {
+    /// BEP 3 announces over HTTP or HTTPS
+    Http,
+    /// BEP 15 announces over UDP
+    Udp,
+}
+
+/// Announce URL schemes mapped to the protocol handling them
+///
+/// New protocols are added here and dispatched in [`Tracker::announce`] and
+/// [`Tracker::scrape`].
+const PROTOCOLS: &[(&str, TrackerProtocol)] = &[
+    ("http",  TrackerProtocol::Http),
+    ("https", TrackerProtocol::Http),
+    ("udp",   TrackerProtocol::Udp),
+];
+
+/// Port peers are accepted on unless configured otherwise
+pub const PEER_PORT: u16 = 6881;
+
+/// Magic constant opening every UDP connect request (BEP 15)
+const UDP_PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+const UDP_CONNECT: u32  = 0;
+const UDP_ANNOUNCE: u32 = 1;
+const UDP_SCRAPE: u32   = 2;
+const UDP_ERROR: u32    = 3;
+
+
+/// Wait for the first answer of a UDP tracker, doubled after every
+/// retransmission (BEP 15)
+const UDP_INITIAL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a connection id handed out by a UDP tracker stays valid
+const UDP_CONNECTION_TTL: Duration = Duration::from_secs(60);
+
+/// Largest datagram accepted from a UDP tracker
+const UDP_MAX_PACKET: usize = 64 * 1024;
+
+impl TrackerProtocol {
+    /// Parses an announce URL and looks up the protocol handling its scheme
+    pub fn for_url(announce: &str) -> Result<(Self, Url), ApplicationError> {
+        let url      = Url::parse(announce)
+            .map_err(|e| ApplicationError::TrackerError(format!("{}: {}", announce, e)))?;
+        let protocol = PROTOCOLS
+            .iter()
+            .find(|(scheme, _)| *scheme == url.scheme())
+            .map(|(_, protocol)| *protocol)
+            .ok_or_else(|| {
+                ApplicationError::TrackerError(format!(
+                    "{}: unsupported tracker scheme '{}'",
+                    announce,
+                    url.scheme()
+                ))
+            })?;
+        Ok((protocol, url))
+    }
+}
+
+/// Swarm totals a tracker reports for one torrent through its scrape endpoint
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Scrape {
+    /// Peers with the complete torrent
+    pub seeders:   u64,
+    /// Peers still downloading
+    pub leechers:  u64,
+    /// Number of completed downloads the tracker has seen
+    pub downloads: u64,
+}
+
+/// Derives a tracker's scrape URL from its announce URL
+///
+/// By convention only trackers whose path ends in a component starting
+/// with `announce` support scraping; `None` for the others.
+pub fn scrape_url(announce: &Url) -> Option<Url> {
+    let mut url     = announce.clone();
+    let path        = url.path().to_string();
+    let (dir, last) = path.rsplit_once('/')?;
+    let rest        = last.strip_prefix("announce")?;
+    url.set_path(&format!("{}/scrape{}", dir, rest));
+    Some(url)
+}
+
+/// Peers asked from a tracker per announce, the usual default of trackers
+const NUM_WANT: u32 = 50;
+
+/// Lifecycle event reported with an announce (BEP 3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// A regular announce at the tracker's interval
+    None,
+    /// The first announce of the torrent
+    Started,
+    /// The download just finished
+    Completed,
+    /// The torrent is going away
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` parameter of HTTP announces, which regular ones omit
+    fn name(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None      => None,
+            AnnounceEvent::Started   => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped   => Some("stopped"),
+        }
+    }
+
+    /// Value of the `event` field of UDP announces (BEP 15)
+    fn udp_code(self) -> u32 {
+        match self {
+            AnnounceEvent::None      => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started   => 2,
+            AnnounceEvent::Stopped   => 3,
+        }
+    }
+
+    /// Peers to ask for: none when the torrent is going away
+    fn num_want(self) -> u32 {
+        match self {
+            AnnounceEvent::Stopped => 0,
+            _                      => NUM_WANT,
+        }
+    }
+}
+
+/// Transfer counters and event sent with an announce
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub uploaded:   u64,
+    pub downloaded: u64,
+    /// Bytes we still need of the torrent
+    pub left:       u64,
+    pub event:      AnnounceEvent,
+}
+
+/// What a successful announce returned
+#[derive(Debug, Clone)]
+pub struct Announce {
+    pub peers:      Vec<Peer>,
+    /// How long the tracker wants us to wait before announcing again
+    pub interval:   Option<Duration>,
+    pub seeders:    Option<u64>,
+    pub leechers:   Option<u64>,
+    /// Completed downloads, only reported by some HTTP trackers
+    pub downloads:  Option<u64>,
+    /// Message the tracker attached to an otherwise successful response
+    pub warning:    Option<String>,
+    /// New announce URL, if the tracker redirected the request elsewhere
+    pub redirected: Option<Url>,
+}
+
+/// Weight of the newest measurement in a tracker's round-trip estimate
+const RTT_SMOOTHING: f64 = 0.5;
+
+/// Health of a tracker, as shown in the tracker list of a torrent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", content = "message", rename_all = "snake_case")]
+pub enum TrackerHealth {
+    /// No announce has completed yet
+    NotContacted,
+    Working,
+    /// The tracker answered with a warning message
+    Warning(String),
+    /// The last announce failed
+    Error(String),
+}
+
+/// State of one of a torrent's trackers
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackerStatus {
+    pub url:           String,
+    /// Tier of the tracker in the torrent's announce list (BEP 12), 0 first
+    pub tier:          usize,
+    pub health:        TrackerHealth,
+    /// Smoothed round-trip time of successful announces, in milliseconds
+    pub rtt_ms:        Option<u64>,
+    /// Time of the last announce, in seconds since the UNIX epoch
+    pub last_announce: Option<u64>,
+    /// Earliest time the tracker accepts a new announce, in seconds since the UNIX epoch
+    pub next_announce: Option<u64>,
+    /// Seeders and leechers reported by the last successful announce or scrape
+    pub seeders:       Option<u64>,
+    pub leechers:      Option<u64>,
+    /// Completed downloads reported by the last scrape, or announce if it has them
+    pub downloads:     Option<u64>,
+    /// Number of peers returned by the last successful announce
+    pub peers:         usize,
+}
+
+impl TrackerStatus {
+    pub fn new(url: &str, tier: usize) -> Self {
+        Self {
+            url:           url.to_string(),
+            tier,
+            health:        TrackerHealth::NotContacted,
+            rtt_ms:        None,
+            last_announce: None,
+            next_announce: None,
+            seeders:       None,
+            leechers:      None,
+            downloads:     None,
+            peers:         0,
+        }
+    }
+
+    /// Records the outcome of an announce that took `rtt`
+    pub fn update(&mut self, result: &Result<Announce, ApplicationError>, rtt: Duration) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.last_announce = Some(now);
+
+        match result {
+            Ok(announce) => {
+                self.health = match &announce.warning {
+                    Some(warning) => TrackerHealth::Warning(warning.clone()),
+                    None          => TrackerHealth::Working,
+                };
+                self.next_announce = announce.interval.map(|i| now + i.as_secs());
+                self.seeders       = announce.seeders;
+                self.leechers      = announce.leechers;
+                self.downloads     = announce.downloads.or(self.downloads);
+                self.peers         = announce.peers.len();
+
+                let sample  = rtt.as_secs_f64() * 1000.0;
+                let average = self.rtt_ms.map_or(sample, |old| old as f64 + RTT_SMOOTHING * (sample - old as f64));
+                self.rtt_ms = Some(average.round() as u64);
+            }
+            Err(e) => {
+                self.health        = TrackerHealth::Error(format!("{:?}", e));
+                self.next_announce = None;
+            }
+        }
+    }
+
+    /// Records the swarm totals of a scrape, fresher than the announce before it
+    pub fn scraped(&mut self, scrape: &Scrape) {
+        self.seeders   = Some(scrape.seeders);
+        self.leechers  = Some(scrape.leechers);
+        self.downloads = Some(scrape.downloads);
+    }
+
+    /// Orders the trackers of a tier: fastest working ones first, then the
+    /// ones never contacted, then the ones whose last announce failed
+    pub fn preference(&self) -> (u8, u64) {
+        match (&self.health, self.rtt_ms) {
+            (TrackerHealth::Error(_), _)     => (2, 0),
+            (TrackerHealth::NotContacted, _) => (1, 0),
+            (_, rtt)                         => (0, rtt.unwrap_or(0)),
+        }
+    }
+}
+
+/// A BEP 15 exchange with a UDP tracker
+///
+/// Holds the connection id the tracker handed out, getting a new one once
+/// it expires.
+struct UdpTracker {
+    socket:     UdpSocket,
+    connection: Option<(u64, Instant)>,
+    retries:    u32,
+}
+
+impl UdpTracker {
+    /// Resolves the tracker of `url` and opens a socket to it, from `bind_ip` if set
+    async fn open(url: &Url, bind_ip: Option<IpAddr>, retries: u32) -> Result<Self, ApplicationError> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| ApplicationError::TrackerError(format!("{}: no host", url)))?;
+        let port = url
+            .port()
+            .ok_or_else(|| ApplicationError::TrackerError(format!("{}: no port", url)))?;
+        let addr = lookup_host((host, port))
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("{}: {}", url, e)))?
+            .find(|addr| bind_ip.is_none_or(|ip| ip.is_ipv4() == addr.is_ipv4()))
+            .ok_or_else(|| ApplicationError::TrackerError(format!("{}: no usable address", url)))?;
+
+        let local  = match bind_ip {
+            Some(ip) => SocketAddr::new(ip, 0),
+            None if addr.is_ipv4() => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            None => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let socket = UdpSocket::bind(local)
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("{}: {}", url, e)))?;
+        socket
+            .connect(addr)
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("{}: {}", url, e)))?;
+
+        Ok(Self { socket, connection: None, retries })
+    }
+
+    /// Sends `action` with `body` and returns the body of the answer
+    ///
+    /// Unanswered requests are sent again after 15 * 2^n seconds, up to
+    /// `retries` times, getting a new connection id first when needed, as
+    /// BEP 15 prescribes.
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, ApplicationError> {
+        for attempt in 0..=self.retries {
+            let wait = UDP_INITIAL_TIMEOUT * 2u32.pow(attempt);
+
+            let connection_id = match self.connection {
+                Some((id, since)) if since.elapsed() < UDP_CONNECTION_TTL => id,
+                _ => {
+                    let mut packet = Vec::with_capacity(16);
+                    packet.write_u64::<BigEndian>(UDP_PROTOCOL_ID).unwrap();
+                    packet.write_u32::<BigEndian>(UDP_CONNECT).unwrap();
+                    let Some(answer) = self.transact(UDP_CONNECT, packet, &[], wait).await? else {
+                        continue;
+                    };
+                    let id = answer
+                        .as_slice()
+                        .read_u64::<BigEndian>()
+                        .map_err(|_| ApplicationError::TrackerError("short connect response".into()))?;
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.write_u64::<BigEndian>(connection_id).unwrap();
+            packet.write_u32::<BigEndian>(action).unwrap();
+            if let Some(answer) = self.transact(action, packet, body, wait).await? {
+                return Ok(answer);
+            }
+        }
+        Err(ApplicationError::TrackerError("UDP tracker did not answer".into()))
+    }
+
+    /// Sends `header`, a transaction id and `body`, then waits up to `wait`
+    /// for the answer carrying the same transaction id
+    ///
+    /// Returns `None` on timeout. Datagrams of other transactions are ignored.
+    async fn transact(
+        &self,
+        action:     u32,
+        mut packet: Vec<u8>,
+        body:       &[u8],
+        wait:       Duration,
+    ) -> Result<Option<Vec<u8>>, ApplicationError> {
+        let transaction = rand::random::<u32>();
+        packet.write_u32::<BigEndian>(transaction).unwrap();
+        packet.extend_from_slice(body);
+        self.socket
+            .send(&packet)
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
+
+        let deadline = time::Instant::now() + wait;
+        let mut buf  = vec![0u8; UDP_MAX_PACKET];
+        loop {
+            let Ok(received) = time::timeout_at(deadline, self.socket.recv(&mut buf)).await else {
+                return Ok(None);
+            };
+            let len = received.map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
+            let mut reader = &buf[..len];
+            let (Ok(kind), Ok(id)) = (reader.read_u32::<BigEndian>(), reader.read_u32::<BigEndian>()) else {
+                continue;
+            };
+            if id != transaction {
+                continue;
+            }
+            if kind == UDP_ERROR {
+                return Err(ApplicationError::TrackerError(String::from_utf8_lossy(reader).into_owned()));
+            }
+            if kind != action {
+                return Err(ApplicationError::TrackerError(format!("unexpected UDP tracker action {}", kind)));
+            }
+            return Ok(Some(reader.to_vec()));
+        }
+    }
+}
+
+/// Reads peers in compact form: 6 bytes each for IPv4, 18 for IPv6, the
+/// address then the port (BEP 23, BEP 7 and BEP 15)
+///
+/// IPv4-mapped IPv6 addresses are turned back into IPv4 ones.
+fn compact_peers(data: &[u8], ipv6: bool) -> Vec<Peer> {
+    let size = if ipv6 { 18 } else { 6 };
+    data.chunks_exact(size)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(size - 2);
+            let ip         = match <[u8; 16]>::try_from(ip) {
+                Ok(octets) => IpAddr::from(octets).to_canonical(),
+                Err(_)     => IpAddr::from([ip[0], ip[1], ip[2], ip[3]]),
+            };
+            Peer {
+                ip,
+                port:    u16::from_be_bytes([port[0], port[1]]),
+                source:  PeerSource::Tracker,
+                peer_id: None,
+                host:    None,
+            }
+        })
+        .collect()
 }
 
 impl AnnounceResponse {
 
-    pub fn peers(&self) -> Vec<Peer> {
+    /// Extracts the peer list, resolving host names found in non-compact responses
+    ///
+    /// IPv6 peers come from the `peers6` key, or from `peers` in its
+    /// dictionary form.
+    pub async fn peers(&self) -> Vec<Peer> {
         let mut result = Vec::new();
 
-        match &self.peers_data {
+        if let Some(Value::Bytes(data)) = &self.peers6 {
+            result.extend(compact_peers(data, true));
+        }
+
+        let Some(peers_data) = &self.peers_data else {
+            return result;
+        };
+
+        match peers_data {
 
             Value::Bytes(data) => {
 
@@ -43,23 +500,13 @@ impl AnnounceResponse {
                  * [192, 168, 1, 1, 26, 225]
                  * (where 26 * 256 + 225 = 6881)
                  *
-                 * The code iterates through the `data` byte string in chunks 
-                 * of 6 bytes. For each valid 6-byte chunk, it extracts the 
-                 * IPv4 address and port, then creates a `Peer` struct and adds 
-                 * it to the `result` vector.
+                 * The `data` byte string is read in chunks of 6 bytes by
+                 * `compact_peers`; a trailing partial chunk is ignored.
+                 * IPv6 peers come in the separate `peers6` key, with
+                 * 18-byte entries (16 for the address, 2 for the port).
                  */
 
-
-                for chunk in data.chunks(6) {
-                    if chunk.len() == 6 {
-                        let ip   = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
-                        let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                        result.push(Peer {
-                            ip:   IpAddr::V4(ip),
-                            port,
-                        });
-                    }
-                }
+                result.extend(compact_peers(data, false));
             }
             Value::List(list) => {
 
@@ -71,19 +518,24 @@ impl AnnounceResponse {
                  * of Bencoded dictionaries.
                  * 
                  * Each dictionary typically contains the following keys:
-                 * - "ip": (Byte String) The peer's IPv4 address as a 
-                 *    dotted-decimal string (e.g., "192.168.1.1").
+                 * - "ip": (Byte String) The peer's address: a dotted-decimal
+                 *    IPv4 string (e.g., "192.168.1.1"), an IPv6 string or
+                 *    a DNS name.
                  * - "port": (Integer) The peer's port number.
+                 * - "peer id": (Byte String, optional) The 20-byte id the
+                 *    peer will send in its handshake.
                  *
                  * The code iterates through each 'item' in the 'list'.
                  * It expects each 'item' to be a 'Value::Dict'.
                  * Inside each dictionary, it attempts to extract the 
-                 * "ip" and "port" values.
+                 * "ip", "port" and "peer id" values.
                  * 
                  * - "ip" is parsed from a byte string to a UTF-8 string, 
-                 *    then to an Ipv4Addr.
+                 *    then to an IpAddr; anything else is resolved as a
+                 *    host name, keeping the first address returned.
                  * - "port" is cast from an integer, with a range check 
                  *    to ensure it fits in u16.
+                 * - "peer id" is kept only when exactly 20 bytes long.
                  * 
                  * If both IP and port are successfully extracted, a 'Peer' 
                  * struct is created and added to the 'result' vector.
@@ -93,26 +545,53 @@ impl AnnounceResponse {
                     if let Value::Dict(dict) = item {
 
                         // Get the IP string
-                        let ip = dict.get(&b"ip".to_vec())
+                        let host = dict.get(&b"ip".to_vec())
                             .and_then(|v| match v {
                                 Value::Bytes(b) => String::from_utf8(b.clone()).ok(),
                                            _    => None,
-                            })
-                            .and_then(|s| s.parse::<Ipv4Addr>().ok())
-                            .map(IpAddr::V4);
+                            });
                         
                         // Get the port string
                         let port = dict.get(&b"port".to_vec())
                             .and_then(|v| match v {
-                                Value::Int(n)   => Some(*n as u16),
+                                Value::Int(n)   => u16::try_from(*n).ok(),
                                            _    => None,
                             });
-                        
+
+                        // Get the peer id
+                        let peer_id = dict.get(&b"peer id".to_vec())
+                            .and_then(|v| match v {
+                                Value::Bytes(b) => <[u8; 20]>::try_from(b.as_slice()).ok(),
+                                           _    => None,
+                            });
+
+                        let (Some(host), Some(port)) = (host, port) else {
+                            continue;
+                        };
+
+                        // Resolve the IP, keeping host names for dialing;
+                        // IPv6 addresses may come in brackets
+                        let literal = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(&host);
+                        let (ip, host) = match literal.parse::<IpAddr>() {
+                            Ok(ip) => (Some(ip.to_canonical()), None),
+                            Err(_) => {
+                                let ip = lookup_host((host.as_str(), port))
+                                    .await
+                                    .ok()
+                                    .and_then(|mut addrs| addrs.next())
+                                    .map(|addr| addr.ip());
+                                (ip, Some(host))
+                            }
+                        };
+
                         // Add the result
-                        if let (Some(ip), Some(port)) = (ip, port) {
+                        if let Some(ip) = ip {
                             result.push(Peer { 
                                 ip, 
-                                port 
+                                port,
+                                source:  PeerSource::Tracker,
+                                peer_id,
+                                host,
                             });
                         }
                     }
@@ -125,50 +604,195 @@ impl AnnounceResponse {
 }
 
 impl Tracker {
-    /// A fixed peer ID used to identify the client
-    const PEER_ID: [u8; 20] = *b"-RU0001-123456789010";
+    /// Builds the HTTP client according to `http`, connecting from `bind_ip` if
+    /// set; UDP trackers are asked according to `udp`
+    pub fn new(
+        announce_ip: Option<IpAddr>,
+        bind_ip:     Option<IpAddr>,
+        endpoints:   Endpoints,
+        http:        &TrackerHttp,
+        udp:         &TrackerUdp,
+    ) -> Result<Self, ApplicationError> {
+        let redirects = match http.max_redirects {
+            0 => Policy::none(),
+            n => Policy::limited(n),
+        };
+
+        let mut builder = Client::builder()
+            .timeout(http.timeout)
+            .redirect(redirects)
+            .gzip(http.gzip)
+            .local_address(bind_ip);
+
+        if let Some(path) = &http.ca_cert {
+            let pem = fs::read(path)
+                .map_err(|e| ApplicationError::TrackerError(format!("{}: {}", path.display(), e)))?;
+            let cert = Certificate::from_pem(&pem)
+                .map_err(|e| ApplicationError::TrackerError(format!("{}: {}", path.display(), e)))?;
+            builder = builder.add_root_certificate(cert);
+        }
 
+        let client = builder
+            .build()
+            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
+        Ok(Self {
+            announce_ip,
+            endpoints,
+            client,
+            bind_ip,
+            udp_retries: udp.retries,
+        })
+    }
+
+    /// Percent-encodes raw bytes such as an info hash or peer id
     fn percent_encode(bytes: &[u8; 20]) -> String {
-        bytes.iter().map(|b| format!("%{:02X}", b)).collect()
+        percent_encoding::percent_encode(bytes, QUERY_VALUE).to_string()
+    }
+
+    /// Adds the credentials of `auth` to a tracker request
+    fn authorize(mut request: RequestBuilder, auth: &TrackerAuth) -> RequestBuilder {
+        if let Some((user, password)) = &auth.basic {
+            request = request.basic_auth(user, password.as_ref());
+        }
+        for (name, value) in &auth.headers {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Appends `params` to the query of `base`, keeping any parameters it
+    /// already has (private trackers often put a passkey there)
+    ///
+    /// Values must already be percent-encoded; they are not encoded again.
+    fn with_query(base: &Url, params: &[(&str, String)]) -> Url {
+        let mut query = base.query().unwrap_or_default().trim_end_matches('&').to_string();
+        for (key, value) in params {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query.push_str(key);
+            query.push('=');
+            query.push_str(value);
+        }
+
+        let mut url = base.clone();
+        url.set_query(Some(&query));
+        url
     }
 
     /// Sends an announce request to the tracker and returns the list of peers
-    pub async fn announce(&self, torrent: &Torrent) -> Result<Vec<Peer>, ApplicationError> {
-        let announce   = &torrent.announce;
-        let info_hash  = &torrent.info_hash();
-        let peer_id    = &Self::PEER_ID;
-        let uploaded   = 0u64;
-        let downloaded = 0u64;
-        let left       = torrent.total_size() as u64;
-        let port       = 6881u16;
-
-        let base_url = Url::parse(announce)
-            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
+    pub async fn announce(
+        &self,
+        announce:  &str,
+        info_hash: &[u8; 20],
+        progress:  Progress,
+        identity:  &Identity,
+        auth:      &TrackerAuth,
+    ) -> Result<Announce, ApplicationError> {
+        match TrackerProtocol::for_url(announce)? {
+            (TrackerProtocol::Http, url) => self.announce_http(url, info_hash, progress, identity, auth).await,
+            (TrackerProtocol::Udp, url)  => self.announce_udp(&url, info_hash, progress, identity).await,
+        }
+    }
+
+    /// Announces over UDP, giving up once every retransmission went unanswered
+    async fn announce_udp(
+        &self,
+        url:       &Url,
+        info_hash: &[u8; 20],
+        progress:  Progress,
+        identity:  &Identity,
+    ) -> Result<Announce, ApplicationError> {
+        let mut tracker = UdpTracker::open(url, self.bind_ip, self.udp_retries).await?;
+        let ipv6        = tracker.socket.peer_addr().is_ok_and(|addr| addr.is_ipv6());
+
+        // The field only holds an IPv4 address, useless to IPv6 trackers
+        let ip = match (self.announce_ip, self.endpoints.ipv4) {
+            (Some(IpAddr::V4(ip)), _)  => u32::from(ip),
+            (None, Some(ip)) if !ipv6 => u32::from(ip),
+            _                          => 0,
+        };
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(info_hash);
+        body.extend_from_slice(&identity.peer_id);
+        body.write_u64::<BigEndian>(progress.downloaded).unwrap();
+        body.write_u64::<BigEndian>(progress.left).unwrap();
+        body.write_u64::<BigEndian>(progress.uploaded).unwrap();
+        body.write_u32::<BigEndian>(progress.event.udp_code()).unwrap();
+        body.write_u32::<BigEndian>(ip).unwrap();
+        body.write_u32::<BigEndian>(identity.key).unwrap();
+        body.write_u32::<BigEndian>(progress.event.num_want()).unwrap();
+        body.write_u16::<BigEndian>(self.endpoints.port).unwrap();
+
+        let answer     = tracker.request(UDP_ANNOUNCE, &body).await?;
+        let mut reader = answer.as_slice();
+        let short      = |_| ApplicationError::TrackerError("short UDP announce response".into());
+        let interval   = reader.read_u32::<BigEndian>().map_err(short)?;
+        let leechers   = reader.read_u32::<BigEndian>().map_err(short)?;
+        let seeders    = reader.read_u32::<BigEndian>().map_err(short)?;
+
+        Ok(Announce {
+            peers:      compact_peers(reader, ipv6),
+            interval:   Some(Duration::from_secs(interval.into())),
+            seeders:    Some(seeders.into()),
+            leechers:   Some(leechers.into()),
+            downloads:  None,
+            warning:    None,
+            redirected: None,
+        })
+    }
+
+    async fn announce_http(
+        &self,
+        base_url:  Url,
+        info_hash: &[u8; 20],
+        progress:  Progress,
+        identity:  &Identity,
+        auth:      &TrackerAuth,
+    ) -> Result<Announce, ApplicationError> {
+        let peer_id = &identity.peer_id;
+        let port    = self.endpoints.port;
 
         let params = [
             ("info_hash",  Tracker::percent_encode(info_hash)),
             ("peer_id",    Tracker::percent_encode(peer_id)),
             ("port",       port.to_string()),
-            ("uploaded",   uploaded.to_string()),
-            ("downloaded", downloaded.to_string()),
-            ("left",       left.to_string()),
-            ("event",      "started".to_string()),
+            ("uploaded",   progress.uploaded.to_string()),
+            ("downloaded", progress.downloaded.to_string()),
+            ("left",       progress.left.to_string()),
+            ("key",        identity.key_hex()),
+            ("compact",    "1".to_string()),
+            ("no_peer_id", "1".to_string()),
+            ("numwant",    progress.event.num_want().to_string()),
         ];
 
-        let query = params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect::<Vec<_>>()
-            .join("&");
-
-        let url = format!("{}?{}", base_url, query);
+        let mut params = params.to_vec();
+        if let Some(event) = progress.event.name() {
+            params.push(("event", event.to_string()));
+        }
+        if let Some(ip) = self.announce_ip {
+            params.push(("ip", ip.to_string()));
+        }
+        // Addresses of the families `ip` leaves out
+        if let Some(ip) = self.endpoints.ipv4.filter(|_| !self.announce_ip.is_some_and(|ip| ip.is_ipv4())) {
+            params.push(("ipv4", ip.to_string()));
+        }
+        if let Some(ip) = self.endpoints.ipv6.filter(|_| !self.announce_ip.is_some_and(|ip| ip.is_ipv6())) {
+            params.push(("ipv6", ip.to_string()));
+        }
 
-        let client = Client::new();
-        let raw = client
-            .get(&url)
+        let url      = Tracker::with_query(&base_url, &params);
+        let response = Tracker::authorize(self.client.get(url), auth)
             .send()
             .await
-            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?
+            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
+
+        // Redirects were followed by the client; remember where they led
+        let mut target = response.url().clone();
+        target.set_query(base_url.query());
+        let redirected = (target != base_url).then_some(target);
+
+        let raw = response
             .bytes()
             .await
             .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
@@ -176,6 +800,89 @@ impl Tracker {
         let resp: AnnounceResponse = de::from_bytes(&raw)
             .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
 
-        Ok(resp.peers())
+        if let Some(reason) = resp.failure {
+            return Err(ApplicationError::TrackerError(reason));
+        }
+
+        Ok(Announce {
+            peers:     resp.peers().await,
+            interval:  resp.interval.and_then(|i| u64::try_from(i).ok()).map(Duration::from_secs),
+            seeders:   resp.complete.and_then(|n| u64::try_from(n).ok()),
+            leechers:  resp.incomplete.and_then(|n| u64::try_from(n).ok()),
+            downloads: resp.downloaded.and_then(|n| u64::try_from(n).ok()),
+            warning:   resp.warning,
+            redirected,
+        })
+    }
+
+    /// Asks the tracker behind `announce` for the swarm totals of `info_hash`
+    pub async fn scrape(
+        &self,
+        announce:  &str,
+        info_hash: &[u8; 20],
+        auth:      &TrackerAuth,
+    ) -> Result<Scrape, ApplicationError> {
+        match TrackerProtocol::for_url(announce)? {
+            (TrackerProtocol::Http, url) => self.scrape_http(&url, info_hash, auth).await,
+            (TrackerProtocol::Udp, url)  => self.scrape_udp(&url, info_hash).await,
+        }
+    }
+
+    async fn scrape_udp(&self, url: &Url, info_hash: &[u8; 20]) -> Result<Scrape, ApplicationError> {
+        let mut tracker = UdpTracker::open(url, self.bind_ip, self.udp_retries).await?;
+        let answer      = tracker.request(UDP_SCRAPE, info_hash).await?;
+        let mut reader  = answer.as_slice();
+        let short       = |_| ApplicationError::TrackerError("short UDP scrape response".into());
+
+        Ok(Scrape {
+            seeders:   reader.read_u32::<BigEndian>().map_err(short)?.into(),
+            downloads: reader.read_u32::<BigEndian>().map_err(short)?.into(),
+            leechers:  reader.read_u32::<BigEndian>().map_err(short)?.into(),
+        })
+    }
+
+    async fn scrape_http(
+        &self,
+        announce:  &Url,
+        info_hash: &[u8; 20],
+        auth:      &TrackerAuth,
+    ) -> Result<Scrape, ApplicationError> {
+        let base_url = scrape_url(announce)
+            .ok_or_else(|| ApplicationError::TrackerError(format!("{}: scrape not supported", announce)))?;
+        let url      = Tracker::with_query(&base_url, &[("info_hash", Tracker::percent_encode(info_hash))]);
+
+        let raw = Tracker::authorize(self.client.get(url), auth)
+            .send()
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?
+            .bytes()
+            .await
+            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?;
+
+        let Value::Dict(resp) = de::from_bytes::<Value>(&raw)
+            .map_err(|e| ApplicationError::TrackerError(format!("{}", e)))?
+        else {
+            return Err(ApplicationError::TrackerError("scrape response is not a dictionary".into()));
+        };
+        if let Some(Value::Bytes(reason)) = resp.get(b"failure reason".as_slice()) {
+            return Err(ApplicationError::TrackerError(String::from_utf8_lossy(reason).into_owned()));
+        }
+
+        let Some(Value::Dict(files)) = resp.get(b"files".as_slice()) else {
+            return Err(ApplicationError::TrackerError("scrape response without files".into()));
+        };
+        let Some(Value::Dict(file)) = files.get(info_hash.as_slice()) else {
+            return Err(ApplicationError::TrackerError("torrent unknown to the tracker".into()));
+        };
+        let count = |key: &[u8]| match file.get(key) {
+            Some(Value::Int(n)) => u64::try_from(*n).unwrap_or(0),
+            _                   => 0,
+        };
+
+        Ok(Scrape {
+            seeders:   count(b"complete"),
+            leechers:  count(b"incomplete"),
+            downloads: count(b"downloaded"),
+        })
     }
 }
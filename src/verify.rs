@@ -0,0 +1,86 @@
+use std::{num::NonZeroUsize, thread};
+
+use core_affinity::CoreId;
+use sha1::{Digest, Sha1};
+
+/// Returns `true` if the SHA1 of `data` equals `expected`
+///
+/// `sha1` picks SHA-NI instructions at runtime when the CPU has them; the
+/// `asm-sha1` feature switches the fallback to an assembly implementation.
+pub fn piece_matches(data: &[u8], expected: &[u8; 20]) -> bool {
+    Sha1::digest(data).as_slice() == expected
+}
+
+/// Returns the number of hashing threads used when none is configured: one per core
+pub fn default_workers() -> NonZeroUsize {
+    thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
+
+/// Threads hashing pieces in parallel and the cores they run on
+#[derive(Debug, Clone)]
+pub struct HashWorkers {
+    pub count: NonZeroUsize,
+    /// Cores the threads are pinned to, worker `i` on `cores[i % len]`;
+    /// empty leaves scheduling to the OS
+    pub cores: Vec<usize>,
+}
+
+impl HashWorkers {
+    /// Uses `count` threads, or one per pinned core, or one per core if neither is set
+    pub fn new(count: Option<NonZeroUsize>, cores: Vec<usize>) -> Self {
+        let count = count
+            .or_else(|| NonZeroUsize::new(cores.len()))
+            .unwrap_or_else(default_workers);
+        Self { count, cores }
+    }
+
+    /// Pins the calling thread, worker number `worker`, to its core
+    ///
+    /// Best effort: on failure, e.g. a core that doesn't exist, the thread
+    /// keeps running wherever the OS puts it.
+    fn pin(&self, worker: usize) {
+        if self.cores.is_empty() {
+            return;
+        }
+        let id = self.cores[worker % self.cores.len()];
+        if !core_affinity::set_for_current(CoreId { id }) {
+            println!("Failed to pin hashing worker {} to core {}", worker, id);
+        }
+    }
+}
+
+/// Verifies consecutive pieces laid out back to back in `data`
+///
+/// `hashes[i]` is checked against the `i`-th `piece_length` chunk of `data`
+/// (the last one may be shorter). Pieces are split into one contiguous run
+/// per worker and hashed in parallel, so this blocks: call it from
+/// `spawn_blocking` inside async code. With pinned workers each run stays
+/// on one core, keeping its pieces in that core's cache.
+pub fn verify_pieces(
+    data:         &[u8],
+    piece_length: usize,
+    hashes:       &[[u8; 20]],
+    workers:      &HashWorkers,
+) -> Vec<bool> {
+    let pieces = data.chunks(piece_length).collect::<Vec<_>>();
+    let count  = pieces.len().min(hashes.len());
+    let run    = count.div_ceil(workers.count.get()).max(1);
+
+    let mut result = vec![false; count];
+    thread::scope(|scope| {
+        for (worker, ((out, pieces), hashes)) in result
+            .chunks_mut(run)
+            .zip(pieces[..count].chunks(run))
+            .zip(hashes[..count].chunks(run))
+            .enumerate()
+        {
+            scope.spawn(move || {
+                workers.pin(worker);
+                for ((ok, piece), hash) in out.iter_mut().zip(pieces).zip(hashes) {
+                    *ok = piece_matches(piece, hash);
+                }
+            });
+        }
+    });
+    result
+}
@@ -0,0 +1,209 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, TcpListener as StdListener},
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::Duration,
+};
+
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use torrentz::{Config, Limits, Session, Torrent, TorrentId, TorrentState};
+
+const PIECE_LEN: usize = 16384;
+
+/// A session seeding a single-file torrent on a local port
+struct Seeder {
+    session:   Session,
+    id:        TorrentId,
+    port:      u16,
+    info_hash: [u8; 20],
+    dir:       PathBuf,
+}
+
+impl Seeder {
+    /// Writes the file of a torrent named `name` and starts seeding it
+    async fn start(name: &str, limits: Limits) -> Self {
+        let dir = std::env::temp_dir().join(format!("torrentz-{}-{}", name, std::process::id()));
+        let _   = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let content = (0..40000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(dir.join(name), &content).unwrap();
+        let pieces   = content.chunks(PIECE_LEN).flat_map(|c| Sha1::digest(c).to_vec()).collect::<Vec<_>>();
+        let mut info = format!(
+            "d6:lengthi{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+            content.len(),
+            name.len(),
+            name,
+            PIECE_LEN,
+            pieces.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&pieces);
+        info.push(b'e');
+        let torrent   = Torrent::from_metadata(info, &[]).unwrap();
+        let info_hash = torrent.info_hash();
+
+        let port    = StdListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let config  = Config {
+            download_dir: dir.clone(),
+            listen_port:  port,
+            listen_ips:   vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            limits,
+            ..Config::default()
+        };
+        let session = Session::new(config).unwrap();
+        let handle  = session.add_torrent(torrent).await.unwrap();
+        for _ in 0..100 {
+            if handle.state() == TorrentState::Seeding {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(handle.state(), TorrentState::Seeding);
+
+        Self { session, id: handle.id(), port, info_hash, dir }
+    }
+
+    /// Opens a connection, sends a handshake and returns the one received, if any
+    async fn handshake(&self, peer_id: &[u8; 20]) -> Option<(TcpStream, [u8; 68])> {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).await.ok()?;
+        let mut buf    = Vec::with_capacity(68);
+        buf.push(19);
+        buf.extend_from_slice(b"BitTorrent protocol");
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(peer_id);
+        stream.write_all(&buf).await.ok()?;
+
+        let mut reply = [0u8; 68];
+        time::timeout(Duration::from_secs(5), stream.read_exact(&mut reply)).await.ok()?.ok()?;
+        Some((stream, reply))
+    }
+
+    async fn stop(self) {
+        self.session.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Reads messages until one with id `id` arrives; `false` if the peer
+/// closes the connection or takes too long
+async fn wait_for(stream: &mut TcpStream, id: u8) -> bool {
+    let read = async {
+        loop {
+            let mut length = [0u8; 4];
+            stream.read_exact(&mut length).await.ok()?;
+            let mut msg = vec![0u8; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut msg).await.ok()?;
+            if msg.first() == Some(&id) {
+                return Some(());
+            }
+        }
+    };
+    matches!(time::timeout(Duration::from_secs(5), read).await, Ok(Some(())))
+}
+
+/// A peer whose handshake is refused must not keep holding one of the
+/// torrent's incoming connection slots
+#[tokio::test]
+async fn refused_handshakes_free_incoming_slots() {
+    let limits = Limits {
+        max_incoming: NonZeroUsize::new(2),
+        ..Limits::default()
+    };
+    let seeder = Seeder::start("refused.bin", limits).await;
+
+    // Learn the torrent's peer id, then claim it: the torrent takes such
+    // peers for itself and refuses them after answering their handshake
+    let (_, reply) = seeder.handshake(b"-TT0001-000000000000").await.unwrap();
+    let own_id: [u8; 20] = reply[48..68].try_into().unwrap();
+    for _ in 0..3 {
+        if let Some((mut stream, _)) = seeder.handshake(&own_id).await {
+            let mut rest = Vec::new();
+            let _ = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await;
+        }
+    }
+
+    let mut accepted = false;
+    for _ in 0..20 {
+        if seeder.handshake(b"-TT0001-000000000001").await.is_some() {
+            accepted = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    seeder.stop().await;
+    assert!(accepted, "a valid peer was refused after failed handshakes");
+}
+
+/// Keep-alives from an idle leecher must not end its connection
+#[tokio::test]
+async fn keep_alive_keeps_upload_open() {
+    let seeder          = Seeder::start("idle.bin", Limits::default()).await;
+    let (mut stream, _) = seeder.handshake(b"-TT0001-000000000002").await.unwrap();
+
+    stream.write_all(&[0, 0, 0, 0]).await.unwrap();
+    time::sleep(Duration::from_millis(200)).await;
+    stream.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+    let unchoked = wait_for(&mut stream, 1).await;
+
+    seeder.stop().await;
+    assert!(unchoked, "the connection ended after a keep-alive");
+}
+
+/// A frame longer than any message is refused before its body arrives
+#[tokio::test]
+async fn oversized_frame_closes_connection() {
+    let seeder          = Seeder::start("oversized.bin", Limits::default()).await;
+    let (mut stream, _) = seeder.handshake(b"-TT0001-000000000003").await.unwrap();
+
+    stream.write_all(&[0xff, 0xff, 0xff, 0xf0, 5]).await.unwrap();
+    let mut rest = Vec::new();
+    let closed   = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await.is_ok();
+
+    seeder.stop().await;
+    assert!(closed, "the connection waited for a 4 GiB message");
+}
+
+/// The session totals only count the torrents it still has
+#[tokio::test]
+async fn removed_torrent_leaves_session_totals() {
+    let seeder          = Seeder::start("removed.bin", Limits::default()).await;
+    let (mut stream, _) = seeder.handshake(b"-TT0001-000000000004").await.unwrap();
+
+    // Interested, then a request for the first block of piece 0
+    stream.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+    assert!(wait_for(&mut stream, 1).await);
+    let mut request = vec![0, 0, 0, 13, 6];
+    request.extend_from_slice(&[0; 8]);
+    request.extend_from_slice(&(PIECE_LEN as u32).to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    assert!(wait_for(&mut stream, 7).await);
+
+    let mut uploaded = 0;
+    for _ in 0..30 {
+        uploaded = seeder.session.stats().await.unwrap().total_uploaded;
+        if uploaded > 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(uploaded > 0, "the upload was never counted");
+
+    seeder.session.remove_torrent(seeder.id).await.unwrap();
+    for _ in 0..30 {
+        uploaded = seeder.session.stats().await.unwrap().total_uploaded;
+        if uploaded == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    seeder.stop().await;
+    assert_eq!(uploaded, 0, "the removed torrent still counts");
+}